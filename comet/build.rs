@@ -1,3 +1,10 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    if std::env::var_os("CARGO_FEATURE_GUN_TRANSPORT").is_some() {
+        // Messages only, the services are hand-written for a configurable service name
+        tonic_build::configure()
+            .build_client(false)
+            .build_server(false)
+            .compile(&["src/protos/gun/gun.proto"], &["src/protos/gun"])?;
+    }
     Ok(())
 }
@@ -25,6 +25,7 @@ pub async fn handle_conn(
             stream
         };
 
+        let fake_lease = ctx_clone.dns.restore_fake(&mut conn.dest_addr);
         info!("Accepted {}", conn);
 
         // Routing
@@ -36,41 +37,22 @@ pub async fn handle_conn(
 
         info!("Routed to {}", outbound_tag);
 
-        Ok::<_, anyhow::Error>((stream, conn, outbound_tag))
+        Ok::<_, anyhow::Error>((stream, conn, outbound_tag, fake_lease))
     };
 
-    let (stream, conn, outbound_tag) =
+    let (stream, conn, outbound_tag, _fake_lease) =
         tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake_downlink_task)
             .await?
             .with_context(|| "downlink handshake failed")?;
 
-    // Prepare, save original dest
-    let dest_ori = conn.dest_addr.clone();
-    if let Some(outbound_pipeline) = ctx.outbound_manager.get_pipeline(&outbound_tag)? {
-        ctx.clone_plumber()
-            .prepare(outbound_pipeline, conn, ctx.clone())
+    let outbound = match ctx.outbound_manager.get_mux(&outbound_tag)? {
+        // UDP keeps using dedicated connections
+        Some(mux) if conn.typ == TransportType::Tcp => mux
+            .open(&outbound_tag, conn, &ctx)
             .await
-            .with_context(|| format!("preparing outbound pipeline {}", outbound_pipeline))?;
-    }
-
-    // Connect
-    let mut outbound = ctx
-        .outbound_manager
-        .connect(&outbound_tag, conn, &ctx)
-        .await
-        .with_context(|| format!("connecting outbound {}", outbound_tag))?;
-
-    // Restore dest
-    conn.dest_addr = dest_ori;
-
-    // Outbound Pipeline
-    if let Some(outbound_pipeline) = ctx.outbound_manager.get_pipeline(&outbound_tag)? {
-        outbound = ctx
-            .clone_plumber()
-            .process(outbound_pipeline, conn, outbound, ctx.clone())
-            .await
-            .with_context(|| format!("running outbound pipeline {}", outbound_pipeline))?;
-    }
+            .with_context(|| format!("opening mux stream on {}", outbound_tag))?,
+        _ => connect_outbound(&outbound_tag, conn, &ctx).await?,
+    };
 
     // Bi-directional Copy
     match (stream, outbound) {
@@ -107,3 +89,40 @@ pub async fn handle_conn(
     }
     Ok(())
 }
+
+/// Prepares, connects and runs the outbound pipeline of `tag`.
+pub(crate) async fn connect_outbound(
+    tag: &str,
+    conn: &mut Connection,
+    ctx: &AppContextRef,
+) -> Result<ProxyStream> {
+    // Prepare, save original dest
+    let dest_ori = conn.dest_addr.clone();
+    if let Some(outbound_pipeline) = ctx.outbound_manager.get_pipeline(tag)? {
+        ctx.clone_plumber()
+            .prepare(outbound_pipeline, conn, ctx.clone())
+            .await
+            .with_context(|| format!("preparing outbound pipeline {}", outbound_pipeline))?;
+    }
+
+    // Connect
+    let mut outbound = ctx
+        .outbound_manager
+        .connect(tag, conn, ctx)
+        .await
+        .with_context(|| format!("connecting outbound {}", tag))?;
+
+    // Restore dest
+    conn.dest_addr = dest_ori;
+
+    // Outbound Pipeline
+    if let Some(outbound_pipeline) = ctx.outbound_manager.get_pipeline(tag)? {
+        outbound = ctx
+            .clone_plumber()
+            .process(outbound_pipeline, conn, outbound, ctx.clone())
+            .await
+            .with_context(|| format!("running outbound pipeline {}", outbound_pipeline))?;
+    }
+
+    Ok(outbound)
+}
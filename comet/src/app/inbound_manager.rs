@@ -1,6 +1,8 @@
 use crate::config::{Config, Inbound, InboundTransportType};
 use crate::prelude::*;
 use crate::utils::metered_stream::MeteredStream;
+#[cfg(target_os = "linux")]
+use crate::{config::TransparentMode, net_wrapper::transparent};
 use log::info;
 use once_cell::sync::OnceCell;
 use std::collections::HashMap;
@@ -35,11 +37,17 @@ impl InboundManager {
             let ctx = ctx.clone();
             let transport = &inbound.1.transport;
             let ip = transport.listen.unwrap_or_else(|| [0, 0, 0, 0].into());
-            let port = transport.port;
+            let port = transport.listen_port()?;
             let tag = inbound.0.clone();
 
             match transport.r#type {
                 InboundTransportType::Tcp => {
+                    #[cfg(target_os = "linux")]
+                    let listener = match transport.transparent {
+                        Some(mode) => transparent::bind_tcp((ip, port).into(), mode)?,
+                        None => TcpListener::bind(&(ip, port)).await?,
+                    };
+                    #[cfg(not(target_os = "linux"))]
                     let listener = TcpListener::bind(&(ip, port)).await?;
                     let sender = channel.0.clone();
                     info!("Inbound TCP:{} listening on {}:{}", tag, ip, port);
@@ -53,6 +61,47 @@ impl InboundManager {
                             .await;
                     });
                 }
+                #[cfg(feature = "gun-transport")]
+                InboundTransportType::Gun { ref service_name } => {
+                    let listener = TcpListener::bind(&(ip, port)).await?;
+                    let sender = channel.0.clone();
+                    info!("Inbound gun:{} listening on {}:{}", tag, ip, port);
+
+                    tokio::spawn(crate::handler::inbound::gun::serve(
+                        listener,
+                        tag,
+                        inbound.1.clone(),
+                        service_name.clone(),
+                        sender,
+                        ctx,
+                    ));
+                }
+                #[cfg(target_os = "linux")]
+                InboundTransportType::Tun {
+                    ref name,
+                    address,
+                    mtu,
+                } => {
+                    let tun = crate::tun::TunInbound::new(name, address, mtu).await?;
+                    let sender = channel.0.clone();
+                    info!("Inbound TUN:{} capturing on {} ({})", tag, name, address);
+
+                    tokio::spawn(tun.serve(tag, inbound.1.clone(), sender, ctx));
+                }
+                #[cfg(target_os = "linux")]
+                InboundTransportType::Udp if transport.transparent.is_some() => {
+                    if transport.transparent != Some(TransparentMode::Tproxy) {
+                        anyhow::bail!("Inbound {} only supports TPROXY for UDP", tag);
+                    }
+                    let socket = transparent::bind_udp((ip, port).into())?;
+                    let sender = channel.0.clone();
+                    info!("Inbound UDP:{} (TPROXY) listening on {}:{}", tag, ip, port);
+
+                    let inbound = inbound.1.clone();
+                    tokio::spawn(async move {
+                        handle_tproxy_udp(socket, tag, inbound, sender).await;
+                    });
+                }
                 InboundTransportType::Udp => {
                     let socket = UdpSocket::bind(&(ip, port)).await?;
                     let sender = channel.0.clone();
@@ -84,18 +133,29 @@ impl InboundManager {
     ) {
         loop {
             let (stream, src_addr) = listener.accept().await.unwrap();
-            let conn = Connection::new(
+            let mut conn = Connection::new(
                 src_addr,
                 tag.clone(),
                 inbound.pipeline.clone(),
                 TransportType::Tcp,
             );
+            if let Ok(local_addr) = stream.local_addr() {
+                conn.set_var(vars::LOCAL_ADDR, local_addr);
+            }
 
             info!(
                 "({}) Inbound {}/TCP accepted from {}",
                 conn.id, tag, src_addr
             );
 
+            #[cfg(target_os = "linux")]
+            if let Some(mode) = inbound.transport.transparent {
+                match transparent::original_dst(&stream, mode) {
+                    Ok(addr) => conn.set_var(vars::ORIGINAL_DST, addr),
+                    Err(e) => warn!("({}) Failed to get original destination: {}", conn.id, e),
+                }
+            }
+
             let stream = if inbound.metering {
                 RWPair::new(MeteredStream::new_inbound(stream, &tag, &ctx))
             } else {
@@ -212,3 +272,83 @@ impl InboundManager {
         ))
     }
 }
+
+/// Relays TPROXY datagrams, one connection per source and original destination.
+#[cfg(target_os = "linux")]
+async fn handle_tproxy_udp(
+    socket: UdpSocket,
+    tag: SmolStr,
+    inbound: Inbound,
+    sender: ConnSender<ProxyStream>,
+) {
+    use std::collections::hash_map::Entry;
+
+    let mut sessions: HashMap<(SocketAddr, SocketAddr), Sender<UdpPacket>> = HashMap::new();
+    loop {
+        let mut buffer = [0u8; 4096];
+        let (size, src_addr, dst_addr) =
+            match transparent::recv_from_orig_dst(&socket, &mut buffer).await {
+                Ok(r) => r,
+                Err(e) => {
+                    warn!("Inbound {}/UDP failed to receive: {}", tag, e);
+                    continue;
+                }
+            };
+        let mut packet = UdpPacket::new(dst_addr, BytesMut::from(&buffer[0..size]));
+
+        if let Some(sender) = sessions.get(&(src_addr, dst_addr)) {
+            match sender.send(packet).await {
+                Ok(()) => continue,
+                // Receiver dropped
+                Err(e) => packet = e.0,
+            }
+        }
+        sessions.retain(|_, s| !s.is_closed());
+
+        let (read_sender, read_receiver) = channel(10);
+        let (write_sender, mut write_receiver) = channel::<UdpPacket>(10);
+
+        tokio::spawn(async move {
+            // Replies must come from the address the client sent to
+            let mut reply_sockets = HashMap::new();
+            while let Some(packet) = write_receiver.recv().await {
+                let from = packet.target().unwrap_or(dst_addr);
+                let socket = match reply_sockets.entry(from) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => match transparent::bind_udp_reply(from) {
+                        Ok(socket) => e.insert(socket),
+                        Err(err) => {
+                            warn!("Failed to bind UDP reply socket on {}: {}", from, err);
+                            continue;
+                        }
+                    },
+                };
+                if socket.send_to(&packet, src_addr).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        sessions.insert((src_addr, dst_addr), read_sender.clone());
+        read_sender.send(packet).await.unwrap();
+
+        let mut conn = Connection::new(
+            src_addr,
+            tag.clone(),
+            inbound.pipeline.clone(),
+            TransportType::Udp,
+        );
+        conn.set_var(vars::ORIGINAL_DST, dst_addr);
+        info!(
+            "({}) Inbound {}/UDP accepted from {} to {}",
+            conn.id, tag, src_addr, dst_addr
+        );
+
+        sender
+            .send((
+                conn,
+                UdpStream::new(ReceiverStream::new(read_receiver), write_sender).into(),
+            ))
+            .unwrap();
+    }
+}
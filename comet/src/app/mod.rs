@@ -1,6 +1,7 @@
 pub mod dispatcher;
 pub mod inbound_manager;
 pub mod metrics;
+pub mod mux;
 pub mod outbound_manager;
 pub mod plumber;
 // pub mod api;
@@ -0,0 +1,393 @@
+//! Mux.cool client, sharing long-lived outbound sessions between connections.
+//!
+//! ```text
+//! +-------------+----------+-------------+----------+
+//! | Meta Length | Metadata | Data Length |   Data   |
+//! +-------------+----------+-------------+----------+
+//! |      2      |    L     |      2      | Variable |
+//! +-------------+----------+-------------+----------+
+//!
+//! Metadata:
+//! +------------+--------+--------+-------------------------------------------+
+//! | Session ID | Status | Option | New only: Network | Port | ATYP | Address |
+//! +------------+--------+--------+-------------------------------------------+
+//! |     2      |   1    |   1    |      1      |  2   |  1   |   Variable   |
+//! +------------+--------+--------+-------------------------------------------+
+//! ```
+//!
+//! Data follows the metadata when `Option` has `OPT_DATA` set. Sessions are opened by running
+//! the outbound towards `v1.mux.cool`, for which VMess and VLESS send their mux command.
+use super::dispatcher::connect_outbound;
+use crate::config::MuxConfig;
+use crate::prelude::*;
+use crate::utils::socks_addr::{encode_host, AddrTypes};
+use anyhow::bail;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::WriteHalf;
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::time::timeout;
+
+pub const MUX_DOMAIN: &str = "v1.mux.cool";
+const MUX_PORT: u16 = 9527;
+
+const STATUS_NEW: u8 = 0x01;
+const STATUS_KEEP: u8 = 0x02;
+const STATUS_END: u8 = 0x03;
+const OPT_DATA: u8 = 0x01;
+const NETWORK_TCP: u8 = 0x01;
+
+const ADDR_TYPES: AddrTypes = AddrTypes {
+    ipv4: 1,
+    domain: 2,
+    ipv6: 3,
+};
+
+const MAX_CHUNK: usize = 8192;
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a stream may leave its buffer full before it is reset.
+const STREAM_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whether a destination asks for a mux session rather than a single connection.
+pub fn is_mux_dest(dest: &DestAddr) -> bool {
+    dest.domain.as_deref() == Some(MUX_DOMAIN)
+}
+
+/// Sessions of one outbound.
+pub struct MuxPool {
+    config: MuxConfig,
+    sessions: Mutex<Vec<Arc<Session>>>,
+    /// Held while opening a stream, so that concurrent connections share a new session.
+    opening: tokio::sync::Mutex<()>,
+}
+
+impl MuxPool {
+    pub fn new(config: MuxConfig) -> Self {
+        Self {
+            config,
+            sessions: Mutex::new(Vec::new()),
+            opening: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Opens a stream to `conn.dest_addr`, connecting a new session if all are full.
+    pub async fn open(
+        &self,
+        tag: &str,
+        conn: &mut Connection,
+        ctx: &AppContextRef,
+    ) -> Result<ProxyStream> {
+        let mut new_meta = BytesMut::with_capacity(1 + 2 + 1 + 256);
+        new_meta.put_u8(NETWORK_TCP);
+        new_meta.put_u16(conn.dest_addr.port_or_error()?);
+        encode_host(&conn.dest_addr, &mut new_meta, ADDR_TYPES)?;
+
+        let _opening = self.opening.lock().await;
+        loop {
+            let (session, fresh) = match self.pick() {
+                Some(session) => (session, false),
+                None => {
+                    let session = Session::connect(tag, conn, ctx, &self.config).await?;
+                    self.sessions.lock().unwrap().push(session.clone());
+                    (session, true)
+                }
+            };
+            // An existing session may have just been closed for idling
+            if let Some(stream) = session.open_stream(new_meta.clone()) {
+                return Ok(stream.into());
+            }
+            if fresh {
+                bail!("Mux session to {} closed right after opening", tag);
+            }
+        }
+    }
+
+    fn pick(&self) -> Option<Arc<Session>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|s| !s.state.lock().unwrap().closed);
+        sessions
+            .iter()
+            .find(|s| s.state.lock().unwrap().streams.len() < self.config.concurrency)
+            .cloned()
+    }
+}
+
+struct SessionState {
+    streams: HashMap<u16, Sender<Bytes>>,
+    next_id: u16,
+    idle_since: Option<Instant>,
+    closed: bool,
+}
+
+struct Session {
+    writer: tokio::sync::Mutex<WriteHalf<RWPair>>,
+    state: Mutex<SessionState>,
+}
+
+impl Session {
+    async fn connect(
+        tag: &str,
+        conn: &Connection,
+        ctx: &AppContextRef,
+        config: &MuxConfig,
+    ) -> Result<Arc<Self>> {
+        let mut session_conn = Connection::new(
+            conn.src_addr,
+            conn.inbound_tag.clone(),
+            None,
+            TransportType::Tcp,
+        );
+        session_conn.dest_addr = DestAddr::new_domain(MUX_DOMAIN, MUX_PORT);
+        let stream = connect_outbound(tag, &mut session_conn, ctx)
+            .await?
+            .into_tcp()?;
+        info!("({}) Mux session to {} opened", session_conn.id, tag);
+
+        let (reader, writer) = tokio::io::split(stream);
+        let session = Arc::new(Self {
+            writer: tokio::sync::Mutex::new(writer),
+            state: Mutex::new(SessionState {
+                streams: HashMap::new(),
+                next_id: 0,
+                idle_since: Some(Instant::now()),
+                closed: false,
+            }),
+        });
+
+        let idle_timeout = Duration::from_secs(config.idle_timeout.max(1) as u64);
+        let this = session.clone();
+        let id = session_conn.id;
+        tokio::spawn(async move {
+            let mut reader = reader;
+            tokio::select! {
+                res = this.read_frames(&mut reader) => {
+                    if let Err(e) = res {
+                        debug!("({}) Mux session closed: {}", id, e);
+                    }
+                }
+                _ = this.watch_idle(idle_timeout) => debug!("({}) Mux session idle", id),
+            }
+            this.close().await;
+        });
+
+        Ok(session)
+    }
+
+    /// Returns `None` if the session is closed.
+    fn open_stream(self: &Arc<Self>, new_meta: BytesMut) -> Option<RWPair> {
+        let (sender, mut receiver) = channel::<Bytes>(16);
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            if state.closed {
+                return None;
+            }
+            loop {
+                state.next_id = state.next_id.wrapping_add(1);
+                if state.next_id != 0 && !state.streams.contains_key(&state.next_id) {
+                    break;
+                }
+            }
+            let id = state.next_id;
+            state.streams.insert(id, sender);
+            state.idle_since = None;
+            id
+        };
+
+        let (local, remote) = tokio::io::duplex(MAX_CHUNK);
+        let (mut remote_read, mut remote_write) = tokio::io::split(remote);
+
+        let this = self.clone();
+        tokio::spawn(async move {
+            // After a half-close the stream stays open for the rest of the response
+            if let Err(e) = this.uplink(id, &new_meta, &mut remote_read).await {
+                debug!("Mux stream {} closed: {}", id, e);
+                this.remove(id);
+            }
+        });
+        let this = self.clone();
+        tokio::spawn(async move {
+            while let Some(data) = receiver.recv().await {
+                if remote_write.write_all(&data).await.is_err() {
+                    // The local stream is gone
+                    this.remove(id);
+                    return;
+                }
+            }
+            let _ = remote_write.shutdown().await;
+        });
+
+        Some(RWPair::new(local))
+    }
+
+    async fn uplink<R: AsyncRead + Unpin>(
+        &self,
+        id: u16,
+        new_meta: &[u8],
+        reader: &mut R,
+    ) -> Result<()> {
+        self.write_frame(id, STATUS_NEW, new_meta, None).await?;
+        let mut buf = vec![0u8; MAX_CHUNK];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            if !self.state.lock().unwrap().streams.contains_key(&id) {
+                // Ended by the server
+                return Ok(());
+            }
+            self.write_frame(id, STATUS_KEEP, &[], Some(&buf[..n]))
+                .await?;
+        }
+        self.write_frame(id, STATUS_END, &[], None).await
+    }
+
+    async fn write_frame(
+        &self,
+        id: u16,
+        status: u8,
+        meta: &[u8],
+        data: Option<&[u8]>,
+    ) -> Result<()> {
+        let buf = encode_frame(id, status, meta, data);
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&buf).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    async fn read_frames<R: AsyncRead + Unpin>(&self, reader: &mut R) -> Result<()> {
+        loop {
+            let Frame { id, status, data } = read_frame(reader).await?;
+            match (status, data) {
+                (STATUS_KEEP, Some(data)) => {
+                    let sender = self.state.lock().unwrap().streams.get(&id).cloned();
+                    if let Some(sender) = sender {
+                        // Slow streams hold up the session like a slow reader of a single
+                        // connection would, and are only reset once they stop reading.
+                        let sent = match timeout(STREAM_STALL_TIMEOUT, sender.send(data)).await {
+                            Ok(res) => res.is_ok(),
+                            Err(_) => {
+                                warn!("Resetting mux stream {} which is not reading", id);
+                                false
+                            }
+                        };
+                        if !sent {
+                            self.remove(id);
+                            self.write_frame(id, STATUS_END, &[], None).await?;
+                        }
+                    }
+                }
+                (STATUS_END, _) => self.remove(id),
+                // KeepAlive, and New which is only sent by clients
+                _ => {}
+            }
+        }
+    }
+
+    async fn watch_idle(&self, idle_timeout: Duration) {
+        let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL.min(idle_timeout));
+        loop {
+            interval.tick().await;
+            let mut state = self.state.lock().unwrap();
+            if matches!(state.idle_since, Some(since) if since.elapsed() >= idle_timeout) {
+                state.closed = true;
+                return;
+            }
+        }
+    }
+
+    fn remove(&self, id: u16) {
+        let mut state = self.state.lock().unwrap();
+        if state.streams.remove(&id).is_some() && state.streams.is_empty() {
+            state.idle_since = Some(Instant::now());
+        }
+    }
+
+    async fn close(&self) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.closed = true;
+            state.streams.clear();
+        }
+        let _ = self.writer.lock().await.shutdown().await;
+    }
+}
+
+/// Frame received from the server. Metadata beyond the header is skipped.
+struct Frame {
+    id: u16,
+    status: u8,
+    data: Option<Bytes>,
+}
+
+fn encode_frame(id: u16, status: u8, meta: &[u8], data: Option<&[u8]>) -> BytesMut {
+    let data_len = data.map_or(0, |d| 2 + d.len());
+    let mut buf = BytesMut::with_capacity(2 + 4 + meta.len() + data_len);
+    buf.put_u16((4 + meta.len()) as u16);
+    buf.put_u16(id);
+    buf.put_u8(status);
+    buf.put_u8(if data.is_some() { OPT_DATA } else { 0 });
+    buf.put_slice(meta);
+    if let Some(data) = data {
+        buf.put_u16(data.len() as u16);
+        buf.put_slice(data);
+    }
+    buf
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Frame> {
+    let meta_len = reader.read_u16().await? as usize;
+    if meta_len < 4 {
+        bail!("Invalid mux metadata length: {}", meta_len);
+    }
+    let mut meta = BytesMut::zeroed(meta_len);
+    reader.read_exact(&mut meta).await?;
+    let id = u16::from_be_bytes([meta[0], meta[1]]);
+    let (status, option) = (meta[2], meta[3]);
+
+    let data = if option & OPT_DATA != 0 {
+        let len = reader.read_u16().await? as usize;
+        let mut data = BytesMut::zeroed(len);
+        reader.read_exact(&mut data).await?;
+        Some(data.freeze())
+    } else {
+        None
+    };
+    Ok(Frame { id, status, data })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn frame_round_trip() {
+        let mut stream = BytesMut::new();
+        stream.extend_from_slice(&encode_frame(1, STATUS_NEW, &[NETWORK_TCP, 0, 80], None));
+        stream.extend_from_slice(&encode_frame(1, STATUS_KEEP, &[], Some(b"hello")));
+        stream.extend_from_slice(&encode_frame(2, STATUS_KEEP, &[], Some(&[])));
+        stream.extend_from_slice(&encode_frame(1, STATUS_END, &[], None));
+        let mut reader = &stream[..];
+
+        let expected: [(u16, u8, Option<&[u8]>); 4] = [
+            (1, STATUS_NEW, None),
+            (1, STATUS_KEEP, Some(b"hello")),
+            (2, STATUS_KEEP, Some(b"")),
+            (1, STATUS_END, None),
+        ];
+        for (id, status, data) in expected.iter() {
+            let frame = read_frame(&mut reader).await.unwrap();
+            assert_eq!((frame.id, frame.status), (*id, *status));
+            assert_eq!(frame.data.as_deref(), *data);
+        }
+        assert!(reader.is_empty());
+        assert!(read_frame(&mut reader).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn short_metadata_is_rejected() {
+        let mut reader: &[u8] = &[0, 2, 0, 1];
+        assert!(read_frame(&mut reader).await.is_err());
+    }
+}
@@ -1,15 +1,37 @@
-use crate::config::{Config, OutboundTransportType};
+use crate::app::mux::MuxPool;
+use crate::config::{Config, IpPreference, OutboundTransportType};
 use crate::handler::outbound::OutboundHandler;
 use crate::prelude::*;
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use std::collections::HashMap;
 use std::time::Duration;
 use tokio::time::timeout;
 
+/// Processors that can carry mux.cool sessions.
+const MUX_PROCESSORS: [&str; 2] = ["vmess_client", "vless_client"];
+
+/// Mux sessions are opened to a placeholder destination that only VMess and VLESS understand.
+fn check_mux_pipeline(tag: &str, pipeline: Option<&str>, config: &Config) -> Result<()> {
+    let last = pipeline
+        .and_then(|p| config.pipelines.get(p))
+        .and_then(|processors| processors.last())
+        .and_then(|processor| processor.get("type"))
+        .and_then(YamlValue::as_str);
+    match last {
+        Some(name) if MUX_PROCESSORS.contains(&name) => Ok(()),
+        _ => bail!(
+            "Outbound {} uses mux, but its pipeline does not end in {}",
+            tag,
+            MUX_PROCESSORS.join(" or ")
+        ),
+    }
+}
+
 struct OutboundInstance {
     pipeline: Option<SmolStr>,
     handler: Box<dyn OutboundHandler>,
     timeout: u32,
+    mux: Option<MuxPool>,
 }
 
 pub struct OutboundManager {
@@ -17,18 +39,34 @@ pub struct OutboundManager {
 }
 
 impl OutboundManager {
-    pub fn new(config: &Config) -> Self {
+    pub fn new(config: &Config) -> Result<Self> {
         use crate::handler::outbound::*;
 
         let outbounds = config
             .outbounds
             .iter()
             .map(|(tag, outbound)| {
+                let dials = !matches!(
+                    outbound.typ,
+                    OutboundTransportType::Dashboard | OutboundTransportType::DnsServer { .. }
+                );
+                if !dials && outbound.ip_preference != IpPreference::default() {
+                    bail!(
+                        "Outbound {} does not connect anywhere, ip_preference is unused",
+                        tag
+                    );
+                }
+                if outbound.mux.is_some() {
+                    check_mux_pipeline(tag, outbound.pipeline.as_deref(), config)?;
+                }
                 let handler: Box<dyn OutboundHandler> = match outbound.typ {
                     OutboundTransportType::Tcp => Box::new(TcpHandler::new(outbound)),
                     OutboundTransportType::Udp => Box::new(UdpHandler::new(outbound)),
                     OutboundTransportType::Dashboard => Box::new(DashboardHandler::new(outbound)),
                     OutboundTransportType::TcpUdp => Box::new(TcpUdpHandler::new(outbound)),
+                    OutboundTransportType::DnsServer { .. } => {
+                        Box::new(DnsServerHandler::new(outbound))
+                    }
                     #[cfg(feature = "gun-transport")]
                     OutboundTransportType::Gun { .. } => Box::new(GunHandler::new(outbound)),
                 };
@@ -36,12 +74,13 @@ impl OutboundManager {
                     pipeline: outbound.pipeline.clone(),
                     timeout: outbound.timeout,
                     handler,
+                    mux: outbound.mux.clone().map(MuxPool::new),
                 };
-                (tag.clone(), instance)
+                Ok((tag.clone(), instance))
             })
-            .collect();
+            .collect::<Result<_>>()?;
 
-        Self { outbounds }
+        Ok(Self { outbounds })
     }
 
     pub async fn connect(
@@ -69,6 +108,11 @@ impl OutboundManager {
             .map(|r| r.as_str()))
     }
 
+    /// Sessions to open streams on, if the outbound is multiplexed.
+    pub fn get_mux(&self, tag: &str) -> Result<Option<&MuxPool>> {
+        Ok(self.get_outbound(tag)?.mux.as_ref())
+    }
+
     fn get_outbound(&self, tag: &str) -> Result<&OutboundInstance> {
         let outbound = self
             .outbounds
@@ -178,6 +178,18 @@ impl Connection {
     pub fn get_var<T: Any + Send + Sync>(&self, key: &str) -> Option<&T> {
         self.variables.get(key).and_then(|v| v.downcast_ref::<T>())
     }
+
+    /// Removes a variable, returning it if it exists and is of type `T`.
+    pub fn take_var<T: Any + Send + Sync>(&mut self, key: &str) -> Option<T> {
+        let value = self.variables.remove(key)?;
+        match value.downcast::<T>() {
+            Ok(v) => Some(*v),
+            Err(v) => {
+                self.variables.insert(key.into(), v);
+                None
+            }
+        }
+    }
 }
 
 impl fmt::Display for Connection {
@@ -194,4 +206,14 @@ pub mod vars {
     pub static DEST: &str = "dest";
     pub static SS_KEY: &str = "ss-key";
     pub static SS_SALT: &str = "ss-salt";
+    /// Authenticated username of an inbound connection (`SmolStr`).
+    pub static USER: &str = "user";
+    /// SNI received by `tls_server` (`SmolStr`).
+    pub static TLS_SNI: &str = "tls-sni";
+    /// ALPN protocol negotiated by `tls_server` (`SmolStr`).
+    pub static TLS_ALPN: &str = "tls-alpn";
+    /// Destination before REDIRECT or TPROXY, set by transparent inbounds (`SocketAddr`).
+    pub static ORIGINAL_DST: &str = "original-dst";
+    /// Local address of a TCP inbound connection (`SocketAddr`).
+    pub static LOCAL_ADDR: &str = "local-addr";
 }
@@ -35,10 +35,14 @@ pub struct AppContext {
 
 impl AppContext {
     pub async fn new(config: &Config) -> Result<Self> {
+        #[cfg(target_os = "linux")]
+        crate::net_wrapper::init_outbound_socket(&config.outbound_socket);
+
         Ok(AppContext {
             plumber: Arc::new(Plumber::new(config).with_context(|| "When creating plumber")?),
             inbound_manager: Arc::new(InboundManager::new(config)),
-            outbound_manager: OutboundManager::new(config),
+            outbound_manager: OutboundManager::new(config)
+                .with_context(|| "When creating outbound manager")?,
             metrics: Metrics::new(config),
             router: Router::new(config).with_context(|| "When creating router")?,
             #[cfg(target_os = "android")]
@@ -1,14 +1,14 @@
 use crate::{dns::DnsConfig, prelude::*, router::RouterConfig};
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use serde::Deserialize;
 use smol_str::SmolStr;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr};
 use std::{collections::HashMap, path::PathBuf};
 use tokio::fs::{create_dir_all, File};
 
 pub mod outbound;
 
-pub use outbound::{Outbound, OutboundAddr, OutboundTransportType};
+pub use outbound::{IpPreference, MuxConfig, Outbound, OutboundAddr, OutboundTransportType};
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct Config {
@@ -29,6 +29,9 @@ pub struct Config {
     pub server_providers: HashMap<SmolStr, crate::server_provider::ProviderConfig>,
     #[serde(default)]
     pub dns: DnsConfig,
+    #[cfg(target_os = "linux")]
+    #[serde(default)]
+    pub outbound_socket: OutboundSocketConfig,
 }
 
 fn default_current_dir() -> PathBuf {
@@ -50,14 +53,87 @@ pub struct Inbound {
 pub enum InboundTransportType {
     Tcp,
     Udp,
+    #[cfg(feature = "gun-transport")]
+    Gun {
+        #[serde(default = "default_gun_service_name")]
+        service_name: SmolStr,
+    },
+    /// Captures traffic routed to a TUN interface, ignoring `port` and `listen`.
+    #[cfg(target_os = "linux")]
+    Tun {
+        #[serde(default = "default_tun_name")]
+        name: SmolStr,
+        #[serde(default = "default_tun_address")]
+        address: Ipv4Addr,
+        #[serde(default = "default_tun_mtu")]
+        mtu: u16,
+    },
+}
+
+#[cfg(feature = "gun-transport")]
+pub(crate) fn default_gun_service_name() -> SmolStr {
+    "GunService".into()
+}
+
+#[cfg(target_os = "linux")]
+fn default_tun_name() -> SmolStr {
+    "comet0".into()
+}
+
+#[cfg(target_os = "linux")]
+fn default_tun_address() -> Ipv4Addr {
+    Ipv4Addr::new(198, 18, 0, 1)
+}
+
+#[cfg(target_os = "linux")]
+fn default_tun_mtu() -> u16 {
+    1500
 }
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct InboundTransportConfig {
     #[serde(flatten)]
     pub r#type: InboundTransportType,
-    pub port: u16,
+    /// Required by every transport except TUN.
+    pub port: Option<u16>,
     pub listen: Option<IpAddr>,
+    /// Recovers the original destination of redirected traffic.
+    #[cfg(target_os = "linux")]
+    #[serde(default)]
+    pub transparent: Option<TransparentMode>,
+}
+
+impl InboundTransportConfig {
+    /// Port the inbound listens on, 0 for a TUN.
+    pub fn listen_port(&self) -> Result<u16> {
+        match (&self.r#type, self.port) {
+            #[cfg(target_os = "linux")]
+            (InboundTransportType::Tun { .. }, _) => Ok(0),
+            (_, Some(port)) => Ok(port),
+            (_, None) => bail!("A port is required"),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransparentMode {
+    /// `SO_ORIGINAL_DST` of connections, TCP only.
+    Redirect,
+    /// `IP_TRANSPARENT` sockets, for both TCP and UDP.
+    Tproxy,
+}
+
+/// Options of sockets Comet connects out with, so that their traffic can be routed around a
+/// TUN inbound or a transparent proxy.
+#[cfg(target_os = "linux")]
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct OutboundSocketConfig {
+    /// `SO_MARK` for policy routing, requires `CAP_NET_ADMIN`.
+    pub mark: Option<u32>,
+    /// Interface to send through with `SO_BINDTODEVICE`.
+    pub bind_device: Option<SmolStr>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -85,6 +161,12 @@ pub async fn load_file(path: &str) -> Result<Config> {
 
 pub async fn load_string(input: &str) -> Result<Config> {
     let config: Config = serde_yaml::from_str(input)?;
+    for (tag, inbound) in &config.inbounds {
+        inbound
+            .transport
+            .listen_port()
+            .with_context(|| format!("When loading inbound {}", tag))?;
+    }
 
     create_dir_all(&config.data_dir).await?;
 
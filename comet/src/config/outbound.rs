@@ -10,6 +10,9 @@ pub struct Outbound {
     pub metering: bool,
     #[serde(default)]
     pub timeout: u32,
+    pub mux: Option<MuxConfig>,
+    #[serde(default)]
+    pub ip_preference: IpPreference,
     #[serde(flatten)]
     pub typ: OutboundTransportType,
 }
@@ -22,6 +25,10 @@ pub enum OutboundTransportType {
     #[serde(rename = "tcp+udp")]
     TcpUdp,
     Dashboard,
+    DnsServer {
+        #[serde(default)]
+        mode: crate::dns::server::DnsServerMode,
+    },
     #[cfg(feature = "gun-transport")]
     Gun {
         config: crate::handler::outbound::GunConfig,
@@ -34,9 +41,118 @@ impl Default for OutboundTransportType {
     }
 }
 
+/// Shares mux.cool sessions between the TCP connections of an outbound.
+#[derive(Deserialize, Clone, Debug)]
+pub struct MuxConfig {
+    /// Maximum number of connections in one session.
+    #[serde(default = "default_mux_concurrency")]
+    pub concurrency: usize,
+    /// Seconds a session without connections is kept open.
+    #[serde(default = "default_mux_idle_timeout")]
+    pub idle_timeout: u32,
+}
+
+fn default_mux_concurrency() -> usize {
+    8
+}
+
+fn default_mux_idle_timeout() -> u32 {
+    60
+}
+
+/// Address families used for resolved destinations and the order they are tried in.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IpPreference {
+    /// Starts with the family of the first resolved address.
+    #[default]
+    Auto,
+    Ipv4Only,
+    Ipv6Only,
+    PreferIpv4,
+    PreferIpv6,
+}
+
+impl IpPreference {
+    /// Filters `ips` and interleaves the families, preferred one first.
+    pub fn arrange(self, ips: Vec<IpAddr>) -> Vec<IpAddr> {
+        let first_v6 = ips.first().is_some_and(IpAddr::is_ipv6);
+        let (v4, v6): (Vec<_>, Vec<_>) = ips.into_iter().partition(IpAddr::is_ipv4);
+        let (first, second) = match self {
+            IpPreference::Ipv4Only => return v4,
+            IpPreference::Ipv6Only => return v6,
+            IpPreference::PreferIpv4 => (v4, v6),
+            IpPreference::PreferIpv6 => (v6, v4),
+            IpPreference::Auto if first_v6 => (v6, v4),
+            IpPreference::Auto => (v4, v6),
+        };
+
+        let mut arranged = Vec::with_capacity(first.len() + second.len());
+        let (mut first, mut second) = (first.into_iter(), second.into_iter());
+        loop {
+            match (first.next(), second.next()) {
+                (None, None) => break,
+                (a, b) => arranged.extend(a.into_iter().chain(b)),
+            }
+        }
+        arranged
+    }
+}
+
 #[derive(Deserialize, Clone, Debug)]
 #[serde(untagged)]
 pub enum OutboundAddr {
     Ip(IpAddr),
     Domain(SmolStr),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ips(list: &[&str]) -> Vec<IpAddr> {
+        list.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn arrange() {
+        let resolved = ips(&["::1", "1.1.1.1", "::2", "2.2.2.2", "3.3.3.3"]);
+        let cases = [
+            (
+                IpPreference::Ipv4Only,
+                ips(&["1.1.1.1", "2.2.2.2", "3.3.3.3"]),
+            ),
+            (IpPreference::Ipv6Only, ips(&["::1", "::2"])),
+            (
+                IpPreference::PreferIpv4,
+                ips(&["1.1.1.1", "::1", "2.2.2.2", "::2", "3.3.3.3"]),
+            ),
+            (
+                IpPreference::PreferIpv6,
+                ips(&["::1", "1.1.1.1", "::2", "2.2.2.2", "3.3.3.3"]),
+            ),
+            (
+                IpPreference::Auto,
+                ips(&["::1", "1.1.1.1", "::2", "2.2.2.2", "3.3.3.3"]),
+            ),
+        ];
+        for (preference, expected) in cases.iter() {
+            assert_eq!(
+                &preference.arrange(resolved.clone()),
+                expected,
+                "{:?}",
+                preference
+            );
+        }
+    }
+
+    #[test]
+    fn arrange_auto_follows_first_family() {
+        let resolved = ips(&["1.1.1.1", "::1", "::2"]);
+        assert_eq!(
+            IpPreference::Auto.arrange(resolved),
+            ips(&["1.1.1.1", "::1", "::2"])
+        );
+        assert!(IpPreference::Ipv6Only.arrange(ips(&["1.1.1.1"])).is_empty());
+    }
+}
@@ -69,19 +69,19 @@ impl SsCrypterInner {
         }
     }
 
-    fn encrypt_packet(&self, packet: &mut [u8], nonce: &[u8]) {
+    fn encrypt_packet(&self, packet: &mut [u8], nonce: &[u8], aad: &[u8]) {
         match self {
-            SsCrypterInner::Aes128Gcm(c) => c.aead_encrypt_slice(nonce, &[], packet),
-            SsCrypterInner::Aes256Gcm(c) => c.aead_encrypt_slice(nonce, &[], packet),
-            SsCrypterInner::Chacha20Poly1305(c) => c.aead_encrypt_slice(nonce, &[], packet),
+            SsCrypterInner::Aes128Gcm(c) => c.aead_encrypt_slice(nonce, aad, packet),
+            SsCrypterInner::Aes256Gcm(c) => c.aead_encrypt_slice(nonce, aad, packet),
+            SsCrypterInner::Chacha20Poly1305(c) => c.aead_encrypt_slice(nonce, aad, packet),
         }
     }
 
-    fn decrypt_packet(&self, packet: &mut [u8], nonce: &[u8]) -> bool {
+    fn decrypt_packet(&self, packet: &mut [u8], nonce: &[u8], aad: &[u8]) -> bool {
         match self {
-            SsCrypterInner::Aes128Gcm(c) => c.aead_decrypt_slice(nonce, &[], packet),
-            SsCrypterInner::Aes256Gcm(c) => c.aead_decrypt_slice(nonce, &[], packet),
-            SsCrypterInner::Chacha20Poly1305(c) => c.aead_decrypt_slice(nonce, &[], packet),
+            SsCrypterInner::Aes128Gcm(c) => c.aead_decrypt_slice(nonce, aad, packet),
+            SsCrypterInner::Aes256Gcm(c) => c.aead_decrypt_slice(nonce, aad, packet),
+            SsCrypterInner::Chacha20Poly1305(c) => c.aead_decrypt_slice(nonce, aad, packet),
         }
     }
 }
@@ -110,10 +110,9 @@ impl<N: NonceSeq> SsCrypter<N> {
     pub fn tag_len(&self) -> usize {
         self.inner.tag_len()
     }
-}
 
-impl<N: NonceSeq> AeadCrypter for SsCrypter<N> {
-    fn update(&mut self, in_out: &mut [u8]) -> Result<usize> {
+    /// Like `update`, authenticating `aad` as well.
+    pub fn update_with_aad(&mut self, aad: &[u8], in_out: &mut [u8]) -> Result<usize> {
         let tag_len = self.inner.tag_len();
         debug_assert!(in_out.len() >= tag_len);
 
@@ -124,11 +123,11 @@ impl<N: NonceSeq> AeadCrypter for SsCrypter<N> {
 
         match self.mode {
             CrypterMode::Encrypt => {
-                self.inner.encrypt_packet(in_out, &nonce[..]);
+                self.inner.encrypt_packet(in_out, &nonce[..], aad);
                 Ok(in_out.len())
             }
             CrypterMode::Decrypt => {
-                if self.inner.decrypt_packet(in_out, &nonce[..]) {
+                if self.inner.decrypt_packet(in_out, &nonce[..], aad) {
                     Ok(in_out.len() - tag_len)
                 } else {
                     Err(anyhow!("Decryption failed"))
@@ -138,6 +137,29 @@ impl<N: NonceSeq> AeadCrypter for SsCrypter<N> {
     }
 }
 
+impl<N: NonceSeq> AeadCrypter for SsCrypter<N> {
+    fn update(&mut self, in_out: &mut [u8]) -> Result<usize> {
+        self.update_with_aad(&[], in_out)
+    }
+}
+
 pub trait NonceSeq: Send + Sync {
     fn advance(&mut self) -> Option<[u8; 12]>;
 }
+
+/// The same nonce every time, for one-shot crypters.
+pub struct FixedNonce([u8; 12]);
+
+impl FixedNonce {
+    pub fn new(nonce: &[u8]) -> Self {
+        let mut this = Self([0; 12]);
+        this.0.copy_from_slice(&nonce[..12]);
+        this
+    }
+}
+
+impl NonceSeq for FixedNonce {
+    fn advance(&mut self) -> Option<[u8; 12]> {
+        Some(self.0)
+    }
+}
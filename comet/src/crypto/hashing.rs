@@ -7,6 +7,7 @@ pub enum HashKind {
     Md5,
     Sha1,
     Sha224,
+    Sha256,
 }
 
 impl HashKind {
@@ -15,6 +16,7 @@ impl HashKind {
             HashKind::Md5 => 16,
             HashKind::Sha1 => 20,
             HashKind::Sha224 => 28,
+            HashKind::Sha256 => 32,
         }
     }
 }
@@ -29,6 +31,7 @@ enum SsHasherInner {
     Md5(ss_hash::Md5),
     Sha1(ss_hash::Sha1),
     Sha224(ss_hash::Sha224),
+    Sha256(ss_hash::Sha256),
 }
 
 impl SsHasherInner {
@@ -37,6 +40,7 @@ impl SsHasherInner {
             HashKind::Md5 => Self::Md5(ss_hash::Md5::new()),
             HashKind::Sha1 => Self::Sha1(ss_hash::Sha1::new()),
             HashKind::Sha224 => Self::Sha224(ss_hash::Sha224::new()),
+            HashKind::Sha256 => Self::Sha256(ss_hash::Sha256::new()),
         }
     }
     fn update(&mut self, data: &[u8]) {
@@ -44,6 +48,7 @@ impl SsHasherInner {
             Self::Md5(s) => s.update(data),
             Self::Sha1(s) => s.update(data),
             Self::Sha224(s) => s.update(data),
+            Self::Sha256(s) => s.update(data),
         }
     }
 
@@ -52,6 +57,7 @@ impl SsHasherInner {
             Self::Md5(s) => Bytes::copy_from_slice(&s.finalize()),
             Self::Sha1(s) => Bytes::copy_from_slice(&s.finalize()),
             Self::Sha224(s) => Bytes::copy_from_slice(&s.finalize()),
+            Self::Sha256(s) => Bytes::copy_from_slice(&s.finalize()),
         }
     }
 }
@@ -152,3 +158,16 @@ pub fn sign_bytes(kind: HashKind, key: &[u8], input: &[u8]) -> Bytes {
     signer.finish()
 }
 
+/// HKDF (RFC 5869), filling `okm` with key material derived from `ikm`.
+pub fn hkdf(kind: HashKind, salt: &[u8], ikm: &[u8], info: &[u8], okm: &mut [u8]) {
+    let prk = sign_bytes(kind, salt, ikm);
+    let mut block = Bytes::new();
+    for (i, chunk) in okm.chunks_mut(kind.output_len()).enumerate() {
+        let mut signer = new_signer(kind, &prk);
+        signer.update(&block);
+        signer.update(info);
+        signer.update(&[i as u8 + 1]);
+        block = signer.finish();
+        chunk.copy_from_slice(&block[..chunk.len()]);
+    }
+}
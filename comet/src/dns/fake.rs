@@ -0,0 +1,255 @@
+//! Fake IP pool mapping domains to addresses in configured CIDRs.
+//!
+//! A domain gets the same offset in the IPv4 and the IPv6 range, allocated sequentially and
+//! kept as long as the domain is used. When the pool is full, the least recently used offset
+//! without live connections is recycled.
+use crate::prelude::*;
+use crate::router::matching::DomainCondition;
+use anyhow::bail;
+use ipnetwork::{Ipv4Network, Ipv6Network};
+use lru_cache::LruCache;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// File in `data_dir` the pool is persisted to.
+const PERSIST_FILE: &str = "fake_ip.json";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FakeIpConfig {
+    #[serde(default = "default_ipv4")]
+    ipv4: Ipv4Network,
+    /// AAAA queries get no fake answers if unset.
+    ipv6: Option<Ipv6Network>,
+    /// Keeps mappings across restarts.
+    #[serde(default)]
+    persist: bool,
+    /// Domains resolved to their real IPs.
+    #[serde(default)]
+    exclude: Vec<DomainCondition>,
+}
+
+fn default_ipv4() -> Ipv4Network {
+    Ipv4Network::new(Ipv4Addr::new(10, 233, 0, 0), 16).unwrap()
+}
+
+impl Default for FakeIpConfig {
+    fn default() -> Self {
+        Self {
+            ipv4: default_ipv4(),
+            ipv6: None,
+            persist: false,
+            exclude: Vec::new(),
+        }
+    }
+}
+
+struct Slot {
+    domain: SmolStr,
+    leases: usize,
+}
+
+struct State {
+    domains: HashMap<SmolStr, u32>,
+    slots: LruCache<u32, Slot>,
+    next: u32,
+    dirty: bool,
+}
+
+pub struct FakePool {
+    ipv4: Ipv4Network,
+    ipv6: Option<Ipv6Network>,
+    exclude: Vec<DomainCondition>,
+    /// Offsets are in `1..capacity`, skipping the network address.
+    capacity: u32,
+    path: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+/// Keeps a fake IP from being recycled while a connection uses it.
+pub struct FakeLease {
+    pool: Arc<FakePool>,
+    offset: u32,
+}
+
+impl Drop for FakeLease {
+    fn drop(&mut self) {
+        let mut state = self.pool.state.lock().unwrap();
+        if let Some(slot) = state.slots.get_mut(&self.offset) {
+            slot.leases -= 1;
+        }
+    }
+}
+
+fn range_size(host_bits: u32) -> u32 {
+    // Also skips the IPv4 broadcast address
+    (1u64 << host_bits.min(32))
+        .saturating_sub(1)
+        .min(u32::MAX as u64) as u32
+}
+
+impl FakePool {
+    pub fn new(config: &FakeIpConfig, data_dir: &std::path::Path) -> Result<Self> {
+        let mut capacity = range_size(32 - config.ipv4.prefix() as u32);
+        if let Some(ipv6) = &config.ipv6 {
+            capacity = capacity.min(range_size(128 - ipv6.prefix() as u32));
+        }
+        if capacity < 2 {
+            bail!("Fake IP range {} is too small", config.ipv4);
+        }
+
+        let pool = Self {
+            ipv4: config.ipv4,
+            ipv6: config.ipv6,
+            exclude: config.exclude.clone(),
+            capacity,
+            path: config.persist.then(|| data_dir.join(PERSIST_FILE)),
+            state: Mutex::new(State {
+                domains: HashMap::new(),
+                slots: LruCache::new(usize::MAX),
+                next: 1,
+                dirty: false,
+            }),
+        };
+        if let Err(e) = pool.load() {
+            warn!("Failed to load fake IP pool: {}", e);
+        }
+        Ok(pool)
+    }
+
+    pub fn is_excluded(&self, domain: &str) -> bool {
+        self.exclude.iter().any(|cond| cond.is_match(domain))
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => self.ipv4.contains(*ip),
+            IpAddr::V6(ip) => self.ipv6.is_some_and(|net| net.contains(*ip)),
+        }
+    }
+
+    fn to_ip(&self, offset: u32, v6: bool) -> Option<IpAddr> {
+        if v6 {
+            let net = u128::from(self.ipv6?.network());
+            Some(Ipv6Addr::from(net + offset as u128).into())
+        } else {
+            let net = u32::from(self.ipv4.network());
+            Some(Ipv4Addr::from(net + offset).into())
+        }
+    }
+
+    fn to_offset(&self, ip: &IpAddr) -> Option<u32> {
+        if !self.contains(ip) {
+            return None;
+        }
+        let offset = match ip {
+            IpAddr::V4(ip) => u32::from(*ip) - u32::from(self.ipv4.network()),
+            IpAddr::V6(ip) => {
+                let offset = u128::from(*ip) - u128::from(self.ipv6?.network());
+                u32::try_from(offset).ok()?
+            }
+        };
+        (1..self.capacity).contains(&offset).then_some(offset)
+    }
+
+    /// Fake IP of `domain`, `Ok(None)` if there is no range for the family.
+    pub fn allocate(&self, domain: &str, v6: bool) -> Result<Option<IpAddr>> {
+        if v6 && self.ipv6.is_none() {
+            return Ok(None);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(&offset) = state.domains.get(domain) {
+            // Marks as recently used
+            state.slots.get_mut(&offset);
+            return Ok(self.to_ip(offset, v6));
+        }
+
+        let offset = if state.next < self.capacity {
+            state.next += 1;
+            state.next - 1
+        } else {
+            let offset = match state.slots.iter().find(|(_, slot)| slot.leases == 0) {
+                Some((&offset, _)) => offset,
+                None => bail!("Fake IP pool exhausted"),
+            };
+            let slot = state.slots.remove(&offset).unwrap();
+            state.domains.remove(&slot.domain);
+            offset
+        };
+
+        let domain = SmolStr::from(domain);
+        state.domains.insert(domain.clone(), offset);
+        state.slots.insert(offset, Slot { domain, leases: 0 });
+        state.dirty = true;
+        Ok(self.to_ip(offset, v6))
+    }
+
+    /// Domain mapped to `ip`, leasing the mapping until the returned guard is dropped.
+    pub fn lease(self: &Arc<Self>, ip: &IpAddr) -> Option<(SmolStr, FakeLease)> {
+        let offset = self.to_offset(ip)?;
+        let mut state = self.state.lock().unwrap();
+        let slot = state.slots.get_mut(&offset)?;
+        slot.leases += 1;
+        let lease = FakeLease {
+            pool: self.clone(),
+            offset,
+        };
+        Some((slot.domain.clone(), lease))
+    }
+
+    pub fn get(&self, ip: &IpAddr) -> Option<SmolStr> {
+        let offset = self.to_offset(ip)?;
+        let mut state = self.state.lock().unwrap();
+        state.slots.get_mut(&offset).map(|slot| slot.domain.clone())
+    }
+
+    fn load(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) if path.exists() => path,
+            _ => return Ok(()),
+        };
+        // Least recently used first
+        let entries: Vec<(SmolStr, u32)> = serde_json::from_slice(&std::fs::read(path)?)?;
+
+        let mut state = self.state.lock().unwrap();
+        for (domain, offset) in entries {
+            // The range may have shrunk
+            if !(1..self.capacity).contains(&offset)
+                || state.slots.contains_key(&offset)
+                || state.domains.contains_key(&domain)
+            {
+                continue;
+            }
+            state.domains.insert(domain.clone(), offset);
+            state.slots.insert(offset, Slot { domain, leases: 0 });
+            state.next = state.next.max(offset + 1);
+        }
+        info!("Loaded {} fake IPs", state.slots.len());
+        Ok(())
+    }
+
+    /// Writes the mappings out if persistence is enabled and they changed.
+    pub async fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let data = {
+            let mut state = self.state.lock().unwrap();
+            if !state.dirty {
+                return Ok(());
+            }
+            state.dirty = false;
+            let entries: Vec<_> = state
+                .slots
+                .iter()
+                .map(|(offset, slot)| (&slot.domain, offset))
+                .collect();
+            serde_json::to_vec(&entries)?
+        };
+        tokio::fs::write(path, data).await?;
+        Ok(())
+    }
+}
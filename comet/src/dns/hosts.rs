@@ -0,0 +1,55 @@
+//! Static mappings from domains to IPs, answered before any resolver.
+use crate::prelude::*;
+use crate::router::matching::DomainCondition;
+use std::net::IpAddr;
+use std::path::Path;
+
+const SYSTEM_HOSTS: &str = "/etc/hosts";
+
+pub struct Hosts {
+    /// Checked in configuration order.
+    patterns: Vec<(DomainCondition, Vec<IpAddr>)>,
+    system: HashMap<SmolStr, Vec<IpAddr>>,
+}
+
+impl Hosts {
+    pub fn new(patterns: Vec<(DomainCondition, Vec<IpAddr>)>, system: bool) -> Result<Self> {
+        let system = if system {
+            parse_hosts(&std::fs::read_to_string(Path::new(SYSTEM_HOSTS))?)
+        } else {
+            HashMap::new()
+        };
+        Ok(Self { patterns, system })
+    }
+
+    /// IPs mapped to `domain`, empty if it is blocked.
+    pub fn get(&self, domain: &str) -> Option<&[IpAddr]> {
+        let domain = domain.trim_end_matches('.');
+        if let Some((_, ips)) = self.patterns.iter().find(|(cond, _)| cond.is_match(domain)) {
+            return Some(ips);
+        }
+        self.system
+            .get(domain.to_ascii_lowercase().as_str())
+            .map(Vec::as_slice)
+    }
+}
+
+/// Parses lines of `IP name [aliases...]`, ignoring comments.
+fn parse_hosts(content: &str) -> HashMap<SmolStr, Vec<IpAddr>> {
+    let mut hosts: HashMap<SmolStr, Vec<IpAddr>> = HashMap::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let ip = match fields.next().map(str::parse::<IpAddr>) {
+            Some(Ok(ip)) => ip,
+            _ => continue,
+        };
+        for name in fields {
+            let ips = hosts.entry(name.to_ascii_lowercase().into()).or_default();
+            if !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+    }
+    hosts
+}
@@ -2,21 +2,19 @@
 use crate::config::Config;
 use crate::net_wrapper::bind_udp;
 use crate::prelude::*;
-use crate::{crypto::random::xor_rng, router::matching::MatchCondition};
-use anyhow::anyhow;
-use lru_cache::LruCache;
-use rand::Rng;
+use crate::router::matching::{DomainCondition, MatchCondition};
+use anyhow::{anyhow, Context as _};
 use socket::{CustomTokioResolver, CustomTokioRuntime};
 use std::{
     borrow::Cow,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::{IpAddr, SocketAddr},
     task::Context,
     time::Duration,
 };
 use std::{str::FromStr, time::SystemTime};
-use tokio::sync::RwLock;
 use trust_dns_resolver::{
     config::{NameServerConfig, NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    error::ResolveError,
     system_conf::read_system_conf,
     AsyncResolver, TokioHandle,
 };
@@ -30,14 +28,19 @@ use trust_dns_proto::{
 };
 use trust_dns_proto::{rr::DNSClass, serialize::binary::BinEncodable};
 use trust_dns_proto::{
-    rr::{Name, Record, RecordType},
+    rr::{Name, RData, Record, RecordType},
     udp::UdpClientStream,
     TokioTime,
 };
 
-use self::{resolver::Resolver, socket::InternalUdpSocket};
+use self::{hosts::Hosts, resolver::Resolver, socket::InternalUdpSocket};
+use fake::FakePool;
+pub use fake::{FakeIpConfig, FakeLease};
 
+mod fake;
+mod hosts;
 mod resolver;
+pub mod server;
 mod socket;
 
 #[serde_as]
@@ -61,14 +64,31 @@ fn default_timeout() -> Duration {
     Duration::from_secs(10)
 }
 
+#[serde_as]
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct DnsConfig {
     #[serde(default)]
     resolvers: Vec<DnsConfigItem>,
+    #[serde(default)]
+    fake_ip: FakeIpConfig,
+    /// Domain patterns mapped to their IPs, an empty list blocking the domain.
+    #[serde(default)]
+    #[serde_as(as = "HashMap<_, _>")]
+    hosts: Vec<(DomainCondition, Vec<IpAddr>)>,
+    /// Also reads `/etc/hosts`, after `hosts`.
+    #[serde(default)]
+    system_hosts: bool,
 }
 
+/// TTL of records answered from hosts.
+const HOSTS_TTL: u32 = 60;
+
+/// Interval between saves of a persistent fake IP pool.
+const FAKE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
 pub struct DnsService {
-    fake_map: Option<RwLock<LruCache<u16, SmolStr>>>,
+    fake: Arc<FakePool>,
+    hosts: Hosts,
     resolvers: Vec<Resolver>,
 }
 
@@ -89,7 +109,9 @@ impl DnsService {
         }
 
         Ok(Self {
-            fake_map: Some(RwLock::new(LruCache::new(512))),
+            fake: Arc::new(FakePool::new(&dns_config.fake_ip, &config.data_dir)?),
+            hosts: Hosts::new(dns_config.hosts.clone(), dns_config.system_hosts)
+                .with_context(|| "reading system hosts")?,
             resolvers,
         })
     }
@@ -97,9 +119,28 @@ impl DnsService {
     /// Initializes context for internal sockets
     pub fn start(&self, ctx: AppContextRef) {
         socket::init_ctx(ctx);
+
+        let fake = self.fake.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FAKE_SAVE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = fake.save().await {
+                    warn!("Failed to save fake IP pool: {}", e);
+                }
+            }
+        });
     }
 
     pub async fn resolve(&self, domain: &str, ctx: &AppContextRef) -> Result<Vec<IpAddr>> {
+        if let Some(ips) = self.hosts.get(domain) {
+            if ips.is_empty() {
+                bail!("{} is blocked by hosts", domain);
+            }
+            debug!("Resolved {} -> {:?} with hosts", domain, ips);
+            return Ok(ips.to_vec());
+        }
+
         for (i, res) in self.resolvers.iter().enumerate() {
             match res.try_resolve(domain, ctx).await {
                 Ok(Some(result)) => {
@@ -125,6 +166,37 @@ impl DnsService {
         }
     }
 
+    /// Looks up records through hosts or the first resolver whose rule matches.
+    pub async fn lookup(
+        &self,
+        domain: &str,
+        typ: RecordType,
+        ctx: &AppContextRef,
+    ) -> Result<Vec<Record>, ResolveError> {
+        if let (Some(ips), RecordType::A | RecordType::AAAA) = (self.hosts.get(domain), typ) {
+            let name = Name::from_str(domain)?;
+            return Ok(ips
+                .iter()
+                .filter_map(|ip| match (ip, typ) {
+                    (IpAddr::V4(ip), RecordType::A) => Some(RData::A(*ip)),
+                    (IpAddr::V6(ip), RecordType::AAAA) => Some(RData::AAAA(*ip)),
+                    _ => None,
+                })
+                .map(|rdata| Record::from_rdata(name.clone(), HOSTS_TTL, rdata))
+                .collect());
+        }
+
+        for res in &self.resolvers {
+            if let Some(records) = res.try_lookup(domain, typ, ctx).await? {
+                return Ok(records);
+            }
+        }
+        Err(ResolveError::from(format!(
+            "No resolver available for {}",
+            domain
+        )))
+    }
+
     pub fn parse_query(message: &Message) -> Result<(u16, &Query)> {
         let id = message.id();
         let query = message
@@ -135,30 +207,29 @@ impl DnsService {
         Ok((id, query))
     }
 
-    /// Blindly insert an item into the map
-    pub async fn fake_set(&self, domain: &str) -> Ipv4Addr {
-        let map_ref = self.fake_map.as_ref().unwrap();
-        let mut map_ref_write = map_ref.write().await;
-        let mut rng = xor_rng();
-        // This is not optimal, but probably faster than iterating again.
-        loop {
-            let id: u16 = rng.gen();
-            if !map_ref_write.contains_key(&id) {
-                map_ref_write.insert(id, SmolStr::from(domain));
-                let bytes = id.to_be_bytes();
-                let ip = Ipv4Addr::new(10, 233, bytes[0], bytes[1]);
-                break ip;
-            }
-        }
+    /// Whether `domain` should be answered with its real IPs.
+    pub fn fake_excluded(&self, domain: &str) -> bool {
+        self.fake.is_excluded(domain)
+    }
+
+    /// Fake IP of `domain`, `Ok(None)` if there is no range for the family.
+    pub fn fake_set(&self, domain: &str, v6: bool) -> Result<Option<IpAddr>> {
+        self.fake.allocate(domain, v6)
     }
 
-    pub async fn fake_get(&self, addr: &Ipv4Addr) -> Option<SmolStr> {
-        let map_ref = self.fake_map.as_ref().unwrap();
-        let mut map_ref_write = map_ref.write().await;
+    pub fn fake_get(&self, addr: &IpAddr) -> Option<SmolStr> {
+        self.fake.get(addr)
+    }
 
-        let octets = addr.octets();
-        let id = u16::from_be_bytes([octets[2], octets[3]]);
+    pub fn is_fake(&self, addr: &IpAddr) -> bool {
+        self.fake.contains(addr)
+    }
 
-        map_ref_write.get_mut(&id).cloned()
+    /// Replaces a fake IP destination with its domain, which is kept until the lease is dropped.
+    pub fn restore_fake(&self, dest: &mut DestAddr) -> Option<FakeLease> {
+        let (domain, lease) = self.fake.lease(dest.ip.as_ref()?)?;
+        dest.set_domain(domain.as_str());
+        dest.ip = None;
+        Some(lease)
     }
 }
@@ -4,7 +4,9 @@ use anyhow::{anyhow, bail};
 use trust_dns_resolver::{
     config::{NameServerConfig, ResolverConfig, ResolverOpts},
     error::ResolveError,
+    lookup::Lookup,
     lookup_ip::LookupIp,
+    proto::rr::{Record, RecordType},
     system_conf::read_system_conf,
     IntoName, TokioHandle, TryParseIp,
 };
@@ -32,6 +34,13 @@ impl ResolverInner {
             ResolverInner::Direct(r) => r.lookup_ip(host).await,
         }
     }
+
+    async fn lookup(&self, name: &str, typ: RecordType) -> Result<Lookup, ResolveError> {
+        match self {
+            ResolverInner::Default(r) => r.lookup(name, typ).await,
+            ResolverInner::Direct(r) => r.lookup(name, typ).await,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -135,20 +144,26 @@ impl Resolver {
         })
     }
 
+    async fn accepts(&self, domain: &str, ctx: &AppContextRef) -> bool {
+        match &self.rule {
+            Some(rule) => {
+                let dest = DestAddr {
+                    domain: Some(domain.into()),
+                    ..Default::default()
+                };
+                rule.is_match_dest(&dest, MatchMode::DomainOnly, ctx).await
+            }
+            None => true,
+        }
+    }
+
     pub async fn try_resolve(
         &self,
         domain: &str,
         ctx: &AppContextRef,
     ) -> Result<Option<Vec<IpAddr>>> {
-        if let Some(rule) = &self.rule {
-            let dest = DestAddr {
-                domain: Some(domain.into()),
-                ..Default::default()
-            };
-
-            if !rule.is_match_dest(&dest, MatchMode::DomainOnly, ctx).await {
-                return Ok(None);
-            }
+        if !self.accepts(domain, ctx).await {
+            return Ok(None);
         }
 
         let result = self.trust.lookup_ip(domain).await?;
@@ -156,4 +171,19 @@ impl Resolver {
 
         Ok(Some(ans))
     }
+
+    /// Looks up records of any type, `Ok(None)` if the rule does not match.
+    pub async fn try_lookup(
+        &self,
+        domain: &str,
+        typ: RecordType,
+        ctx: &AppContextRef,
+    ) -> Result<Option<Vec<Record>>, ResolveError> {
+        if !self.accepts(domain, ctx).await {
+            return Ok(None);
+        }
+
+        let result = self.trust.lookup(domain, typ).await?;
+        Ok(Some(result.records().to_vec()))
+    }
 }
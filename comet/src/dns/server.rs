@@ -0,0 +1,101 @@
+//! Answers DNS queries from clients, with fake IPs or through the configured resolvers.
+use crate::prelude::*;
+use std::net::IpAddr;
+use trust_dns_proto::{
+    op::{Edns, Message, MessageType, ResponseCode},
+    rr::{RData, Record, RecordType},
+};
+use trust_dns_resolver::error::ResolveErrorKind;
+
+/// TTL of fake IP answers, short since the mapping may be evicted.
+const FAKE_TTL: u32 = 1;
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsServerMode {
+    /// A and AAAA queries are answered with fake IPs, except for excluded domains.
+    #[default]
+    Fake,
+    /// All queries are answered with records from the resolvers.
+    Real,
+}
+
+/// Builds the response to a wire format query, truncated to what the client accepts over UDP.
+pub async fn answer(
+    query: &[u8],
+    mode: DnsServerMode,
+    udp: bool,
+    ctx: &AppContextRef,
+) -> Result<Vec<u8>> {
+    let request = Message::from_vec(query)?;
+    let mut response = Message::new();
+    response
+        .set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request.op_code())
+        .set_recursion_desired(request.recursion_desired())
+        .set_recursion_available(true)
+        .add_queries(request.queries().iter().cloned());
+    if let Some(edns) = request.extensions() {
+        let mut reply_edns = Edns::new();
+        reply_edns.set_max_payload(edns.max_payload().max(512));
+        response.set_edns(reply_edns);
+    }
+
+    let query = match request.queries().first() {
+        Some(query) if request.message_type() == MessageType::Query => query,
+        _ => {
+            response.set_response_code(ResponseCode::FormErr);
+            return Ok(response.to_vec()?);
+        }
+    };
+    let name = query.name();
+    let domain = name.to_utf8();
+    let domain = domain.trim_end_matches('.');
+
+    match (mode, query.query_type()) {
+        (DnsServerMode::Fake, typ @ (RecordType::A | RecordType::AAAA))
+            if !ctx.dns.fake_excluded(domain) =>
+        {
+            let rdata = match ctx.dns.fake_set(domain, typ == RecordType::AAAA) {
+                Ok(Some(IpAddr::V4(ip))) => Some(RData::A(ip)),
+                Ok(Some(IpAddr::V6(ip))) => Some(RData::AAAA(ip)),
+                // No records without a range
+                Ok(None) => None,
+                Err(e) => {
+                    warn!("Failed to allocate fake IP for {}: {}", domain, e);
+                    response.set_response_code(ResponseCode::ServFail);
+                    None
+                }
+            };
+            if let Some(rdata) = rdata {
+                debug!("Answering {} with fake IP {}", domain, rdata);
+                response.add_answer(Record::from_rdata(name.clone(), FAKE_TTL, rdata));
+            }
+        }
+        (_, typ) => match ctx.dns.lookup(domain, typ, ctx).await {
+            Ok(records) => {
+                response.add_answers(records);
+            }
+            Err(e) => {
+                let code = match e.kind() {
+                    ResolveErrorKind::NoRecordsFound { response_code, .. } => *response_code,
+                    _ => {
+                        warn!("Failed to look up {} {}: {}", typ, domain, e);
+                        ResponseCode::ServFail
+                    }
+                };
+                response.set_response_code(code);
+            }
+        },
+    }
+
+    let bytes = response.to_vec()?;
+    if udp && bytes.len() > request.max_payload() as usize {
+        // Clients retry over TCP
+        response.take_answers();
+        response.set_truncated(true);
+        return Ok(response.to_vec()?);
+    }
+    Ok(bytes)
+}
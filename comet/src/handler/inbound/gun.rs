@@ -0,0 +1,129 @@
+//! Terminates gRPC "gun" tunnels over cleartext HTTP/2, each `Tun` call becoming a connection.
+use crate::app::inbound_manager::ConnSender;
+use crate::config::Inbound;
+use crate::prelude::*;
+use crate::protos::gun::Hunk;
+use crate::utils::{io::io_other_error, metered_stream::MeteredStream};
+use futures::future;
+use hyper::{server::conn::Http, Body, Request};
+use std::{convert::Infallible, net::SocketAddr};
+use tokio::net::TcpListener;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tonic::{
+    codec::{ProstCodec, Streaming},
+    server::{Grpc, StreamingService},
+    Status,
+};
+use tower::service_fn;
+
+pub async fn serve(
+    listener: TcpListener,
+    tag: SmolStr,
+    inbound: Inbound,
+    service_name: SmolStr,
+    sender: ConnSender<ProxyStream>,
+    ctx: AppContextRef,
+) {
+    let tun_path: Arc<str> = format!("/{}/Tun", service_name).into();
+    loop {
+        let (stream, src_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Inbound {}/gun failed to accept: {}", tag, e);
+                continue;
+            }
+        };
+
+        let tun = TunService {
+            src_addr,
+            tag: tag.clone(),
+            inbound: inbound.clone(),
+            sender: sender.clone(),
+            ctx: ctx.clone(),
+        };
+        let tun_path = tun_path.clone();
+        let service = service_fn(move |req: Request<Body>| {
+            let tun = tun.clone();
+            let tun_path = tun_path.clone();
+            async move {
+                if req.uri().path() != &*tun_path {
+                    return Ok::<_, Infallible>(Status::unimplemented("").to_http());
+                }
+                let mut grpc = Grpc::new(ProstCodec::<Hunk, Hunk>::default());
+                Ok(grpc.streaming(tun, req).await)
+            }
+        });
+
+        tokio::spawn(async move {
+            if let Err(e) = Http::new()
+                .http2_only(true)
+                .serve_connection(stream, service)
+                .await
+            {
+                debug!("gun connection from {} closed: {}", src_addr, e);
+            }
+        });
+    }
+}
+
+#[derive(Clone)]
+struct TunService {
+    src_addr: SocketAddr,
+    tag: SmolStr,
+    inbound: Inbound,
+    sender: ConnSender<ProxyStream>,
+    ctx: AppContextRef,
+}
+
+type HunkStream = Pin<Box<dyn Stream<Item = Result<Hunk, Status>> + Send + Sync>>;
+
+impl StreamingService<Hunk> for TunService {
+    type Response = Hunk;
+    type ResponseStream = HunkStream;
+    type Future = future::Ready<Result<tonic::Response<HunkStream>, Status>>;
+
+    // `Status` is what tonic asks for
+    #[allow(clippy::result_large_err)]
+    fn call(&mut self, request: tonic::Request<Streaming<Hunk>>) -> Self::Future {
+        let (uplink, downlink) = tokio::io::duplex(4096);
+        let (uplink_read, mut uplink_write) = tokio::io::split(uplink);
+
+        let hunks = request
+            .into_inner()
+            .map(|h| h.map(|hunk| Bytes::from(hunk.data)).map_err(io_other_error));
+        let mut st = StreamReader::new(hunks);
+        tokio::spawn(async move {
+            if let Err(e) = tokio::io::copy(&mut st, &mut uplink_write).await {
+                debug!("gun tunnel closed: {}", e);
+            }
+            let _ = uplink_write.shutdown().await;
+        });
+
+        let conn = Connection::new(
+            self.src_addr,
+            self.tag.clone(),
+            self.inbound.pipeline.clone(),
+            TransportType::Tcp,
+        );
+        info!(
+            "({}) Inbound {}/gun accepted from {}",
+            conn.id, self.tag, self.src_addr
+        );
+        let stream = if self.inbound.metering {
+            RWPair::new(MeteredStream::new_inbound(downlink, &self.tag, &self.ctx))
+        } else {
+            RWPair::new(downlink)
+        };
+        if self.sender.send((conn, stream.into())).is_err() {
+            return future::ready(Err(Status::unavailable("Shutting down")));
+        }
+
+        let response = ReaderStream::new(uplink_read).map(|b| {
+            b.map(|data| Hunk {
+                data: data.to_vec(),
+            })
+            .map_err(|e| Status::internal(e.to_string()))
+        });
+        future::ready(Ok(tonic::Response::new(Box::pin(response))))
+    }
+}
@@ -0,0 +1,2 @@
+#[cfg(feature = "gun-transport")]
+pub mod gun;
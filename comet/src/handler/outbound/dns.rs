@@ -0,0 +1,94 @@
+use super::{NewOutboundHandler, Outbound, OutboundHandler};
+use crate::config::OutboundTransportType;
+use crate::dns::server::{answer, DnsServerMode};
+use crate::prelude::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc::channel;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Serves DNS itself instead of connecting anywhere, over UDP or length-prefixed TCP.
+///
+/// Clients reach it through plain inbounds routed here by `inbound_name`.
+pub struct DnsServerHandler {
+    mode: DnsServerMode,
+}
+
+impl DnsServerHandler {
+    async fn serve_tcp(
+        mut stream: DuplexStream,
+        mode: DnsServerMode,
+        ctx: AppContextRef,
+    ) -> Result<()> {
+        loop {
+            let len = match stream.read_u16().await {
+                Ok(len) => len as usize,
+                // Client is done
+                Err(_) => return Ok(()),
+            };
+            let mut query = vec![0u8; len];
+            stream.read_exact(&mut query).await?;
+
+            let response = answer(&query, mode, false, &ctx).await?;
+            stream.write_u16(response.len() as u16).await?;
+            stream.write_all(&response).await?;
+            stream.flush().await?;
+        }
+    }
+}
+
+#[async_trait]
+impl OutboundHandler for DnsServerHandler {
+    async fn handle(
+        &self,
+        _tag: &str,
+        conn: &mut Connection,
+        ctx: &AppContextRef,
+    ) -> Result<ProxyStream> {
+        let mode = self.mode;
+        let ctx = ctx.clone();
+
+        if conn.typ == TransportType::Tcp {
+            let (uplink, downlink) = tokio::io::duplex(4096);
+            tokio::spawn(async move {
+                if let Err(e) = Self::serve_tcp(uplink, mode, ctx).await {
+                    warn!("DNS server failed: {}", e);
+                }
+            });
+            return Ok(RWPair::new(downlink).into());
+        }
+
+        let (read_sender, read_receiver) = channel::<UdpPacket>(10);
+        let (write_sender, mut write_receiver) = channel::<UdpPacket>(10);
+        tokio::spawn(async move {
+            while let Some(packet) = write_receiver.recv().await {
+                let response = match answer(&packet, mode, true, &ctx).await {
+                    Ok(response) => BytesMut::from(&response[..]),
+                    Err(e) => {
+                        warn!("Failed to answer DNS query: {}", e);
+                        continue;
+                    }
+                };
+                // Replies come from where the query was sent
+                let reply = match packet.target() {
+                    Some(target) => UdpPacket::new(target, response),
+                    None => UdpPacket::new_unknown(response),
+                };
+                if read_sender.send(reply).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(UdpStream::new(ReceiverStream::new(read_receiver), write_sender).into())
+    }
+}
+
+impl NewOutboundHandler for DnsServerHandler {
+    fn new(config: &Outbound) -> Self {
+        let mode = match config.typ {
+            OutboundTransportType::DnsServer { mode } => mode,
+            _ => unreachable!(),
+        };
+        Self { mode }
+    }
+}
@@ -1,10 +1,10 @@
 use super::{NewOutboundHandler, Outbound, OutboundHandler};
+use crate::config::{IpPreference, OutboundTransportType};
 use crate::prelude::*;
-use anyhow::anyhow;
-use std::{
-    convert::TryFrom,
-    net::{IpAddr, SocketAddr},
-};
+use crate::utils::{io::io_other_error, metered_stream::MeteredStream};
+use anyhow::{anyhow, bail};
+use once_cell::sync::OnceCell;
+use std::net::SocketAddr;
 use tokio_util::io::{ReaderStream, StreamReader};
 
 use tonic::{
@@ -14,12 +14,7 @@ use tonic::{
 use tower::service_fn;
 
 mod gun_grpc_client {
-    #[derive(Clone, PartialEq, ::prost::Message)]
-    pub struct Hunk {
-        #[prost(bytes = "vec", tag = "1")]
-        pub data: ::prost::alloc::vec::Vec<u8>,
-    }
-
+    pub use crate::protos::gun::Hunk;
     use tonic::codegen::*;
 
     #[derive(Debug, Clone)]
@@ -125,30 +120,64 @@ use gun_grpc_client::{GunServiceClient, Hunk};
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct GunConfig {
-    
+    server: DestAddr,
+    #[serde(default = "crate::config::default_gun_service_name")]
+    service_name: SmolStr,
+    #[serde(default = "default_tls")]
+    tls: bool,
+    /// Defaults to the server domain.
+    sni: Option<SmolStr>,
 }
 
+fn default_tls() -> bool {
+    true
+}
+
+/// Tunnels connections as streams of a single HTTP/2 channel, created on first use.
 pub struct GunHandler {
-    client: GunServiceClient<Channel>,
+    config: GunConfig,
+    client: OnceCell<GunServiceClient<Channel>>,
     metering: bool,
+    ip_preference: IpPreference,
 }
 
 impl GunHandler {
-    fn create_client(
-        addr: IpAddr,
-        port: u16,
-        service_name: &str,
-    ) -> Result<GunServiceClient<Channel>> {
-        let connector = move |_: Uri| async move {
-            let socket_addr = SocketAddr::from((addr, port));
-            crate::net_wrapper::connect_tcp(socket_addr).await
+    fn create_client(&self, ctx: &AppContextRef) -> Result<GunServiceClient<Channel>> {
+        let config = &self.config;
+        let server = config.server.clone();
+        let port = server.port_or_error()?;
+        let ctx = ctx.clone();
+        let ip_preference = self.ip_preference;
+        let connector = move |_: Uri| {
+            let server = server.clone();
+            let ctx = ctx.clone();
+            async move {
+                let ips = ip_preference.arrange(ctx.dns.resolve_addr(&server, &ctx).await?);
+                let ip = ips
+                    .first()
+                    .ok_or_else(|| anyhow!("No address for {}", server))?;
+                let stream = crate::net_wrapper::connect_tcp(SocketAddr::new(*ip, port)).await?;
+                Ok::<_, anyhow::Error>(stream)
+            }
         };
 
-        let channel = Endpoint::try_from("https://[::]:443")?
-            .tls_config(ClientTlsConfig::new().domain_name("api-global.reimu.moe"))?
-            .connect_with_connector_lazy(service_fn(connector))?;
+        let endpoint = if config.tls {
+            let sni = match (&config.sni, &config.server.domain) {
+                (Some(sni), _) | (None, Some(sni)) => sni,
+                (None, None) => bail!("SNI is required for an IP server"),
+            };
+            Endpoint::from_shared(format!("https://{}:{}", sni, port))?
+                .tls_config(ClientTlsConfig::new().domain_name(sni.as_str()))?
+        } else {
+            let authority = match &config.server.domain {
+                Some(domain) => format!("{}:{}", domain, port),
+                None => SocketAddr::new(*config.server.ip_or_error()?, port).to_string(),
+            };
+            Endpoint::from_shared(format!("http://{}", authority))?
+        };
+        let channel = endpoint.connect_with_connector_lazy(service_fn(connector))?;
 
-        Ok(GunServiceClient::new(channel, service_name))
+        Ok(GunServiceClient::new(channel, config.service_name.as_str()))
     }
 }
 
@@ -157,10 +186,13 @@ impl OutboundHandler for GunHandler {
     async fn handle(
         &self,
         tag: &str,
-        conn: &mut Connection,
+        _conn: &mut Connection,
         ctx: &AppContextRef,
     ) -> Result<ProxyStream> {
-        let mut client = self.client.clone();
+        let mut client = self
+            .client
+            .get_or_try_init(|| self.create_client(ctx))?
+            .clone();
         let (uplink, downlink) = tokio::io::duplex(4096);
 
         let (uplink_read, mut uplink_write) = tokio::io::split(uplink);
@@ -175,7 +207,7 @@ impl OutboundHandler for GunHandler {
 
         let ss = response.into_inner().map(|h| match h {
             Ok(hunk) => Ok(Bytes::from(hunk.data)),
-            Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
+            Err(e) => Err(io_other_error(e)),
         });
         let mut st = StreamReader::new(ss);
         tokio::spawn(async move {
@@ -185,16 +217,30 @@ impl OutboundHandler for GunHandler {
             let _ = uplink_write.shutdown().await;
         });
 
-        Ok(RWPair::new(downlink).into())
+        Ok(if self.metering {
+            RWPair::new(MeteredStream::new_outbound(downlink, tag, ctx))
+        } else {
+            RWPair::new(downlink)
+        }
+        .into())
+    }
+
+    fn ip_preference(&self) -> IpPreference {
+        self.ip_preference
     }
 }
 
 impl NewOutboundHandler for GunHandler {
     fn new(config: &Outbound) -> Self {
+        let gun_config = match &config.typ {
+            OutboundTransportType::Gun { config } => config.clone(),
+            _ => unreachable!(),
+        };
         Self {
+            config: gun_config,
+            client: OnceCell::new(),
             metering: config.metering,
-            client: GunHandler::create_client(IpAddr::from([127, 0, 0, 1]), 443, "mygrpcservice")
-                .unwrap(),
+            ip_preference: config.ip_preference,
         }
     }
 }
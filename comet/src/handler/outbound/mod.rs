@@ -1,9 +1,11 @@
-use crate::config::Outbound;
+use crate::config::{IpPreference, Outbound};
 use crate::prelude::*;
+use anyhow::bail;
 use std::net::IpAddr;
 
 mod both;
 mod dashboard;
+mod dns;
 mod tcp;
 mod udp;
 #[cfg(feature = "gun-transport")]
@@ -11,6 +13,7 @@ mod gun;
 
 pub use both::TcpUdpHandler;
 pub use dashboard::DashboardHandler;
+pub use dns::DnsServerHandler;
 pub use tcp::TcpHandler;
 pub use udp::UdpHandler;
 #[cfg(feature = "gun-transport")]
@@ -27,6 +30,11 @@ pub trait OutboundHandler: Send + Sync + Unpin {
         ctx: &AppContextRef,
     ) -> Result<ProxyStream>;
 
+    fn ip_preference(&self) -> IpPreference {
+        IpPreference::default()
+    }
+
+    /// Resolves the destination, ordering the addresses by `ip_preference`.
     async fn resolve_addr(
         &self,
         conn: &Connection,
@@ -34,6 +42,10 @@ pub trait OutboundHandler: Send + Sync + Unpin {
     ) -> Result<(Vec<IpAddr>, u16)> {
         let port = conn.dest_addr.port_or_error()?;
         let ips = ctx.dns.resolve_addr(&conn.dest_addr, ctx).await?;
+        let ips = self.ip_preference().arrange(ips);
+        if ips.is_empty() {
+            bail!("No address of the preferred family for {}", conn.dest_addr);
+        }
         Ok((ips, port))
     }
 }
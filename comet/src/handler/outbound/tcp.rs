@@ -1,12 +1,21 @@
 use super::{NewOutboundHandler, Outbound, OutboundHandler};
+use crate::config::IpPreference;
 use crate::prelude::*;
 use crate::utils::metered_stream::MeteredStream;
 use anyhow::anyhow;
+use futures::stream::FuturesUnordered;
+use std::collections::VecDeque;
 use std::net::IpAddr;
 use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Delay before racing the next address, as recommended by RFC 8305.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
 
 pub struct TcpHandler {
     metering: bool,
+    ip_preference: IpPreference,
 }
 
 impl TcpHandler {
@@ -24,6 +33,41 @@ impl TcpHandler {
             RWPair::new(stream)
         })
     }
+
+    /// Happy Eyeballs: starts an attempt every `ATTEMPT_DELAY` or as soon as one fails, and
+    /// keeps the first connection established.
+    async fn connect_any(
+        &self,
+        tag: &str,
+        ips: Vec<IpAddr>,
+        port: u16,
+        ctx: &AppContextRef,
+    ) -> Result<RWPair> {
+        let attempt = |ip| async move { (ip, self.connect(tag, ip, port, ctx).await) };
+        let mut pending: VecDeque<_> = ips.into();
+        let mut attempts = FuturesUnordered::new();
+
+        while let Some(ip) = pending.pop_front() {
+            attempts.push(attempt(ip));
+            tokio::select! {
+                Some((ip, res)) = attempts.next() => match res {
+                    // Dropping the other attempts cancels them
+                    Ok(stream) => return Ok(stream),
+                    Err(err) => warn!("Trying {}:{} failed: {}", ip, port, err),
+                },
+                _ = sleep(ATTEMPT_DELAY), if !pending.is_empty() => {}
+                else => {}
+            }
+        }
+        // Remaining attempts after all were started
+        while let Some((ip, res)) = attempts.next().await {
+            match res {
+                Ok(stream) => return Ok(stream),
+                Err(err) => warn!("Trying {}:{} failed: {}", ip, port, err),
+            }
+        }
+        Err(anyhow!("All attempts failed"))
+    }
 }
 
 #[async_trait]
@@ -35,14 +79,11 @@ impl OutboundHandler for TcpHandler {
         ctx: &AppContextRef,
     ) -> Result<ProxyStream> {
         let (ips, port) = self.resolve_addr(conn, ctx).await?;
+        Ok(self.connect_any(tag, ips, port, ctx).await?.into())
+    }
 
-        for ip in ips {
-            match self.connect(tag, ip, port, ctx).await {
-                Ok(stream) => return Ok(stream.into()),
-                Err(err) => warn!("Trying {}:{} failed: {}", ip, port, err),
-            }
-        }
-        Err(anyhow!("All attempts failed"))
+    fn ip_preference(&self) -> IpPreference {
+        self.ip_preference
     }
 }
 
@@ -50,6 +91,7 @@ impl NewOutboundHandler for TcpHandler {
     fn new(config: &Outbound) -> Self {
         Self {
             metering: config.metering,
+            ip_preference: config.ip_preference,
         }
     }
 }
@@ -1,4 +1,5 @@
 use super::{NewOutboundHandler, Outbound, OutboundHandler};
+use crate::config::IpPreference;
 use crate::prelude::*;
 use anyhow::anyhow;
 use log::error;
@@ -7,7 +8,9 @@ use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use tokio::sync::mpsc::channel;
 use tokio_stream::wrappers::ReceiverStream;
 
-pub struct UdpHandler {}
+pub struct UdpHandler {
+    ip_preference: IpPreference,
+}
 
 macro_rules! break_if_err {
     ($e:expr) => {
@@ -31,7 +34,12 @@ impl OutboundHandler for UdpHandler {
     ) -> Result<ProxyStream> {
         let resolved = self.resolve_addr(conn, ctx).await.ok();
 
-        let addr_type = conn.get_var("addr_type").unwrap_or(&AddrType::V4);
+        // The socket must be able to reach the resolved destination
+        let addr_type = match &resolved {
+            Some((ips, _)) if ips[0].is_ipv6() => &AddrType::V6,
+            Some(_) => &AddrType::V4,
+            None => conn.get_var("addr_type").unwrap_or(&AddrType::V4),
+        };
         let bind_ip = match addr_type {
             AddrType::V4 => IpAddr::from(Ipv4Addr::from(0u32)),
             AddrType::V6 => IpAddr::from(Ipv6Addr::from(0u128)),
@@ -71,10 +79,16 @@ impl OutboundHandler for UdpHandler {
 
         Ok(UdpStream::new(ReceiverStream::new(read_receiver), write_sender).into())
     }
+
+    fn ip_preference(&self) -> IpPreference {
+        self.ip_preference
+    }
 }
 
 impl NewOutboundHandler for UdpHandler {
-    fn new(_config: &Outbound) -> Self {
-        Self {}
+    fn new(config: &Outbound) -> Self {
+        Self {
+            ip_preference: config.ip_preference,
+        }
     }
 }
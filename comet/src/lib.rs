@@ -17,6 +17,8 @@ pub mod utils;
 
 #[cfg(target_os = "android")]
 pub mod android;
+#[cfg(target_os = "linux")]
+pub mod tun;
 
 use crate::app::dispatcher;
 use crate::prelude::*;
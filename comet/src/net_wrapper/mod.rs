@@ -3,8 +3,15 @@ use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use std::net::SocketAddr;
 use tokio::net::{TcpSocket, TcpStream, UdpSocket};
 
+#[cfg(target_os = "linux")]
+mod outbound_socket;
 #[cfg(target_os = "android")]
 mod protect;
+#[cfg(target_os = "linux")]
+pub mod transparent;
+
+#[cfg(target_os = "linux")]
+pub use outbound_socket::init as init_outbound_socket;
 
 pub async fn connect_tcp(addr: SocketAddr) -> IoResult<TcpStream> {
     let sock = match addr {
@@ -18,6 +25,8 @@ pub async fn connect_tcp(addr: SocketAddr) -> IoResult<TcpStream> {
         let fd = sock.as_raw_fd();
         protect::protect_async(fd).await?;
     }
+    #[cfg(target_os = "linux")]
+    outbound_socket::apply(&sock)?;
     sock.connect(addr).await
 }
 
@@ -32,6 +41,8 @@ pub async fn bind_udp(addr: SocketAddr) -> IoResult<UdpSocket> {
         let fd = sock.as_raw_fd();
         protect::protect_async(fd).await?;
     }
+    #[cfg(target_os = "linux")]
+    outbound_socket::apply(&sock)?;
     sock.bind(&SockAddr::from(addr))?;
     UdpSocket::from_std(sock.into())
 }
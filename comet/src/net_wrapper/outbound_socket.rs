@@ -0,0 +1,40 @@
+//! Mark and interface binding of outbound sockets.
+use super::transparent::setsockopt;
+use crate::config::OutboundSocketConfig;
+use crate::prelude::*;
+use libc::{c_int, c_void, socklen_t};
+use once_cell::sync::OnceCell;
+use std::io::Error;
+use std::os::unix::io::AsRawFd;
+
+static CONFIG: OnceCell<OutboundSocketConfig> = OnceCell::new();
+
+pub fn init(config: &OutboundSocketConfig) {
+    let _ = CONFIG.set(config.clone());
+}
+
+pub fn apply(sock: &impl AsRawFd) -> IoResult<()> {
+    let config = match CONFIG.get() {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+    let fd = sock.as_raw_fd();
+    if let Some(mark) = config.mark {
+        setsockopt(fd, libc::SOL_SOCKET, libc::SO_MARK, mark as c_int)?;
+    }
+    if let Some(device) = &config.bind_device {
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                device.as_ptr() as *const c_void,
+                device.len() as socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,203 @@
+//! Sockets for transparent proxying with iptables/nftables REDIRECT and TPROXY.
+use crate::config::TransparentMode;
+use crate::prelude::*;
+use libc::{c_int, c_void, sockaddr_in, sockaddr_in6, sockaddr_storage, socklen_t};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::io::{Error, ErrorKind};
+use std::mem::{size_of, MaybeUninit};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::AsRawFd;
+use tokio::io::Interest;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+pub(super) fn setsockopt<T>(fd: c_int, level: c_int, name: c_int, value: T) -> IoResult<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const T as *const c_void,
+            size_of::<T>() as socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(Error::last_os_error())
+    }
+}
+
+/// Allows binding to and receiving for non-local addresses.
+fn set_transparent(sock: &Socket, addr: &SocketAddr) -> IoResult<()> {
+    let fd = sock.as_raw_fd();
+    match addr {
+        SocketAddr::V4(_) => setsockopt(fd, libc::SOL_IP, libc::IP_TRANSPARENT, 1 as c_int),
+        SocketAddr::V6(_) => {
+            setsockopt(fd, libc::SOL_IPV6, libc::IPV6_TRANSPARENT, 1 as c_int)?;
+            // Dual-stack sockets also see IPv4 traffic
+            let _ = setsockopt(fd, libc::SOL_IP, libc::IP_TRANSPARENT, 1 as c_int);
+            Ok(())
+        }
+    }
+}
+
+pub fn bind_tcp(addr: SocketAddr, mode: TransparentMode) -> IoResult<TcpListener> {
+    let sock = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    sock.set_nonblocking(true)?;
+    sock.set_reuse_address(true)?;
+    if mode == TransparentMode::Tproxy {
+        set_transparent(&sock, &addr)?;
+    }
+    sock.bind(&SockAddr::from(addr))?;
+    sock.listen(1024)?;
+    TcpListener::from_std(sock.into())
+}
+
+/// Binds a TPROXY socket that reports the original destination of each datagram.
+pub fn bind_udp(addr: SocketAddr) -> IoResult<UdpSocket> {
+    let sock = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    sock.set_nonblocking(true)?;
+    set_transparent(&sock, &addr)?;
+    let fd = sock.as_raw_fd();
+    match addr {
+        SocketAddr::V4(_) => setsockopt(fd, libc::SOL_IP, libc::IP_RECVORIGDSTADDR, 1 as c_int)?,
+        SocketAddr::V6(_) => {
+            setsockopt(fd, libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR, 1 as c_int)?;
+            let _ = setsockopt(fd, libc::SOL_IP, libc::IP_RECVORIGDSTADDR, 1 as c_int);
+        }
+    }
+    sock.bind(&SockAddr::from(addr))?;
+    UdpSocket::from_std(sock.into())
+}
+
+/// Binds a socket to a non-local address, for replies that appear to come from it.
+pub fn bind_udp_reply(addr: SocketAddr) -> IoResult<UdpSocket> {
+    let sock = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    sock.set_nonblocking(true)?;
+    sock.set_reuse_address(true)?;
+    set_transparent(&sock, &addr)?;
+    sock.bind(&SockAddr::from(addr))?;
+    UdpSocket::from_std(sock.into())
+}
+
+/// Original destination of an accepted connection.
+pub fn original_dst(stream: &TcpStream, mode: TransparentMode) -> IoResult<SocketAddr> {
+    let local_addr = stream.local_addr()?;
+    if mode == TransparentMode::Tproxy {
+        // TPROXY keeps the original destination as the local address
+        return Ok(local_addr);
+    }
+
+    let (level, name) = match local_addr {
+        SocketAddr::V6(addr) if addr.ip().to_ipv4_mapped().is_none() => {
+            (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST)
+        }
+        _ => (libc::SOL_IP, libc::SO_ORIGINAL_DST),
+    };
+    let mut storage = MaybeUninit::<sockaddr_storage>::zeroed();
+    let mut len = size_of::<sockaddr_storage>() as socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            storage.as_mut_ptr() as *mut c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    unsafe { to_socket_addr(storage.as_ptr()) }
+}
+
+unsafe fn to_socket_addr(storage: *const sockaddr_storage) -> IoResult<SocketAddr> {
+    match (*storage).ss_family as c_int {
+        libc::AF_INET => {
+            let addr = &*(storage as *const sockaddr_in);
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let addr = &*(storage as *const sockaddr_in6);
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        family => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unsupported address family {}", family),
+        )),
+    }
+}
+
+/// Receives a datagram on a socket from `bind_udp`, returning its source and original destination.
+pub async fn recv_from_orig_dst(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> IoResult<(usize, SocketAddr, SocketAddr)> {
+    loop {
+        socket.readable().await?;
+        match socket.try_io(Interest::READABLE, || {
+            recvmsg_orig_dst(socket.as_raw_fd(), buf)
+        }) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            res => return res,
+        }
+    }
+}
+
+fn recvmsg_orig_dst(fd: c_int, buf: &mut [u8]) -> IoResult<(usize, SocketAddr, SocketAddr)> {
+    let mut src = MaybeUninit::<sockaddr_storage>::zeroed();
+    // Aligned for `cmsghdr`
+    let mut control = [0u64; 8];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = src.as_mut_ptr() as *mut c_void;
+    msg.msg_namelen = size_of::<sockaddr_storage>() as socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = size_of::<[u64; 8]>() as _;
+
+    let n = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if n < 0 {
+        return Err(Error::last_os_error());
+    }
+    let src = unsafe { to_socket_addr(src.as_ptr())? };
+
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let (level, typ) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+        if (level == libc::SOL_IP && typ == libc::IP_ORIGDSTADDR)
+            || (level == libc::SOL_IPV6 && typ == libc::IPV6_ORIGDSTADDR)
+        {
+            let dst = unsafe { to_socket_addr(libc::CMSG_DATA(cmsg) as *const sockaddr_storage)? };
+            return Ok((n as usize, unmap(src), unmap(dst)));
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        "Missing original destination",
+    ))
+}
+
+/// Dual-stack sockets report IPv4 peers as mapped IPv6 addresses.
+fn unmap(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => addr,
+        },
+        addr => addr,
+    }
+}
@@ -1,13 +1,37 @@
 use super::http::server::ServerProcessor as HttpProcessor;
-use super::socks5::Socks5ProxyServerProcessor as Socks5Processor;
+use super::socks5::server::Socks5ProxyServerProcessor as Socks5Processor;
+use super::socks5::socks4_server::{
+    self, ServerConfig as Socks4Config, Socks4ProxyServerProcessor as Socks4Processor,
+};
 use crate::{prelude::*, utils::prepend_io::PrependReader};
+use anyhow::bail;
 
 pub fn register(plumber: &mut Plumber) {
-    plumber.register("any_server", |_, _| {
-        Ok(Box::new(AnyProxyServerProcessor {}))
+    plumber.register("any_server", |conf, _| {
+        let users: UsersConfig = from_value(conf.clone())?;
+        let socks4_config: Socks4Config = from_value(conf.clone())?;
+        // SOCKS4 has no passwords, so it must be opted into with `user_ids` when they are required
+        let socks4 = (users.users.is_empty() || !socks4_config.user_ids.is_empty())
+            .then(|| Arc::new(Socks4Processor::new(socks4_config)));
+        Ok(Box::new(AnyProxyServerProcessor {
+            socks4,
+            socks5: Arc::new(Socks5Processor::new(from_value(conf.clone())?)),
+            http: Arc::new(HttpProcessor::new(from_value(conf)?)),
+        }))
     });
 }
-pub struct AnyProxyServerProcessor {}
+
+#[derive(Deserialize)]
+struct UsersConfig {
+    #[serde(default)]
+    users: Vec<YamlValue>,
+}
+
+pub struct AnyProxyServerProcessor {
+    socks4: Option<Arc<Socks4Processor>>,
+    socks5: Arc<Socks5Processor>,
+    http: Arc<HttpProcessor>,
+}
 
 #[async_trait]
 impl Processor for AnyProxyServerProcessor {
@@ -20,15 +44,16 @@ impl Processor for AnyProxyServerProcessor {
         let mut stream = stream.into_tcp()?;
         let first_byte = stream.read_u8().await?;
         let prep = PrependReader::new(stream, &[first_byte][..]);
-        let stream = RWPair::new(prep).into();
+        let stream = RWPair::new(prep);
 
-        match first_byte {
-            4 | 5 => {
-                Arc::new(Socks5Processor {})
-                    .process(stream, conn, ctx)
-                    .await
+        match (first_byte, &self.socks4) {
+            (4, Some(socks4)) => socks4.clone().process(stream.into(), conn, ctx).await,
+            (4, None) => {
+                socks4_server::reject(stream).await?;
+                bail!("SOCKS4 is disabled since users are required");
             }
-            _ => Arc::new(HttpProcessor {}).process(stream, conn, ctx).await,
+            (5, _) => self.socks5.clone().process(stream.into(), conn, ctx).await,
+            _ => self.http.clone().process(stream.into(), conn, ctx).await,
         }
     }
 }
@@ -1,13 +1,38 @@
 use crate::prelude::*;
 use crate::utils::io::eof;
 use crate::utils::prepend_io::PrependReader;
+use anyhow::bail;
 use bytes::{Buf, BytesMut};
 
 pub fn register(plumber: &mut Plumber) {
-    plumber.register("http_proxy_client", |_, _| Ok(Box::new(ClientProcessor {})));
+    plumber.register("http_proxy_client", |conf, _| {
+        let config: ClientConfig = from_value(conf)?;
+        Ok(Box::new(ClientProcessor::new(config)))
+    });
 }
 
-pub struct ClientProcessor {}
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientConfig {
+    username: Option<SmolStr>,
+    #[serde(default)]
+    password: SmolStr,
+}
+
+pub struct ClientProcessor {
+    /// Value of `Proxy-Authorization`
+    authorization: Option<String>,
+}
+
+impl ClientProcessor {
+    pub fn new(config: ClientConfig) -> Self {
+        let password = config.password;
+        let authorization = config.username.map(|username| {
+            let credentials = format!("{}:{}", username, password);
+            format!("Basic {}", base64::encode(credentials))
+        });
+        Self { authorization }
+    }
+}
 
 #[async_trait]
 impl Processor for ClientProcessor {
@@ -23,11 +48,17 @@ impl Processor for ClientProcessor {
         } else {
             conn.dest_addr.ip_or_error()?.to_string()
         };
-        let request = format!(
-            "CONNECT {0}:{1} HTTP/1.1\r\nHost: {0}\r\n\r\n",
+        let mut request = format!(
+            "CONNECT {0}:{1} HTTP/1.1\r\nHost: {0}\r\n",
             dest_addr,
             conn.dest_addr.port_or_error()?
         );
+        if let Some(authorization) = &self.authorization {
+            request.push_str("Proxy-Authorization: ");
+            request.push_str(authorization);
+            request.push_str("\r\n");
+        }
+        request.push_str("\r\n");
         stream.write_all(request.as_bytes()).await?;
         let mut buffer = BytesMut::with_capacity(512);
         loop {
@@ -39,6 +70,14 @@ impl Processor for ClientProcessor {
             let n = stream.read_buf(&mut buffer).await?;
             match res.parse(&buffer[..])? {
                 httparse::Status::Complete(len) => {
+                    let code = res.code.unwrap_or(0);
+                    if !(200..300).contains(&code) {
+                        bail!(
+                            "Proxy refused to connect: {} {}",
+                            code,
+                            res.reason.unwrap_or("")
+                        );
+                    }
                     buffer.advance(len);
                     return Ok(RWPair::new(PrependReader::new(stream, buffer)).into());
                 }
@@ -1,7 +1,7 @@
 use crate::prelude::*;
 use crate::utils::io::eof;
 use crate::utils::prepend_io::PrependReader;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use bytes::{Buf, BytesMut};
 use std::net::IpAddr;
 use std::str::FromStr;
@@ -10,10 +10,140 @@ use url::{Host, Url};
 use httparse::{Request, Status};
 
 pub fn register(plumber: &mut Plumber) {
-    plumber.register("http_proxy_server", |_, _| Ok(Box::new(ServerProcessor {})));
+    plumber.register("http_proxy_server", |conf, _| {
+        let config: ServerConfig = from_value(conf)?;
+        Ok(Box::new(ServerProcessor::new(config)))
+    });
 }
 
-pub struct ServerProcessor {}
+/// Headers that only make sense between the client and us.
+const HOP_BY_HOP_HEADERS: &[&str] = &["connection", "keep-alive", "te", "trailer", "upgrade"];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ServerConfig {
+    /// Basic `Proxy-Authorization` is required if non-empty.
+    #[serde(default)]
+    users: Vec<UserConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct UserConfig {
+    username: SmolStr,
+    password: SmolStr,
+}
+
+pub struct ServerProcessor {
+    users: HashMap<SmolStr, SmolStr>,
+}
+
+impl ServerProcessor {
+    pub fn new(config: ServerConfig) -> Self {
+        let users = config
+            .users
+            .into_iter()
+            .map(|u| (u.username, u.password))
+            .collect();
+        Self { users }
+    }
+
+    /// Checks `Proxy-Authorization`, returning the authenticated username.
+    fn authenticate(&self, req: &Request) -> Option<SmolStr> {
+        let value = req
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case("Proxy-Authorization"))?
+            .value;
+        let value = std::str::from_utf8(value).ok()?.trim();
+
+        let mut split = value.splitn(2, ' ');
+        if !split.next()?.eq_ignore_ascii_case("Basic") {
+            return None;
+        }
+        let decoded = base64::decode(split.next()?.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+
+        match self.users.get(username) {
+            Some(p) if p == password => Some(username.into()),
+            _ => None,
+        }
+    }
+}
+
+/// Rewrites a plain HTTP request head for the origin server: the request target
+/// is turned into origin-form and hop-by-hop and `Proxy-*` headers are removed.
+fn rewrite_request(req: &Request) -> Result<BytesMut> {
+    let method = req.method.ok_or_else(|| anyhow!("No method specifed"))?;
+    let path = req.path.ok_or_else(|| anyhow!("No path specified"))?;
+    let mut authority = None;
+    let path = match Url::parse(path) {
+        Ok(url) if url.has_host() => {
+            authority = url.host_str().map(|host| match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_string(),
+            });
+            match url.query() {
+                Some(query) => format!("{}?{}", url.path(), query),
+                None => url.path().to_string(),
+            }
+        }
+        _ => path.to_string(),
+    };
+
+    // Headers nominated by `Connection` are hop-by-hop too
+    let mut connection_headers = vec![];
+    let mut upgrade = None;
+    for header in req.headers.iter() {
+        if header.name.eq_ignore_ascii_case("Connection")
+            || header.name.eq_ignore_ascii_case("Proxy-Connection")
+        {
+            let value = std::str::from_utf8(header.value)?;
+            connection_headers.extend(value.split(',').map(|s| s.trim().to_ascii_lowercase()));
+        } else if header.name.eq_ignore_ascii_case("Upgrade") {
+            upgrade = Some(header.value);
+        }
+    }
+
+    let mut head = BytesMut::with_capacity(512);
+    head.put_slice(method.as_bytes());
+    head.put_u8(b' ');
+    head.put_slice(path.as_bytes());
+    head.put_slice(format!(" HTTP/1.{}\r\n", req.version.unwrap_or(1)).as_bytes());
+
+    for header in req.headers.iter() {
+        let name = header.name.to_ascii_lowercase();
+        if HOP_BY_HOP_HEADERS.contains(&name.as_str())
+            || name.starts_with("proxy-")
+            || connection_headers.contains(&name)
+        {
+            continue;
+        }
+        head.put_slice(header.name.as_bytes());
+        head.put_slice(b": ");
+        head.put_slice(header.value);
+        head.put_slice(b"\r\n");
+    }
+    // HTTP/1.0 clients may only send the absolute URI
+    let has_host = req
+        .headers
+        .iter()
+        .any(|h| h.name.eq_ignore_ascii_case("Host"));
+    if let (false, Some(authority)) = (has_host, authority) {
+        head.put_slice(format!("Host: {}\r\n", authority).as_bytes());
+    }
+
+    // Later requests on this connection would bypass us, so ask the server to close it
+    // unless we are switching protocols.
+    if let Some(upgrade) = upgrade {
+        head.put_slice(b"Connection: Upgrade\r\nUpgrade: ");
+        head.put_slice(upgrade);
+        head.put_slice(b"\r\n\r\n");
+    } else {
+        head.put_slice(b"Connection: close\r\n\r\n");
+    }
+
+    Ok(head)
+}
 
 #[async_trait]
 impl Processor for ServerProcessor {
@@ -35,6 +165,21 @@ impl Processor for ServerProcessor {
 
             match req.parse(&buffer[..])? {
                 Status::Complete(len) => {
+                    if !self.users.is_empty() {
+                        match self.authenticate(&req) {
+                            Some(user) => conn.set_var(vars::USER, user),
+                            None => {
+                                let response = "HTTP/1.1 407 Proxy Authentication Required\r\n\
+                                    Proxy-Authenticate: Basic realm=\"comet\"\r\n\
+                                    Connection: close\r\n\
+                                    Content-Length: 0\r\n\r\n";
+                                stream.write_all(response.as_bytes()).await?;
+                                stream.shutdown().await?;
+                                bail!("Proxy authentication failed");
+                            }
+                        }
+                    }
+
                     if let Some(url) = req.path.and_then(|p| Url::parse(p).ok()) {
                         // We have a host in path
                         if let Some(host) = url.host() {
@@ -48,7 +193,7 @@ impl Processor for ServerProcessor {
                     }
 
                     if !conn.dest_addr.is_valid() {
-                        for header in req.headers {
+                        for header in req.headers.iter() {
                             if header.name.eq_ignore_ascii_case("Host") {
                                 let host = std::str::from_utf8(header.value)?;
                                 let mut split = host.split(':');
@@ -74,6 +219,10 @@ impl Processor for ServerProcessor {
                         buffer.advance(len);
                         let response = "HTTP/1.1 200 Connection Established\r\n\r\n";
                         stream.write_all(response.as_bytes()).await?;
+                    } else {
+                        let mut head = rewrite_request(&req)?;
+                        head.extend_from_slice(&buffer[len..]);
+                        buffer = head;
                     }
                     return Ok(RWPair::new(PrependReader::new(stream, buffer)).into());
                 }
@@ -1,10 +1,14 @@
 use crate::prelude::*;
 use anyhow::anyhow;
+use std::net::SocketAddr;
 
 pub fn register(plumber: &mut Plumber) {
     plumber.register("associate_uid", |_, _| {
         Ok(Box::new(AssociateUidProcessor {}))
     });
+    plumber.register("transparent_dest", |_, _| {
+        Ok(Box::new(TransparentDestProcessor {}))
+    });
 }
 
 pub struct AssociateUidProcessor {}
@@ -76,3 +80,22 @@ impl Processor for AssociateUidProcessor {
         Ok(stream)
     }
 }
+
+/// Sets `dest_addr` to the original destination recorded by a transparent inbound.
+pub struct TransparentDestProcessor {}
+
+#[async_trait]
+impl Processor for TransparentDestProcessor {
+    async fn process(
+        self: Arc<Self>,
+        stream: ProxyStream,
+        conn: &mut Connection,
+        _ctx: AppContextRef,
+    ) -> Result<ProxyStream> {
+        let addr = *conn
+            .get_var::<SocketAddr>(vars::ORIGINAL_DST)
+            .ok_or_else(|| anyhow!("No original destination"))?;
+        conn.dest_addr = DestAddr::new_ip(addr.ip(), addr.port());
+        Ok(stream)
+    }
+}
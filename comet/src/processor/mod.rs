@@ -11,9 +11,11 @@ pub mod sniffer;
 pub mod socks5;
 pub mod timeout;
 pub mod tls;
+pub mod tls_server;
 #[cfg(feature = "tls-mitm")]
 pub mod tls_mitm;
 pub mod trojan;
+pub mod vless;
 pub mod vmess;
 pub mod ws;
 
@@ -25,7 +27,9 @@ pub fn do_register(plumber: &mut Plumber) {
     #[cfg(any(target_os = "linux", target_os = "android"))]
     linux::register(plumber);
 
-    socks5::register(plumber);
+    socks5::client::register(plumber);
+    socks5::server::register(plumber);
+    socks5::socks4_server::register(plumber);
     sniffer::register(plumber);
     http::client::register(plumber);
     http::server::register(plumber);
@@ -34,9 +38,11 @@ pub fn do_register(plumber: &mut Plumber) {
     any_proxy::register(plumber);
     set_dest::register(plumber);
     vmess::register(plumber);
+    vless::register(plumber);
     ws::register(plumber);
     trojan::register(plumber);
     tls::register(plumber);
+    tls_server::register(plumber);
 
     #[cfg(feature = "tls-mitm")]
     tls_mitm::register(plumber);
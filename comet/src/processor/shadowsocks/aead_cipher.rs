@@ -1,7 +1,37 @@
+use crate::crypto::aead::{AeadCrypter, NonceSeq, SsCrypter};
+use crate::crypto::hashing::{hkdf, HashKind};
 use crate::crypto::*;
 use crate::prelude::*;
+use crate::utils::io::*;
+use crate::utils::socks_addr::{decode_addr, encode_addr, encode_socket_addr};
 use aead::AeadCipherKind;
+use anyhow::bail;
+use futures::ready;
 use shadowsocks_crypto::v1::openssl_bytes_to_key;
+use std::cmp;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc::channel;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::io::poll_read_buf;
+
+pub fn register(plumber: &mut Plumber) {
+    plumber.register("ss_aead_client", |conf, _| {
+        let config: CipherConfig = from_value(conf)?;
+        let processor = ClientProcessor::new(config.method, config.password.as_str());
+        Ok(Box::new(processor))
+    });
+    plumber.register("ss_aead_server", |conf, _| {
+        let config: CipherConfig = from_value(conf)?;
+        let processor = ServerProcessor::new(config.method, config.password.as_str());
+        Ok(Box::new(processor))
+    });
+}
+
+/// Payload size limit of a single chunk.
+const MAX_PAYLOAD_LEN: usize = 0x3FFF;
+const SUBKEY_INFO: &[u8] = b"ss-subkey";
 
 #[derive(Deserialize, Debug, Clone, Copy)]
 pub enum SsAeadCipherKind {
@@ -9,6 +39,8 @@ pub enum SsAeadCipherKind {
     Aes128Gcm,
     #[serde(rename = "aes-256-gcm")]
     Aes256Gcm,
+    #[serde(rename = "chacha20-ietf-poly1305")]
+    Chacha20Poly1305,
 }
 
 impl From<SsAeadCipherKind> for AeadCipherKind {
@@ -16,6 +48,7 @@ impl From<SsAeadCipherKind> for AeadCipherKind {
         match val {
             SsAeadCipherKind::Aes128Gcm => AeadCipherKind::Aes128Gcm,
             SsAeadCipherKind::Aes256Gcm => AeadCipherKind::Aes256Gcm,
+            SsAeadCipherKind::Chacha20Poly1305 => AeadCipherKind::Chacha20Poly1305,
         }
     }
 }
@@ -28,9 +61,13 @@ impl SsAeadCipherKind {
         Bytes::from(key)
     }
 
-    fn generate_salt(&self) -> Result<Bytes> {
+    fn salt_len(&self) -> usize {
         let cipher_kind: AeadCipherKind = (*self).into();
-        let salt_len = cipher_kind.iv_len();
+        cipher_kind.iv_len()
+    }
+
+    fn generate_salt(&self) -> Result<Bytes> {
+        let salt_len = self.salt_len();
         let mut salt = BytesMut::with_capacity(salt_len);
         unsafe {
             salt.set_len(salt_len);
@@ -39,4 +76,476 @@ impl SsAeadCipherKind {
         random::rand_bytes(&mut salt)?;
         Ok(salt.freeze())
     }
+
+    /// Creates a crypter keyed with the HKDF-SHA1 subkey of `salt`.
+    fn to_crypter(
+        self,
+        mode: CrypterMode,
+        master_key: &[u8],
+        salt: &[u8],
+    ) -> Result<SsCrypter<SsNonceSeq>> {
+        let cipher_kind: AeadCipherKind = self.into();
+        let mut subkey = vec![0u8; cipher_kind.key_len()];
+        hkdf(HashKind::Sha1, salt, master_key, SUBKEY_INFO, &mut subkey);
+        cipher_kind.to_crypter(mode, &subkey, SsNonceSeq::default())
+    }
+
+    /// Seals a UDP packet as `salt | payload | tag`.
+    fn encrypt_packet(&self, master_key: &[u8], payload: &[u8]) -> Result<BytesMut> {
+        let salt = self.generate_salt()?;
+        let mut crypter = self.to_crypter(CrypterMode::Encrypt, master_key, &salt)?;
+
+        let mut buf = BytesMut::with_capacity(salt.len() + payload.len() + crypter.tag_len());
+        buf.put_slice(&salt);
+        buf.put_slice(payload);
+        buf.put_bytes(0, crypter.tag_len());
+        crypter.update(&mut buf[salt.len()..])?;
+        Ok(buf)
+    }
+
+    fn decrypt_packet(&self, master_key: &[u8], mut packet: BytesMut) -> Result<BytesMut> {
+        if packet.len() < self.salt_len() {
+            bail!("Packet too short");
+        }
+        let mut payload = packet.split_off(self.salt_len());
+        let mut crypter = self.to_crypter(CrypterMode::Decrypt, master_key, &packet)?;
+        if payload.len() < crypter.tag_len() {
+            bail!("Packet too short");
+        }
+
+        let n = crypter.update(&mut payload)?;
+        payload.truncate(n);
+        Ok(payload)
+    }
+}
+
+/// Little-endian counter starting from zero.
+#[derive(Debug, Default)]
+pub struct SsNonceSeq([u8; 12]);
+
+impl NonceSeq for SsNonceSeq {
+    fn advance(&mut self) -> Option<[u8; 12]> {
+        let nonce = self.0;
+        for b in self.0.iter_mut() {
+            *b = b.wrapping_add(1);
+            if *b != 0 {
+                break;
+            }
+        }
+        Some(nonce)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CipherConfig {
+    method: SsAeadCipherKind,
+    password: SmolStr,
+}
+
+#[derive(Debug)]
+pub struct ClientProcessor {
+    method: SsAeadCipherKind,
+    master_key: Bytes,
+}
+
+impl ClientProcessor {
+    pub fn new(method: SsAeadCipherKind, password: &str) -> Self {
+        let key = method.derive_key(password);
+        Self {
+            method,
+            master_key: key,
+        }
+    }
+
+    fn process_udp(&self, outbound: UdpStream, conn: &mut Connection) -> Result<ProxyStream> {
+        let mut outbound = outbound;
+        let method = self.method;
+        let master_key = self.master_key.clone();
+        let default_dest = conn.dest_addr.clone();
+
+        let (read_sender, read_receiver) = channel::<UdpPacket>(10);
+        let (write_sender, mut write_receiver) = channel::<UdpPacket>(10);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    Some(packet) = write_receiver.recv() => {
+                        let mut buf = BytesMut::with_capacity(1 + 256 + 2 + packet.len());
+                        if let Some(target) = packet.target() {
+                            encode_socket_addr(&target, &mut buf);
+                        } else if let Err(e) = encode_addr(&default_dest, &mut buf) {
+                            warn!("Dropping UDP packet: {}", e);
+                            continue;
+                        }
+                        buf.extend_from_slice(&packet);
+
+                        let packet = match method.encrypt_packet(&master_key, &buf) {
+                            Ok(packet) => packet,
+                            Err(e) => {
+                                warn!("Dropping UDP packet: {}", e);
+                                continue;
+                            }
+                        };
+                        if outbound.send(UdpPacket::new_unknown(packet)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(packet) = outbound.next() => {
+                        let packet = method
+                            .decrypt_packet(&master_key, BytesMut::from(&packet[..]))
+                            .and_then(|payload| {
+                                let (src, len) = decode_addr(&payload)?;
+                                let src = SocketAddr::new(*src.ip_or_error()?, src.port_or_error()?);
+                                Ok(UdpPacket::new(src, BytesMut::from(&payload[len..])))
+                            });
+                        match packet {
+                            Ok(packet) => {
+                                if read_sender.send(packet).await.is_err() {
+                                    // Dropped
+                                    break;
+                                }
+                            }
+                            Err(e) => warn!("Dropping UDP packet: {}", e),
+                        }
+                    }
+                    _ = read_sender.closed() => break,
+                    else => break,
+                }
+            }
+        });
+
+        Ok(UdpStream::new(ReceiverStream::new(read_receiver), write_sender).into())
+    }
+}
+
+#[async_trait]
+impl Processor for ClientProcessor {
+    async fn process(
+        self: Arc<Self>,
+        stream: ProxyStream,
+        conn: &mut Connection,
+        _ctx: AppContextRef,
+    ) -> Result<ProxyStream> {
+        let stream = match stream {
+            ProxyStream::Tcp(s) => s,
+            ProxyStream::Udp(s) => return self.process_udp(s, conn),
+        };
+        let stream = AeadStream::new(stream, self.method, &self.master_key)?;
+        Ok(RWPair::new(stream).into())
+    }
+}
+
+#[derive(Debug)]
+pub struct ServerProcessor {
+    method: SsAeadCipherKind,
+    master_key: Bytes,
+}
+
+impl ServerProcessor {
+    pub fn new(method: SsAeadCipherKind, password: &str) -> Self {
+        let key = method.derive_key(password);
+        Self {
+            method,
+            master_key: key,
+        }
+    }
+
+    fn process_udp(&self, inbound: UdpStream, ctx: AppContextRef) -> Result<ProxyStream> {
+        let mut inbound = inbound;
+        let method = self.method;
+        let master_key = self.master_key.clone();
+
+        let (read_sender, read_receiver) = channel::<UdpPacket>(10);
+        let (write_sender, mut write_receiver) = channel::<UdpPacket>(10);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    Some(packet) = inbound.next() => {
+                        match decode_server_packet(method, &master_key, BytesMut::from(&packet[..]), &ctx).await {
+                            Ok(packet) => {
+                                if read_sender.send(packet).await.is_err() {
+                                    // Dropped
+                                    break;
+                                }
+                            }
+                            Err(e) => warn!("Dropping UDP packet: {}", e),
+                        }
+                    }
+                    Some(packet) = write_receiver.recv() => {
+                        let src = match packet.target() {
+                            Some(src) => src,
+                            None => {
+                                warn!("Dropping UDP packet without source");
+                                continue;
+                            }
+                        };
+                        let mut buf = BytesMut::with_capacity(1 + 16 + 2 + packet.len());
+                        encode_socket_addr(&src, &mut buf);
+                        buf.extend_from_slice(&packet);
+
+                        match method.encrypt_packet(&master_key, &buf) {
+                            Ok(packet) => {
+                                if inbound.send(UdpPacket::new_unknown(packet)).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Err(e) => warn!("Dropping UDP packet: {}", e),
+                        }
+                    }
+                    _ = read_sender.closed() => break,
+                    else => break,
+                }
+            }
+        });
+
+        Ok(UdpStream::new(ReceiverStream::new(read_receiver), write_sender).into())
+    }
+}
+
+async fn decode_server_packet(
+    method: SsAeadCipherKind,
+    master_key: &[u8],
+    packet: BytesMut,
+    ctx: &AppContextRef,
+) -> Result<UdpPacket> {
+    let payload = method.decrypt_packet(master_key, packet)?;
+    let (dest, len) = decode_addr(&payload)?;
+    let ips = ctx.dns.resolve_addr(&dest, ctx).await?;
+    let ip = match ips.first() {
+        Some(ip) => *ip,
+        None => bail!("No address for {}", dest),
+    };
+    let target = SocketAddr::new(ip, dest.port_or_error()?);
+    Ok(UdpPacket::new(target, BytesMut::from(&payload[len..])))
+}
+
+#[async_trait]
+impl Processor for ServerProcessor {
+    async fn process(
+        self: Arc<Self>,
+        stream: ProxyStream,
+        _conn: &mut Connection,
+        ctx: AppContextRef,
+    ) -> Result<ProxyStream> {
+        let stream = match stream {
+            ProxyStream::Tcp(s) => s,
+            ProxyStream::Udp(s) => return self.process_udp(s, ctx),
+        };
+        let stream = AeadStream::new(stream, self.method, &self.master_key)?;
+        Ok(RWPair::new(stream).into())
+    }
+}
+
+/// AEAD chunk stream, identical in both directions:
+///
+/// `salt | [encrypted len][len tag] | [encrypted payload][payload tag] | ...`
+#[pin_project::pin_project]
+#[derive(Debug)]
+struct AeadStream<RW> {
+    #[pin]
+    inner: RW,
+    method: SsAeadCipherKind,
+    master_key: Bytes,
+    // Writing
+    encrypter: SsCrypter<SsNonceSeq>,
+    write_state: WriteState,
+    write_buf: BytesMut,
+    // Reading
+    decrypter: Option<SsCrypter<SsNonceSeq>>,
+    read_state: ReadState,
+    read_buf: BytesMut,
+}
+
+impl<RW> AeadStream<RW> {
+    fn new(inner: RW, method: SsAeadCipherKind, master_key: &[u8]) -> Result<Self> {
+        let salt = method.generate_salt()?;
+        let encrypter = method.to_crypter(CrypterMode::Encrypt, master_key, &salt)?;
+        let mut write_buf = BytesMut::with_capacity(salt.len() + 2 * MAX_PAYLOAD_LEN);
+        write_buf.put_slice(&salt);
+
+        Ok(Self {
+            inner,
+            method,
+            master_key: Bytes::copy_from_slice(master_key),
+
+            encrypter,
+            write_state: WriteState::Waiting,
+            write_buf,
+
+            decrypter: None,
+            read_state: ReadState::ReadSalt,
+            read_buf: BytesMut::with_capacity(MAX_PAYLOAD_LEN),
+        })
+    }
+}
+
+#[derive(Debug)]
+enum WriteState {
+    Waiting,
+    Writing { consumed: usize, written: usize },
+}
+
+impl<RW: AsyncWrite + Unpin> AsyncWrite for AeadStream<RW> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        let mut this = self.project();
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        loop {
+            match this.write_state {
+                WriteState::Waiting => {
+                    let consumed = cmp::min(buf.len(), MAX_PAYLOAD_LEN);
+                    let tag_len = this.encrypter.tag_len();
+
+                    let len_start = this.write_buf.len();
+                    this.write_buf.put_u16(consumed as u16);
+                    this.write_buf.put_bytes(0, tag_len);
+                    this.encrypter
+                        .update(&mut this.write_buf[len_start..])
+                        .map_err(|_| crypto_error())?;
+
+                    let payload_start = this.write_buf.len();
+                    this.write_buf.put_slice(&buf[..consumed]);
+                    this.write_buf.put_bytes(0, tag_len);
+                    this.encrypter
+                        .update(&mut this.write_buf[payload_start..])
+                        .map_err(|_| crypto_error())?;
+
+                    *this.write_state = WriteState::Writing {
+                        consumed,
+                        written: 0,
+                    };
+                }
+                WriteState::Writing {
+                    consumed,
+                    ref mut written,
+                } => {
+                    let n = ready!(this
+                        .inner
+                        .as_mut()
+                        .poll_write(cx, &this.write_buf[*written..]))?;
+
+                    *written += n;
+                    if *written >= this.write_buf.len() {
+                        // Writing complete
+                        let result = Poll::Ready(Ok(*consumed));
+                        *this.write_state = WriteState::Waiting;
+                        this.write_buf.clear();
+                        return result;
+                    }
+                }
+            }
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[derive(Debug)]
+enum ReadState {
+    ReadSalt,
+    ReadLength,
+    ReadPayload(usize),
+    Consume(BytesMut),
+}
+
+/// Reads until `buf` holds at least `len` bytes, returning `false` on EOF.
+pub(super) fn poll_fill<R: AsyncRead>(
+    mut inner: Pin<&mut R>,
+    cx: &mut Context<'_>,
+    buf: &mut BytesMut,
+    len: usize,
+) -> Poll<IoResult<bool>> {
+    while buf.len() < len {
+        buf.reserve(len - buf.len());
+        if ready!(poll_read_buf(inner.as_mut(), cx, buf))? == 0 {
+            return Poll::Ready(Ok(false));
+        }
+    }
+    Poll::Ready(Ok(true))
+}
+
+impl<RW: AsyncRead + Unpin> AsyncRead for AeadStream<RW> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        let mut this = self.project();
+
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            match this.read_state {
+                ReadState::ReadSalt => {
+                    let salt_len = this.method.salt_len();
+                    if !ready!(poll_fill(this.inner.as_mut(), cx, this.read_buf, salt_len))? {
+                        if this.read_buf.is_empty() {
+                            return Poll::Ready(Ok(()));
+                        }
+                        return Poll::Ready(Err(eof()));
+                    }
+
+                    let salt = this.read_buf.split_to(salt_len);
+                    let dec = this
+                        .method
+                        .to_crypter(CrypterMode::Decrypt, this.master_key, &salt)
+                        .map_err(|_| crypto_error())?;
+                    *this.decrypter = Some(dec);
+                    *this.read_state = ReadState::ReadLength;
+                }
+                ReadState::ReadLength => {
+                    let dec = this.decrypter.as_mut().unwrap();
+                    let chunk_len = 2 + dec.tag_len();
+                    if !ready!(poll_fill(this.inner.as_mut(), cx, this.read_buf, chunk_len))? {
+                        if this.read_buf.is_empty() {
+                            // EOF between chunks
+                            return Poll::Ready(Ok(()));
+                        }
+                        return Poll::Ready(Err(eof()));
+                    }
+
+                    let mut chunk = this.read_buf.split_to(chunk_len);
+                    dec.update(&mut chunk).map_err(|_| crypto_error())?;
+                    let len = u16::from_be_bytes([chunk[0], chunk[1]]) as usize & MAX_PAYLOAD_LEN;
+                    *this.read_state = ReadState::ReadPayload(len);
+                }
+                ReadState::ReadPayload(len) => {
+                    let dec = this.decrypter.as_mut().unwrap();
+                    let chunk_len = *len + dec.tag_len();
+                    if !ready!(poll_fill(this.inner.as_mut(), cx, this.read_buf, chunk_len))? {
+                        return Poll::Ready(Err(eof()));
+                    }
+
+                    let mut chunk = this.read_buf.split_to(chunk_len);
+                    let n = dec.update(&mut chunk).map_err(|_| crypto_error())?;
+                    chunk.truncate(n);
+                    *this.read_state = if chunk.is_empty() {
+                        ReadState::ReadLength
+                    } else {
+                        ReadState::Consume(chunk)
+                    };
+                }
+                ReadState::Consume(chunk) => {
+                    let n = cmp::min(chunk.len(), buf.remaining());
+                    buf.put_slice(&chunk.split_to(n));
+                    if chunk.is_empty() {
+                        *this.read_state = ReadState::ReadLength;
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
 }
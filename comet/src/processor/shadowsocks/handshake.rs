@@ -1,10 +1,14 @@
 use crate::utils::io::io_other_error;
+use crate::utils::socks_addr::read_addr;
 use crate::{prelude::*, utils::prepend_io::PrependWriter};
 
 pub fn register(plumber: &mut Plumber) {
     plumber.register("ss_handshake_client", |_, _| {
         Ok(Box::new(ShadowsocksClientHandshakeProcessor::new()))
     });
+    plumber.register("ss_handshake_server", |_, _| {
+        Ok(Box::new(ShadowsocksServerHandshakeProcessor {}))
+    });
 }
 
 #[derive(Debug)]
@@ -71,3 +75,23 @@ impl Processor for ShadowsocksClientHandshakeProcessor {
         Ok(RWPair::new(PrependWriter::new(stream, buf)).into())
     }
 }
+
+/// Reads the address header into the destination.
+#[derive(Debug)]
+pub struct ShadowsocksServerHandshakeProcessor {}
+
+#[async_trait]
+impl Processor for ShadowsocksServerHandshakeProcessor {
+    async fn process(
+        self: Arc<Self>,
+        stream: ProxyStream,
+        conn: &mut Connection,
+        _ctx: AppContextRef,
+    ) -> Result<ProxyStream> {
+        let mut stream = stream.into_tcp()?;
+        let mut dest_addr = DestAddr::default();
+        read_addr(&mut stream, &mut dest_addr).await?;
+        conn.dest_addr = dest_addr;
+        Ok(stream.into())
+    }
+}
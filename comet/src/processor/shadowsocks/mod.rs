@@ -2,11 +2,12 @@ mod aead_cipher;
 mod auth;
 mod handshake;
 mod obfs;
+mod ss2022;
 mod stream_cipher;
 
 use crate::Plumber;
 use crate::{prelude::*, utils::urlsafe_base64_decode_string};
-use handshake::ShadowsocksClientHandshakeProcessor;
+use handshake::{ShadowsocksClientHandshakeProcessor, ShadowsocksServerHandshakeProcessor};
 
 use anyhow::anyhow;
 
@@ -14,6 +15,12 @@ use anyhow::anyhow;
 enum MethodType {
     #[serde(rename = "aes-256-cfb")]
     Aes256Cfb,
+    #[serde(rename = "aes-128-gcm")]
+    Aes128Gcm,
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    #[serde(rename = "chacha20-ietf-poly1305")]
+    Chacha20Poly1305,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -100,8 +107,23 @@ impl SsrClientProcessor {
             }
         };
 
-        let cipher: Option<Arc<dyn Processor>> = match config.method {
-            MethodType::Aes256Cfb => Some(Arc::new(stream_cipher::ClientProcessor::new(
+        let aead_kind = match config.method {
+            MethodType::Aes256Cfb => None,
+            MethodType::Aes128Gcm => Some(aead_cipher::SsAeadCipherKind::Aes128Gcm),
+            MethodType::Aes256Gcm => Some(aead_cipher::SsAeadCipherKind::Aes256Gcm),
+            MethodType::Chacha20Poly1305 => Some(aead_cipher::SsAeadCipherKind::Chacha20Poly1305),
+        };
+        if aead_kind.is_some() && auth.is_some() {
+            // SSR protocols are keyed by the stream cipher's IV
+            return Err(anyhow!("SSR protocols require a stream cipher"));
+        }
+
+        let cipher: Option<Arc<dyn Processor>> = match aead_kind {
+            Some(kind) => Some(Arc::new(aead_cipher::ClientProcessor::new(
+                kind,
+                config.password.as_str(),
+            ))),
+            None => Some(Arc::new(stream_cipher::ClientProcessor::new(
                 stream_cipher::SsStreamCipherKind::Aes256Cfb,
                 config.password.as_str(),
             ))),
@@ -158,9 +180,11 @@ impl Processor for SsrClientProcessor {
 }
 
 pub fn register(plumber: &mut Plumber) {
+    aead_cipher::register(plumber);
     auth::register(plumber);
     handshake::register(plumber);
     obfs::register(plumber);
+    ss2022::register(plumber);
     stream_cipher::register(plumber);
 
     plumber.register("ssr_client", |config, _| {
@@ -175,6 +199,80 @@ pub fn register(plumber: &mut Plumber) {
 
         Ok(Box::new(SsrClientProcessor::new(config, dest)?))
     });
+
+    plumber.register("ss_server", |config, _| {
+        let config: ServerConfig = from_value(config)?;
+        Ok(Box::new(SsServerProcessor::new(config)?))
+    });
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(untagged)]
+enum ServerMethod {
+    Stream(stream_cipher::SsStreamCipherKind),
+    Aead(aead_cipher::SsAeadCipherKind),
+    Ss2022(ss2022::Ss2022CipherKind),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerConfig {
+    method: ServerMethod,
+    password: SmolStr,
+}
+
+/// Shadowsocks inbound, decrypting the stream and reading the destination from it.
+pub struct SsServerProcessor {
+    cipher: Arc<dyn Processor>,
+    /// SS2022 reads the address as part of its own header.
+    handshake: Option<Arc<ShadowsocksServerHandshakeProcessor>>,
+}
+
+impl SsServerProcessor {
+    fn new(config: ServerConfig) -> Result<Self> {
+        let password = config.password.as_str();
+        let handshake = Some(Arc::new(ShadowsocksServerHandshakeProcessor {}));
+
+        Ok(match config.method {
+            // Stream cipher framing is the same in both directions
+            ServerMethod::Stream(kind) => Self {
+                cipher: Arc::new(stream_cipher::ClientProcessor::new(kind, password)),
+                handshake,
+            },
+            ServerMethod::Aead(kind) => Self {
+                cipher: Arc::new(aead_cipher::ServerProcessor::new(kind, password)),
+                handshake,
+            },
+            ServerMethod::Ss2022(method) => Self {
+                cipher: Arc::new(ss2022::ServerProcessor::new(ss2022::ServerConfig {
+                    method,
+                    password: config.password,
+                })?),
+                handshake: None,
+            },
+        })
+    }
+}
+
+#[async_trait]
+impl Processor for SsServerProcessor {
+    async fn process(
+        self: Arc<Self>,
+        mut stream: ProxyStream,
+        conn: &mut Connection,
+        ctx: AppContextRef,
+    ) -> Result<ProxyStream> {
+        // UDP packets carry their own addresses
+        let is_tcp = matches!(stream, ProxyStream::Tcp(_));
+        stream = self
+            .cipher
+            .clone()
+            .process(stream, conn, ctx.clone())
+            .await?;
+        if let (true, Some(handshake)) = (is_tcp, &self.handshake) {
+            stream = handshake.clone().process(stream, conn, ctx).await?;
+        }
+        Ok(stream)
+    }
 }
 
 #[derive(Debug, Clone)]
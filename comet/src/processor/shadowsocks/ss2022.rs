@@ -0,0 +1,916 @@
+//! Shadowsocks 2022 (SIP022) with the `2022-blake3-aes-*-gcm` methods.
+use super::aead_cipher::{poll_fill, SsNonceSeq};
+use crate::crypto::aead::{AeadCipherKind, AeadCrypter, FixedNonce, NonceSeq, SsCrypter};
+use crate::crypto::*;
+use crate::prelude::*;
+use crate::utils::io::*;
+use crate::utils::socks_addr::{decode_addr, encode_addr, encode_socket_addr};
+use crate::utils::unix_ts;
+use aes::cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit};
+use anyhow::{anyhow, bail};
+use futures::ready;
+use lru_cache::LruCache;
+use rand::{thread_rng, Rng};
+use std::cmp;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::channel;
+use tokio_stream::wrappers::ReceiverStream;
+
+pub fn register(plumber: &mut Plumber) {
+    plumber.register("ss2022_client", |conf, _| {
+        let config: ClientConfig = from_value(conf)?;
+        Ok(Box::new(ClientProcessor::new(config)?))
+    });
+    plumber.register("ss2022_server", |conf, _| {
+        let config: ServerConfig = from_value(conf)?;
+        Ok(Box::new(ServerProcessor::new(config)?))
+    });
+}
+
+const MAX_PAYLOAD_LEN: usize = 0xFFFF;
+/// Maximum clock difference between peers, in seconds.
+const MAX_TIME_DIFF: u64 = 30;
+/// How long a salt is remembered for replay detection.
+const SALT_TTL: Duration = Duration::from_secs(60);
+/// Interval between sweeps of expired salts.
+const SALT_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+const SUBKEY_CONTEXT: &str = "shadowsocks 2022 session subkey";
+
+const TYPE_CLIENT: u8 = 0;
+const TYPE_SERVER: u8 = 1;
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum Ss2022CipherKind {
+    #[serde(rename = "2022-blake3-aes-128-gcm")]
+    Aes128Gcm,
+    #[serde(rename = "2022-blake3-aes-256-gcm")]
+    Aes256Gcm,
+}
+
+impl From<Ss2022CipherKind> for AeadCipherKind {
+    fn from(val: Ss2022CipherKind) -> Self {
+        match val {
+            Ss2022CipherKind::Aes128Gcm => AeadCipherKind::Aes128Gcm,
+            Ss2022CipherKind::Aes256Gcm => AeadCipherKind::Aes256Gcm,
+        }
+    }
+}
+
+impl Ss2022CipherKind {
+    /// Salts are as long as keys.
+    fn key_len(self) -> usize {
+        AeadCipherKind::from(self).key_len()
+    }
+
+    /// Decodes the base64 pre-shared key.
+    fn decode_key(self, password: &str) -> Result<Bytes> {
+        let key = base64::decode(password)?;
+        if key.len() != self.key_len() {
+            bail!("Key must be {} bytes, got {}", self.key_len(), key.len());
+        }
+        Ok(key.into())
+    }
+
+    fn generate_salt(self) -> Result<Bytes> {
+        let mut salt = vec![0u8; self.key_len()];
+        random::rand_bytes(&mut salt)?;
+        Ok(salt.into())
+    }
+
+    /// Creates a crypter keyed with the BLAKE3 subkey of `salt`.
+    fn to_crypter<N: NonceSeq>(
+        self,
+        mode: CrypterMode,
+        psk: &[u8],
+        salt: &[u8],
+        nonce: N,
+    ) -> Result<SsCrypter<N>> {
+        let material = [psk, salt].concat();
+        let subkey = blake3::derive_key(SUBKEY_CONTEXT, &material);
+        AeadCipherKind::from(self).to_crypter(mode, &subkey[..self.key_len()], nonce)
+    }
+}
+
+/// Remembers recent salts to reject replays.
+#[derive(Debug)]
+struct SaltPool(Mutex<SaltPoolInner>);
+
+#[derive(Debug)]
+struct SaltPoolInner {
+    seen: HashMap<Bytes, Instant>,
+    last_sweep: Instant,
+}
+
+impl Default for SaltPool {
+    fn default() -> Self {
+        Self(Mutex::new(SaltPoolInner {
+            seen: HashMap::new(),
+            last_sweep: Instant::now(),
+        }))
+    }
+}
+
+impl SaltPool {
+    /// Returns `false` if `salt` has been seen.
+    fn insert(&self, salt: &[u8]) -> bool {
+        let mut pool = self.0.lock().unwrap();
+        let now = Instant::now();
+        if now.duration_since(pool.last_sweep) >= SALT_SWEEP_INTERVAL {
+            pool.seen
+                .retain(|_, seen| now.duration_since(*seen) < SALT_TTL);
+            pool.last_sweep = now;
+        }
+        !matches!(pool.seen.insert(Bytes::copy_from_slice(salt), now),
+            Some(seen) if now.duration_since(seen) < SALT_TTL)
+    }
+}
+
+fn check_timestamp(ts: u64) -> Result<()> {
+    let now = unix_ts().as_secs();
+    if now.abs_diff(ts) > MAX_TIME_DIFF {
+        bail!("Timestamp out of range: {}", ts);
+    }
+    Ok(())
+}
+
+/// Seals everything in `buf` after `start` as one chunk, appending the tag.
+fn seal(encrypter: &mut SsCrypter<SsNonceSeq>, buf: &mut BytesMut, start: usize) -> IoResult<()> {
+    buf.put_bytes(0, encrypter.tag_len());
+    encrypter
+        .update(&mut buf[start..])
+        .map_err(|_| crypto_error())?;
+    Ok(())
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ClientConfig {
+    server: Option<DestAddr>,
+    method: Ss2022CipherKind,
+    /// Base64 encoded key.
+    password: SmolStr,
+}
+
+pub struct ClientProcessor {
+    server: Option<DestAddr>,
+    method: Ss2022CipherKind,
+    psk: Bytes,
+    header_cipher: Arc<HeaderCipher>,
+    salt_pool: Arc<SaltPool>,
+}
+
+impl ClientProcessor {
+    pub fn new(config: ClientConfig) -> Result<Self> {
+        let psk = config.method.decode_key(&config.password)?;
+        Ok(Self {
+            server: config.server,
+            method: config.method,
+            header_cipher: Arc::new(HeaderCipher::new(config.method, &psk)),
+            psk,
+            salt_pool: Default::default(),
+        })
+    }
+
+    fn process_udp(&self, outbound: UdpStream, conn: &mut Connection) -> Result<ProxyStream> {
+        let mut outbound = outbound;
+        let mut session = UdpSession::new(
+            self.method,
+            self.psk.clone(),
+            self.header_cipher.clone(),
+            false,
+        );
+        let default_dest = conn.dest_addr.clone();
+
+        let (read_sender, read_receiver) = channel::<UdpPacket>(10);
+        let (write_sender, mut write_receiver) = channel::<UdpPacket>(10);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    Some(packet) = write_receiver.recv() => {
+                        match session.encode(&packet, &default_dest) {
+                            Ok(packet) => {
+                                if outbound.send(UdpPacket::new_unknown(packet)).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Err(e) => warn!("Dropping UDP packet: {}", e),
+                        }
+                    }
+                    Some(packet) = outbound.next() => {
+                        let packet = session.decode(BytesMut::from(&packet[..])).and_then(|(src, payload)| {
+                            let src = SocketAddr::new(*src.ip_or_error()?, src.port_or_error()?);
+                            Ok(UdpPacket::new(src, payload))
+                        });
+                        match packet {
+                            Ok(packet) => {
+                                if read_sender.send(packet).await.is_err() {
+                                    // Dropped
+                                    break;
+                                }
+                            }
+                            Err(e) => warn!("Dropping UDP packet: {}", e),
+                        }
+                    }
+                    _ = read_sender.closed() => break,
+                    else => break,
+                }
+            }
+        });
+
+        Ok(UdpStream::new(ReceiverStream::new(read_receiver), write_sender).into())
+    }
+}
+
+#[async_trait]
+impl Processor for ClientProcessor {
+    async fn prepare(self: Arc<Self>, conn: &mut Connection, _ctx: AppContextRef) -> Result<()> {
+        if let Some(server) = &self.server {
+            conn.dest_addr = server.clone();
+        }
+        Ok(())
+    }
+
+    async fn process(
+        self: Arc<Self>,
+        stream: ProxyStream,
+        conn: &mut Connection,
+        _ctx: AppContextRef,
+    ) -> Result<ProxyStream> {
+        let stream = match stream {
+            ProxyStream::Tcp(s) => s,
+            ProxyStream::Udp(s) => return self.process_udp(s, conn),
+        };
+
+        let mut addr = BytesMut::with_capacity(1 + 256 + 2);
+        encode_addr(&conn.dest_addr, &mut addr)?;
+        let stream = Ss2022Stream::new(
+            stream,
+            self.method,
+            self.psk.clone(),
+            self.salt_pool.clone(),
+            PendingHeader::Request(addr),
+        )?;
+        Ok(RWPair::new(stream).into())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ServerConfig {
+    pub method: Ss2022CipherKind,
+    /// Base64 encoded key.
+    pub password: SmolStr,
+}
+
+pub struct ServerProcessor {
+    method: Ss2022CipherKind,
+    psk: Bytes,
+    header_cipher: Arc<HeaderCipher>,
+    salt_pool: Arc<SaltPool>,
+}
+
+impl ServerProcessor {
+    pub fn new(config: ServerConfig) -> Result<Self> {
+        let psk = config.method.decode_key(&config.password)?;
+        Ok(Self {
+            method: config.method,
+            header_cipher: Arc::new(HeaderCipher::new(config.method, &psk)),
+            psk,
+            salt_pool: Default::default(),
+        })
+    }
+
+    /// Reads the request header, setting the destination of `conn`.
+    async fn accept(
+        &self,
+        mut stream: RWPair,
+        conn: &mut Connection,
+    ) -> Result<Ss2022Stream<RWPair>> {
+        let mut salt = BytesMut::zeroed(self.method.key_len());
+        stream.read_exact(&mut salt).await?;
+        let mut dec = self.method.to_crypter(
+            CrypterMode::Decrypt,
+            &self.psk,
+            &salt,
+            SsNonceSeq::default(),
+        )?;
+
+        let mut header = BytesMut::zeroed(1 + 8 + 2 + dec.tag_len());
+        stream.read_exact(&mut header).await?;
+        dec.update(&mut header)?;
+        if header.get_u8() != TYPE_CLIENT {
+            bail!("Unexpected header type");
+        }
+        check_timestamp(header.get_u64())?;
+        if !self.salt_pool.insert(&salt) {
+            bail!("Replayed salt");
+        }
+
+        let len = header.get_u16() as usize;
+        let mut header = BytesMut::zeroed(len + dec.tag_len());
+        stream.read_exact(&mut header).await?;
+        let n = dec.update(&mut header)?;
+        header.truncate(n);
+
+        let (dest, len) = decode_addr(&header)?;
+        header.advance(len);
+        if header.len() < 2 {
+            bail!("Header incomplete");
+        }
+        let padding_len = header.get_u16() as usize;
+        if header.len() < padding_len {
+            bail!("Padding incomplete");
+        }
+        header.advance(padding_len);
+        conn.dest_addr = dest;
+
+        let mut stream = Ss2022Stream::new(
+            stream,
+            self.method,
+            self.psk.clone(),
+            self.salt_pool.clone(),
+            PendingHeader::Response(salt),
+        )?;
+        stream.decrypter = Some(dec);
+        stream.read_state = if header.is_empty() {
+            ReadState::ReadLength
+        } else {
+            // Initial payload
+            ReadState::Consume(header)
+        };
+        Ok(stream)
+    }
+
+    fn process_udp(&self, inbound: UdpStream, ctx: AppContextRef) -> Result<ProxyStream> {
+        let mut inbound = inbound;
+        let mut session = UdpSession::new(
+            self.method,
+            self.psk.clone(),
+            self.header_cipher.clone(),
+            true,
+        );
+        let default_dest = DestAddr::default();
+
+        let (read_sender, read_receiver) = channel::<UdpPacket>(10);
+        let (write_sender, mut write_receiver) = channel::<UdpPacket>(10);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    Some(packet) = inbound.next() => {
+                        let packet = match session.decode(BytesMut::from(&packet[..])) {
+                            Ok((dest, payload)) => resolve_packet(dest, payload, &ctx).await,
+                            Err(e) => Err(e),
+                        };
+                        match packet {
+                            Ok(packet) => {
+                                if read_sender.send(packet).await.is_err() {
+                                    // Dropped
+                                    break;
+                                }
+                            }
+                            Err(e) => warn!("Dropping UDP packet: {}", e),
+                        }
+                    }
+                    Some(packet) = write_receiver.recv() => {
+                        match session.encode(&packet, &default_dest) {
+                            Ok(packet) => {
+                                if inbound.send(UdpPacket::new_unknown(packet)).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Err(e) => warn!("Dropping UDP packet: {}", e),
+                        }
+                    }
+                    _ = read_sender.closed() => break,
+                    else => break,
+                }
+            }
+        });
+
+        Ok(UdpStream::new(ReceiverStream::new(read_receiver), write_sender).into())
+    }
+}
+
+async fn resolve_packet(
+    dest: DestAddr,
+    payload: BytesMut,
+    ctx: &AppContextRef,
+) -> Result<UdpPacket> {
+    let ips = ctx.dns.resolve_addr(&dest, ctx).await?;
+    let ip = match ips.first() {
+        Some(ip) => *ip,
+        None => bail!("No address for {}", dest),
+    };
+    Ok(UdpPacket::new(
+        SocketAddr::new(ip, dest.port_or_error()?),
+        payload,
+    ))
+}
+
+#[async_trait]
+impl Processor for ServerProcessor {
+    async fn process(
+        self: Arc<Self>,
+        stream: ProxyStream,
+        conn: &mut Connection,
+        ctx: AppContextRef,
+    ) -> Result<ProxyStream> {
+        let stream = match stream {
+            ProxyStream::Tcp(s) => s,
+            ProxyStream::Udp(s) => return self.process_udp(s, ctx),
+        };
+        let stream = self.accept(stream, conn).await?;
+        Ok(RWPair::new(stream).into())
+    }
+}
+
+/// TCP stream of SIP022. The request or response header goes out with the first write.
+/// Clients check the response header on the first read, while servers parse the request
+/// header before constructing the stream.
+#[pin_project::pin_project]
+#[derive(Debug)]
+struct Ss2022Stream<RW> {
+    #[pin]
+    inner: RW,
+    method: Ss2022CipherKind,
+    psk: Bytes,
+    salt_pool: Arc<SaltPool>,
+    // Writing
+    salt: Bytes,
+    header: Option<PendingHeader>,
+    encrypter: SsCrypter<SsNonceSeq>,
+    write_state: WriteState,
+    write_buf: BytesMut,
+    // Reading
+    decrypter: Option<SsCrypter<SsNonceSeq>>,
+    read_state: ReadState,
+    read_buf: BytesMut,
+}
+
+impl<RW> Ss2022Stream<RW> {
+    fn new(
+        inner: RW,
+        method: Ss2022CipherKind,
+        psk: Bytes,
+        salt_pool: Arc<SaltPool>,
+        header: PendingHeader,
+    ) -> Result<Self> {
+        let salt = method.generate_salt()?;
+        let encrypter =
+            method.to_crypter(CrypterMode::Encrypt, &psk, &salt, SsNonceSeq::default())?;
+        let mut write_buf = BytesMut::with_capacity(8192);
+        write_buf.put_slice(&salt);
+
+        Ok(Self {
+            inner,
+            method,
+            psk,
+            salt_pool,
+
+            salt,
+            header: Some(header),
+            encrypter,
+            write_state: WriteState::Waiting,
+            write_buf,
+
+            decrypter: None,
+            read_state: ReadState::ReadSalt,
+            read_buf: BytesMut::with_capacity(8192),
+        })
+    }
+}
+
+/// Header to send with the first write.
+#[derive(Debug)]
+enum PendingHeader {
+    /// Encoded destination of a request.
+    Request(BytesMut),
+    /// Salt of the request being responded to.
+    Response(BytesMut),
+}
+
+#[derive(Debug)]
+enum WriteState {
+    Waiting,
+    Writing { consumed: usize, written: usize },
+}
+
+impl<RW: AsyncWrite + Unpin> AsyncWrite for Ss2022Stream<RW> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        let mut this = self.project();
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        loop {
+            match this.write_state {
+                WriteState::Waiting => {
+                    let consumed = match this.header.take() {
+                        Some(PendingHeader::Request(addr)) => {
+                            // Fixed-length header, then address, padding and initial payload
+                            let consumed = cmp::min(buf.len(), MAX_PAYLOAD_LEN - addr.len() - 2);
+                            let start = this.write_buf.len();
+                            this.write_buf.put_u8(TYPE_CLIENT);
+                            this.write_buf.put_u64(unix_ts().as_secs());
+                            this.write_buf.put_u16((addr.len() + 2 + consumed) as u16);
+                            seal(this.encrypter, this.write_buf, start)?;
+
+                            let start = this.write_buf.len();
+                            this.write_buf.put_slice(&addr);
+                            this.write_buf.put_u16(0);
+                            this.write_buf.put_slice(&buf[..consumed]);
+                            seal(this.encrypter, this.write_buf, start)?;
+                            consumed
+                        }
+                        header => {
+                            let consumed = cmp::min(buf.len(), MAX_PAYLOAD_LEN);
+                            let start = this.write_buf.len();
+                            if let Some(PendingHeader::Response(request_salt)) = header {
+                                this.write_buf.put_u8(TYPE_SERVER);
+                                this.write_buf.put_u64(unix_ts().as_secs());
+                                this.write_buf.put_slice(&request_salt);
+                            }
+                            this.write_buf.put_u16(consumed as u16);
+                            seal(this.encrypter, this.write_buf, start)?;
+
+                            let start = this.write_buf.len();
+                            this.write_buf.put_slice(&buf[..consumed]);
+                            seal(this.encrypter, this.write_buf, start)?;
+                            consumed
+                        }
+                    };
+
+                    *this.write_state = WriteState::Writing {
+                        consumed,
+                        written: 0,
+                    };
+                }
+                WriteState::Writing {
+                    consumed,
+                    ref mut written,
+                } => {
+                    let n = ready!(this
+                        .inner
+                        .as_mut()
+                        .poll_write(cx, &this.write_buf[*written..]))?;
+
+                    *written += n;
+                    if *written >= this.write_buf.len() {
+                        // Writing complete
+                        let result = Poll::Ready(Ok(*consumed));
+                        *this.write_state = WriteState::Waiting;
+                        this.write_buf.clear();
+                        return result;
+                    }
+                }
+            }
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[derive(Debug)]
+enum ReadState {
+    ReadSalt,
+    ReadHeader,
+    ReadLength,
+    ReadPayload(usize),
+    Consume(BytesMut),
+}
+
+impl<RW: AsyncRead + Unpin> AsyncRead for Ss2022Stream<RW> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        let mut this = self.project();
+
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            match this.read_state {
+                ReadState::ReadSalt => {
+                    let salt_len = this.method.key_len();
+                    if !ready!(poll_fill(this.inner.as_mut(), cx, this.read_buf, salt_len))? {
+                        if this.read_buf.is_empty() {
+                            return Poll::Ready(Ok(()));
+                        }
+                        return Poll::Ready(Err(eof()));
+                    }
+
+                    let salt = this.read_buf.split_to(salt_len);
+                    if !this.salt_pool.insert(&salt) {
+                        return Poll::Ready(Err(io_other_error("replayed salt")));
+                    }
+                    let dec = this
+                        .method
+                        .to_crypter(CrypterMode::Decrypt, this.psk, &salt, SsNonceSeq::default())
+                        .map_err(|_| crypto_error())?;
+                    *this.decrypter = Some(dec);
+                    *this.read_state = ReadState::ReadHeader;
+                }
+                ReadState::ReadHeader => {
+                    // Type, timestamp, request salt and length of the first chunk
+                    let dec = this.decrypter.as_mut().unwrap();
+                    let salt_len = this.salt.len();
+                    let header_len = 1 + 8 + salt_len + 2 + dec.tag_len();
+                    if !ready!(poll_fill(
+                        this.inner.as_mut(),
+                        cx,
+                        this.read_buf,
+                        header_len
+                    ))? {
+                        return Poll::Ready(Err(eof()));
+                    }
+
+                    let mut header = this.read_buf.split_to(header_len);
+                    dec.update(&mut header).map_err(|_| crypto_error())?;
+                    if header.get_u8() != TYPE_SERVER {
+                        return Poll::Ready(Err(io_other_error("unexpected header type")));
+                    }
+                    check_timestamp(header.get_u64()).map_err(io_other_error)?;
+                    if header.split_to(salt_len) != this.salt[..] {
+                        return Poll::Ready(Err(io_other_error("request salt mismatch")));
+                    }
+                    *this.read_state = ReadState::ReadPayload(header.get_u16() as usize);
+                }
+                ReadState::ReadLength => {
+                    let dec = this.decrypter.as_mut().unwrap();
+                    let chunk_len = 2 + dec.tag_len();
+                    if !ready!(poll_fill(this.inner.as_mut(), cx, this.read_buf, chunk_len))? {
+                        if this.read_buf.is_empty() {
+                            // EOF between chunks
+                            return Poll::Ready(Ok(()));
+                        }
+                        return Poll::Ready(Err(eof()));
+                    }
+
+                    let mut chunk = this.read_buf.split_to(chunk_len);
+                    dec.update(&mut chunk).map_err(|_| crypto_error())?;
+                    *this.read_state = ReadState::ReadPayload(chunk.get_u16() as usize);
+                }
+                ReadState::ReadPayload(len) => {
+                    let dec = this.decrypter.as_mut().unwrap();
+                    let chunk_len = *len + dec.tag_len();
+                    if !ready!(poll_fill(this.inner.as_mut(), cx, this.read_buf, chunk_len))? {
+                        return Poll::Ready(Err(eof()));
+                    }
+
+                    let mut chunk = this.read_buf.split_to(chunk_len);
+                    let n = dec.update(&mut chunk).map_err(|_| crypto_error())?;
+                    chunk.truncate(n);
+                    *this.read_state = if chunk.is_empty() {
+                        ReadState::ReadLength
+                    } else {
+                        ReadState::Consume(chunk)
+                    };
+                }
+                ReadState::Consume(chunk) => {
+                    let n = cmp::min(chunk.len(), buf.remaining());
+                    buf.put_slice(&chunk.split_to(n));
+                    if chunk.is_empty() {
+                        *this.read_state = ReadState::ReadLength;
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
+}
+
+/// AES block cipher keyed with the PSK, encrypting the UDP packet header.
+enum HeaderCipher {
+    Aes128(Box<aes::Aes128>),
+    Aes256(Box<aes::Aes256>),
+}
+
+impl HeaderCipher {
+    fn new(method: Ss2022CipherKind, psk: &[u8]) -> Self {
+        match method {
+            Ss2022CipherKind::Aes128Gcm => {
+                Self::Aes128(Box::new(aes::Aes128::new(GenericArray::from_slice(psk))))
+            }
+            Ss2022CipherKind::Aes256Gcm => {
+                Self::Aes256(Box::new(aes::Aes256::new(GenericArray::from_slice(psk))))
+            }
+        }
+    }
+
+    fn encrypt(&self, block: &mut [u8]) {
+        let block = GenericArray::from_mut_slice(block);
+        match self {
+            Self::Aes128(c) => c.encrypt_block(block),
+            Self::Aes256(c) => c.encrypt_block(block),
+        }
+    }
+
+    fn decrypt(&self, block: &mut [u8]) {
+        let block = GenericArray::from_mut_slice(block);
+        match self {
+            Self::Aes128(c) => c.decrypt_block(block),
+            Self::Aes256(c) => c.decrypt_block(block),
+        }
+    }
+}
+
+/// Number of peer sessions whose packet windows are kept.
+const PEER_WINDOWS: usize = 16;
+
+/// Sliding window over received packet IDs.
+#[derive(Default)]
+struct PacketWindow {
+    last: u64,
+    bitmap: u64,
+}
+
+impl PacketWindow {
+    /// Returns `false` for replayed or too old packets.
+    fn accept(&mut self, id: u64) -> bool {
+        if id > self.last {
+            let shift = id - self.last;
+            self.bitmap = if shift >= 64 { 0 } else { self.bitmap << shift };
+            self.bitmap |= 1;
+            self.last = id;
+            return true;
+        }
+
+        let offset = self.last - id;
+        if offset >= 64 || self.bitmap & (1 << offset) != 0 {
+            return false;
+        }
+        self.bitmap |= 1 << offset;
+        true
+    }
+}
+
+/// One side of a UDP session.
+///
+/// ```text
+/// packet := AES(session id | packet id) | AEAD(body)
+/// client body := type | timestamp | padding len | padding | address | payload
+/// server body := type | timestamp | client session id | padding len | padding | address | payload
+/// ```
+struct UdpSession {
+    method: Ss2022CipherKind,
+    psk: Bytes,
+    header_cipher: Arc<HeaderCipher>,
+    server: bool,
+    session_id: u64,
+    packet_id: u64,
+    /// Latest session of the peer, replied to by servers.
+    peer_session: Option<u64>,
+    /// Windows of recent peer sessions, so that switching sessions does not reset them.
+    peer_windows: LruCache<u64, PacketWindow>,
+}
+
+impl UdpSession {
+    fn new(
+        method: Ss2022CipherKind,
+        psk: Bytes,
+        header_cipher: Arc<HeaderCipher>,
+        server: bool,
+    ) -> Self {
+        Self {
+            method,
+            psk,
+            header_cipher,
+            server,
+            session_id: thread_rng().gen(),
+            packet_id: 0,
+            peer_session: None,
+            peer_windows: LruCache::new(PEER_WINDOWS),
+        }
+    }
+
+    fn encode(&mut self, packet: &UdpPacket, default_dest: &DestAddr) -> Result<BytesMut> {
+        let mut buf = BytesMut::with_capacity(16 + 1 + 8 + 8 + 2 + 1 + 256 + 2 + packet.len() + 16);
+        buf.put_u64(self.session_id);
+        buf.put_u64(self.packet_id);
+        self.packet_id += 1;
+
+        if self.server {
+            let client_session = self
+                .peer_session
+                .ok_or_else(|| anyhow!("No client session"))?;
+            buf.put_u8(TYPE_SERVER);
+            buf.put_u64(unix_ts().as_secs());
+            buf.put_u64(client_session);
+        } else {
+            buf.put_u8(TYPE_CLIENT);
+            buf.put_u64(unix_ts().as_secs());
+        }
+        buf.put_u16(0);
+        if let Some(target) = packet.target() {
+            encode_socket_addr(&target, &mut buf);
+        } else {
+            encode_addr(default_dest, &mut buf)?;
+        }
+        buf.put_slice(packet);
+
+        let mut crypter = self.method.to_crypter(
+            CrypterMode::Encrypt,
+            &self.psk,
+            &buf[..8],
+            FixedNonce::new(&buf[4..16]),
+        )?;
+        buf.put_bytes(0, crypter.tag_len());
+        crypter.update(&mut buf[16..])?;
+        self.header_cipher.encrypt(&mut buf[..16]);
+        Ok(buf)
+    }
+
+    /// Decodes a packet from the peer into its address and payload.
+    fn decode(&mut self, mut buf: BytesMut) -> Result<(DestAddr, BytesMut)> {
+        if buf.len() < 16 + 16 {
+            bail!("Packet too short");
+        }
+        self.header_cipher.decrypt(&mut buf[..16]);
+        let mut crypter = self.method.to_crypter(
+            CrypterMode::Decrypt,
+            &self.psk,
+            &buf[..8],
+            FixedNonce::new(&buf[4..16]),
+        )?;
+        let mut body = buf.split_off(16);
+        let n = crypter.update(&mut body)?;
+        body.truncate(n);
+
+        let session_id = buf.get_u64();
+        let packet_id = buf.get_u64();
+
+        if body.len() < 1 + 8 + 2 {
+            bail!("Header incomplete");
+        }
+        let expected_type = if self.server {
+            TYPE_CLIENT
+        } else {
+            TYPE_SERVER
+        };
+        if body.get_u8() != expected_type {
+            bail!("Unexpected header type");
+        }
+        check_timestamp(body.get_u64())?;
+        if !self.server {
+            if body.len() < 8 + 2 {
+                bail!("Header incomplete");
+            }
+            if body.get_u64() != self.session_id {
+                bail!("Client session ID mismatch");
+            }
+        }
+        let padding_len = body.get_u16() as usize;
+        if body.len() < padding_len {
+            bail!("Padding incomplete");
+        }
+        body.advance(padding_len);
+        let (addr, len) = decode_addr(&body)?;
+
+        // Only checked after authentication, so that forged packets can't move the window
+        if !self.peer_windows.contains_key(&session_id) {
+            self.peer_windows
+                .insert(session_id, PacketWindow::default());
+        }
+        let window = self.peer_windows.get_mut(&session_id).unwrap();
+        if !window.accept(packet_id) {
+            bail!("Replayed packet {}", packet_id);
+        }
+        self.peer_session = Some(session_id);
+
+        Ok((addr, body.split_off(len)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn salt_pool_rejects_replays() {
+        let pool = SaltPool::default();
+        assert!(pool.insert(b"salt"));
+        assert!(!pool.insert(b"salt"));
+        assert!(pool.insert(b"other"));
+
+        // Expired salts are accepted again, even before a sweep
+        pool.0.lock().unwrap().seen.insert(
+            Bytes::from_static(b"old"),
+            Instant::now() - SALT_TTL - Duration::from_secs(1),
+        );
+        assert!(pool.insert(b"old"));
+    }
+}
@@ -0,0 +1,229 @@
+use super::v5;
+use crate::prelude::*;
+use crate::utils::socks_addr::{decode_addr, encode_addr, encode_socket_addr, read_addr};
+use anyhow::{anyhow, bail};
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::sync::mpsc::channel;
+use tokio_stream::wrappers::ReceiverStream;
+
+pub fn register(plumber: &mut Plumber) {
+    plumber.register("socks5_client", |conf, _| {
+        let config: ClientConfig = from_value(conf)?;
+        Ok(Box::new(ClientProcessor { config }))
+    });
+}
+
+/// Key of the UDP association's controlling connection, carried from `prepare` to `process`.
+const CONTROL_VAR: &str = "socks5-control";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientConfig {
+    server: Option<DestAddr>,
+    username: Option<SmolStr>,
+    #[serde(default)]
+    password: SmolStr,
+}
+
+pub struct ClientProcessor {
+    config: ClientConfig,
+}
+
+impl ClientProcessor {
+    /// Negotiates a method, authenticates and sends a request, returning `BND.ADDR`.
+    async fn handshake(&self, stream: &mut RWPair, cmd: u8, dest: &DestAddr) -> Result<DestAddr> {
+        let method = if self.config.username.is_some() {
+            v5::METH_USER_PASS
+        } else {
+            v5::METH_NO_AUTH
+        };
+        stream.write_all(&[v5::VERSION, 1, method]).await?;
+
+        let mut buffer = [0; 2]; // VER METHOD
+        stream.read_exact(&mut buffer).await?;
+        if buffer[0] != v5::VERSION {
+            bail!("Unsupported version: {}", buffer[0]);
+        }
+        match buffer[1] {
+            v5::METH_NO_AUTH => {}
+            v5::METH_USER_PASS if method == v5::METH_USER_PASS => {
+                let username = self.config.username.as_deref().unwrap_or_default();
+                let password = self.config.password.as_str();
+                if username.len() > 255 || password.len() > 255 {
+                    bail!("Username or password too long");
+                }
+
+                let mut buf = BytesMut::with_capacity(3 + username.len() + password.len());
+                buf.put_u8(v5::AUTH_VERSION);
+                buf.put_u8(username.len() as u8);
+                buf.put_slice(username.as_bytes());
+                buf.put_u8(password.len() as u8);
+                buf.put_slice(password.as_bytes());
+                stream.write_all(&buf).await?;
+
+                stream.read_exact(&mut buffer).await?; // VER STATUS
+                if buffer[1] != 0 {
+                    bail!("Authentication rejected by server");
+                }
+            }
+            v5::METH_NO_ACCEPTABLE => bail!("No acceptable authentication method"),
+            m => bail!("Unexpected method selected by server: {}", m),
+        }
+
+        let mut request = BytesMut::with_capacity(3 + 1 + 256 + 2);
+        request.put_slice(&[v5::VERSION, cmd, 0x00]);
+        encode_addr(dest, &mut request)?;
+        stream.write_all(&request).await?;
+        stream.flush().await?;
+
+        let mut buffer = [0; 3]; // VER REP RSV
+        stream.read_exact(&mut buffer).await?;
+        if buffer[1] != 0 {
+            bail!(
+                "Server replied {:#04x} ({})",
+                buffer[1],
+                reply_message(buffer[1])
+            );
+        }
+
+        let mut bound = DestAddr::default();
+        read_addr(stream, &mut bound).await?;
+        Ok(bound)
+    }
+
+    async fn process_udp(&self, outbound: UdpStream, conn: &mut Connection) -> Result<ProxyStream> {
+        let mut control = conn
+            .take_var::<RWPair>(CONTROL_VAR)
+            .ok_or_else(|| anyhow!("UDP association was not prepared"))?;
+        let mut outbound = outbound;
+        let default_dest = conn.dest_addr.clone();
+
+        let (read_sender, read_receiver) = channel::<UdpPacket>(10);
+        let (write_sender, mut write_receiver) = channel::<UdpPacket>(10);
+
+        tokio::spawn(async move {
+            let mut control_buf = [0u8; 64];
+            loop {
+                tokio::select! {
+                    res = control.read(&mut control_buf) => {
+                        // The association ends with the controlling TCP connection
+                        if !matches!(res, Ok(n) if n > 0) {
+                            break;
+                        }
+                    }
+                    Some(packet) = write_receiver.recv() => {
+                        let mut buf = BytesMut::with_capacity(3 + 1 + 256 + 2 + packet.len());
+                        buf.put_slice(&[0x00, 0x00, 0x00]);
+                        if let Some(target) = packet.target() {
+                            encode_socket_addr(&target, &mut buf);
+                        } else if let Err(e) = encode_addr(&default_dest, &mut buf) {
+                            warn!("Dropping UDP packet: {}", e);
+                            continue;
+                        }
+                        buf.extend_from_slice(&packet);
+
+                        if outbound.send(UdpPacket::new_unknown(buf)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(packet) = outbound.next() => {
+                        match decode_packet(&packet) {
+                            Ok(packet) => {
+                                if read_sender.send(packet).await.is_err() {
+                                    // Dropped
+                                    break;
+                                }
+                            }
+                            Err(e) => warn!("Dropping UDP packet: {}", e),
+                        }
+                    }
+                    _ = read_sender.closed() => break,
+                }
+            }
+            debug!("UDP association closed");
+        });
+
+        Ok(UdpStream::new(ReceiverStream::new(read_receiver), write_sender).into())
+    }
+}
+
+fn decode_packet(buf: &[u8]) -> Result<UdpPacket> {
+    // RSV FRAG ATYP DST.ADDR DST.PORT DATA
+    if buf.len() < 3 {
+        bail!("Header incomplete");
+    }
+    if buf[2] != 0 {
+        bail!("Fragmentation is not supported");
+    }
+
+    let (src, len) = decode_addr(&buf[3..])?;
+    let src = SocketAddr::new(*src.ip_or_error()?, src.port_or_error()?);
+    Ok(UdpPacket::new(src, BytesMut::from(&buf[3 + len..])))
+}
+
+fn reply_message(rep: u8) -> &'static str {
+    match rep {
+        0x01 => "general SOCKS server failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unassigned",
+    }
+}
+
+#[async_trait]
+impl Processor for ClientProcessor {
+    async fn prepare(self: Arc<Self>, conn: &mut Connection, ctx: AppContextRef) -> Result<()> {
+        if let Some(server) = &self.config.server {
+            conn.dest_addr = server.clone();
+        }
+        if conn.typ != TransportType::Udp {
+            return Ok(());
+        }
+
+        // Associate beforehand, so that the outbound is connected to the relay.
+        let ips = ctx.dns.resolve_addr(&conn.dest_addr, &ctx).await?;
+        let server_ip = *ips
+            .first()
+            .ok_or_else(|| anyhow!("No address for {}", conn.dest_addr))?;
+        let server_addr = SocketAddr::new(server_ip, conn.dest_addr.port_or_error()?);
+        let mut control = RWPair::new(crate::net_wrapper::connect_tcp(server_addr).await?);
+
+        let bound = self
+            .handshake(
+                &mut control,
+                v5::CMD_UDP_ASSOCIATE,
+                &DestAddr::new_ip(Ipv4Addr::UNSPECIFIED, 0),
+            )
+            .await?;
+        let relay_ip = match bound.ip {
+            Some(ip) if !ip.is_unspecified() => ip,
+            _ => server_ip,
+        };
+        debug!("UDP relay at {}:{}", relay_ip, bound.port_or_error()?);
+
+        conn.dest_addr = DestAddr::new_ip(relay_ip, bound.port_or_error()?);
+        conn.set_var("addr_type", AddrType::from(relay_ip));
+        conn.set_var(CONTROL_VAR, control);
+        Ok(())
+    }
+
+    async fn process(
+        self: Arc<Self>,
+        stream: ProxyStream,
+        conn: &mut Connection,
+        _ctx: AppContextRef,
+    ) -> Result<ProxyStream> {
+        let mut stream = match stream {
+            ProxyStream::Tcp(s) => s,
+            ProxyStream::Udp(s) => return self.process_udp(s, conn).await,
+        };
+
+        self.handshake(&mut stream, v5::CMD_CONNECT, &conn.dest_addr)
+            .await?;
+        Ok(stream.into())
+    }
+}
@@ -0,0 +1,22 @@
+pub mod client;
+pub mod server;
+pub mod socks4_server;
+
+mod v5 {
+    pub const VERSION: u8 = 5;
+    pub const METH_NO_AUTH: u8 = 0;
+    pub const METH_USER_PASS: u8 = 2;
+    pub const METH_NO_ACCEPTABLE: u8 = 0xff;
+    pub const AUTH_VERSION: u8 = 1;
+    pub const CMD_CONNECT: u8 = 1;
+    pub const CMD_UDP_ASSOCIATE: u8 = 3;
+}
+
+mod v4 {
+    pub const VERSION: u8 = 4;
+    pub const REPLY_VERSION: u8 = 0;
+    pub const CMD_CONNECT: u8 = 1;
+    pub const GRANTED: u8 = 0x5a;
+    pub const REJECTED: u8 = 0x5b;
+    pub const IDENT_MISMATCH: u8 = 0x5d;
+}
@@ -0,0 +1,270 @@
+use super::v5;
+use crate::prelude::*;
+use crate::utils::socks_addr::{self, decode_addr, encode_socket_addr, read_addr};
+use anyhow::bail;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::channel;
+use tokio_stream::wrappers::ReceiverStream;
+
+pub fn register(plumber: &mut Plumber) {
+    plumber.register("socks5_server", |conf, _| {
+        let config: ServerConfig = from_value(conf)?;
+        Ok(Box::new(Socks5ProxyServerProcessor::new(config)))
+    });
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ServerConfig {
+    /// Address to bind UDP relay sockets on, which is also advertised to clients.
+    /// Defaults to the unspecified address of the client's family, advertising the local
+    /// address of the TCP connection instead.
+    udp_addr: Option<IpAddr>,
+    /// Username/password authentication (RFC 1929) is required if non-empty.
+    #[serde(default)]
+    users: Vec<UserConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct UserConfig {
+    username: SmolStr,
+    password: SmolStr,
+}
+
+pub struct Socks5ProxyServerProcessor {
+    config: ServerConfig,
+    users: HashMap<SmolStr, SmolStr>,
+}
+
+impl Socks5ProxyServerProcessor {
+    pub fn new(config: ServerConfig) -> Self {
+        let users = config
+            .users
+            .iter()
+            .map(|u| (u.username.clone(), u.password.clone()))
+            .collect();
+        Self { config, users }
+    }
+
+    async fn authenticate(&self, stream: &mut RWPair, conn: &mut Connection) -> Result<()> {
+        // VER ULEN UNAME PLEN PASSWD
+        let version = stream.read_u8().await?;
+        if version != v5::AUTH_VERSION {
+            bail!("Unsupported auth version: {}", version);
+        }
+
+        let mut username = vec![0; stream.read_u8().await? as usize];
+        stream.read_exact(&mut username).await?;
+        let mut password = vec![0; stream.read_u8().await? as usize];
+        stream.read_exact(&mut password).await?;
+
+        let username = String::from_utf8_lossy(&username);
+        let ok = matches!(self.users.get(username.as_ref()), Some(p) if p.as_bytes() == password.as_slice());
+
+        stream
+            .write_all(&[v5::AUTH_VERSION, if ok { 0x00 } else { 0x01 }])
+            .await?;
+        if !ok {
+            bail!("Authentication failed for user {}", username);
+        }
+
+        conn.set_var(vars::USER, SmolStr::from(username));
+        Ok(())
+    }
+
+    async fn udp_associate(
+        &self,
+        mut stream: RWPair,
+        conn: &mut Connection,
+        ctx: AppContextRef,
+    ) -> Result<ProxyStream> {
+        let bind_ip = self
+            .config
+            .udp_addr
+            .unwrap_or_else(|| socks_addr::unspecified_like(conn.src_addr.ip()));
+        let socket = UdpSocket::bind((bind_ip, 0)).await?;
+
+        // Clients can't send to an unspecified address, so the one they reached us on is given
+        let mut bound = socket.local_addr()?;
+        if bound.ip().is_unspecified() {
+            if let Some(local_addr) = conn.get_var::<SocketAddr>(vars::LOCAL_ADDR) {
+                bound.set_ip(local_addr.ip());
+            }
+        }
+        let mut reply = BytesMut::with_capacity(3 + 1 + 16 + 2);
+        reply.put_slice(&[v5::VERSION, 0x00, 0x00]);
+        encode_socket_addr(&bound, &mut reply);
+        stream.write_all(&reply).await?;
+        stream.flush().await?;
+
+        // Destinations are carried by each packet
+        conn.typ = TransportType::Udp;
+        conn.dest_addr = DestAddr::default();
+
+        let (read_sender, read_receiver) = channel::<UdpPacket>(10);
+        let (write_sender, mut write_receiver) = channel::<UdpPacket>(10);
+
+        let client_ip = conn.src_addr.ip();
+        tokio::spawn(async move {
+            let mut client_addr: Option<SocketAddr> = None;
+            let mut control_buf = [0u8; 64];
+
+            loop {
+                let mut buffer = [0u8; 4096];
+                tokio::select! {
+                    res = stream.read(&mut control_buf) => {
+                        // The association ends with the controlling TCP connection
+                        if !matches!(res, Ok(n) if n > 0) {
+                            break;
+                        }
+                    }
+                    res = socket.recv_from(&mut buffer) => {
+                        let (n, src_addr) = match res {
+                            Ok(r) => r,
+                            Err(e) => {
+                                error!("Failed to receive UDP packet: {}", e);
+                                break;
+                            }
+                        };
+                        if src_addr.ip() != client_ip {
+                            continue;
+                        }
+                        client_addr = Some(src_addr);
+
+                        match decode_packet(&buffer[0..n], &ctx).await {
+                            Ok(Some(packet)) => {
+                                if read_sender.send(packet).await.is_err() {
+                                    // Dropped
+                                    break;
+                                }
+                            }
+                            Ok(None) => {}
+                            Err(e) => warn!("Dropping UDP packet from {}: {}", src_addr, e),
+                        }
+                    }
+                    Some(packet) = write_receiver.recv() => {
+                        let (client_addr, target) = match (client_addr, packet.target()) {
+                            (Some(c), Some(t)) => (c, t),
+                            _ => continue,
+                        };
+
+                        let mut buf = BytesMut::with_capacity(3 + 1 + 16 + 2 + packet.len());
+                        buf.put_slice(&[0x00, 0x00, 0x00]);
+                        encode_socket_addr(&target, &mut buf);
+                        buf.extend_from_slice(&packet);
+
+                        if let Err(e) = socket.send_to(&buf, client_addr).await {
+                            error!("Failed to send UDP packet: {}", e);
+                            break;
+                        }
+                    }
+                    _ = read_sender.closed() => break,
+                }
+            }
+            debug!("UDP association closed");
+        });
+
+        Ok(UdpStream::new(ReceiverStream::new(read_receiver), write_sender).into())
+    }
+}
+
+/// Parses a SOCKS5 UDP request header, dropping fragmented packets.
+///
+/// ```text
+/// +----+------+------+----------+----------+----------+
+/// |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
+/// +----+------+------+----------+----------+----------+
+/// | 2  |  1   |  1   | Variable |    2     | Variable |
+/// +----+------+------+----------+----------+----------+
+/// ```
+async fn decode_packet(buf: &[u8], ctx: &AppContextRef) -> Result<Option<UdpPacket>> {
+    if buf.len() < 3 {
+        bail!("Header incomplete");
+    }
+    if buf[2] != 0 {
+        // Fragmentation is not supported
+        return Ok(None);
+    }
+
+    let (dest, len) = decode_addr(&buf[3..])?;
+    let ip = match dest.ip {
+        Some(ip) => ip,
+        None => {
+            let ips = ctx.dns.resolve(dest.domain_or_error()?, ctx).await?;
+            match ips.first() {
+                Some(ip) => *ip,
+                None => bail!("No address for {}", dest),
+            }
+        }
+    };
+    let target = SocketAddr::new(ip, dest.port_or_error()?);
+
+    Ok(Some(UdpPacket::new(
+        target,
+        BytesMut::from(&buf[3 + len..]),
+    )))
+}
+
+#[async_trait]
+impl Processor for Socks5ProxyServerProcessor {
+    async fn process(
+        self: Arc<Self>,
+        stream: ProxyStream,
+        conn: &mut Connection,
+        ctx: AppContextRef,
+    ) -> Result<ProxyStream> {
+        let mut stream = stream.into_tcp()?;
+        // Read version
+        let version = stream.read_u8().await?;
+        if version != v5::VERSION {
+            bail!("Unsupported version: {}", version);
+        }
+
+        // Read methods
+        let nmethods = stream.read_u8().await?;
+        let mut methods = vec![0; nmethods as usize];
+        stream.read_exact(&mut methods).await?;
+
+        // METHOD selection message
+        if self.users.is_empty() {
+            stream.write_all(&[v5::VERSION, v5::METH_NO_AUTH]).await?;
+        } else if methods.contains(&v5::METH_USER_PASS) {
+            stream.write_all(&[v5::VERSION, v5::METH_USER_PASS]).await?;
+            self.authenticate(&mut stream, conn).await?;
+        } else {
+            stream
+                .write_all(&[v5::VERSION, v5::METH_NO_ACCEPTABLE])
+                .await?;
+            bail!("Client does not support username/password authentication");
+        }
+
+        // Read request
+        let cmd = {
+            let mut buffer = [0; 3]; // VER CMD RSV
+            stream.read_exact(&mut buffer).await?;
+            buffer[1]
+        };
+        read_addr(&mut stream, &mut conn.dest_addr).await?;
+
+        match cmd {
+            v5::CMD_CONNECT => {}
+            v5::CMD_UDP_ASSOCIATE => return self.udp_associate(stream, conn, ctx).await,
+            _ => {
+                // Command not supported
+                stream
+                    .write_all(&[0x05, 0x07, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
+                    .await?;
+                bail!("Unsupported command: {}", cmd);
+            }
+        }
+
+        // Send reply
+        stream
+            .write_all(&[0x05, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x04])
+            .await?;
+        stream.flush().await?;
+
+        // And we are done
+        Ok(stream.into())
+    }
+}
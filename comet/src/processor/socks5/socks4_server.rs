@@ -0,0 +1,110 @@
+use super::v4;
+use crate::prelude::*;
+use anyhow::bail;
+use std::net::Ipv4Addr;
+
+pub fn register(plumber: &mut Plumber) {
+    plumber.register("socks4_server", |conf, _| {
+        let config: ServerConfig = from_value(conf)?;
+        Ok(Box::new(Socks4ProxyServerProcessor::new(config)))
+    });
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ServerConfig {
+    /// Only these user IDs are accepted if non-empty.
+    #[serde(default)]
+    pub user_ids: Vec<SmolStr>,
+}
+
+pub struct Socks4ProxyServerProcessor {
+    config: ServerConfig,
+}
+
+impl Socks4ProxyServerProcessor {
+    pub fn new(config: ServerConfig) -> Self {
+        Self { config }
+    }
+}
+
+/// Reads a NUL terminated string of at most 255 bytes.
+async fn read_string(stream: &mut RWPair) -> Result<String> {
+    let mut buf = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            0 => break,
+            b if buf.len() < 255 => buf.push(b),
+            _ => bail!("String too long"),
+        }
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// Rejects a SOCKS4 client without reading its request.
+pub async fn reject(mut stream: RWPair) -> Result<()> {
+    reply(&mut stream, v4::REJECTED).await
+}
+
+async fn reply(stream: &mut RWPair, status: u8) -> Result<()> {
+    // Port and address are ignored for CONNECT
+    stream
+        .write_all(&[v4::REPLY_VERSION, status, 0, 0, 0, 0, 0, 0])
+        .await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+#[async_trait]
+impl Processor for Socks4ProxyServerProcessor {
+    /// ```text
+    /// +----+----+---------+--------+--------+------+----------------+------+
+    /// | VN | CD | DSTPORT | DSTIP  | USERID | NULL | 4a: DOMAIN     | NULL |
+    /// +----+----+---------+--------+--------+------+----------------+------+
+    /// | 1  | 1  |    2    |   4    |   N    |  1   |       N        |  1   |
+    /// +----+----+---------+--------+--------+------+----------------+------+
+    /// ```
+    ///
+    /// SOCKS4a clients send `0.0.0.x` (x != 0) as DSTIP and append the domain.
+    async fn process(
+        self: Arc<Self>,
+        stream: ProxyStream,
+        conn: &mut Connection,
+        _ctx: AppContextRef,
+    ) -> Result<ProxyStream> {
+        let mut stream = stream.into_tcp()?;
+
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header).await?;
+        if header[0] != v4::VERSION {
+            bail!("Unsupported version: {}", header[0]);
+        }
+        let cmd = header[1];
+        let port = u16::from_be_bytes([header[2], header[3]]);
+        let ip = Ipv4Addr::new(header[4], header[5], header[6], header[7]);
+        let user_id = read_string(&mut stream).await?;
+
+        let mut dest_addr = DestAddr::default();
+        dest_addr.set_port(port);
+        if matches!(ip.octets(), [0, 0, 0, x] if x != 0) {
+            dest_addr.set_host_from_str(&read_string(&mut stream).await?);
+        } else {
+            dest_addr.set_ip(ip);
+        }
+
+        if cmd != v4::CMD_CONNECT {
+            reply(&mut stream, v4::REJECTED).await?;
+            bail!("Unsupported command: {}", cmd);
+        }
+        if !self.config.user_ids.is_empty() {
+            if !self.config.user_ids.iter().any(|u| u == &user_id) {
+                reply(&mut stream, v4::IDENT_MISMATCH).await?;
+                bail!("Rejected user ID {}", user_id);
+            }
+            conn.set_var(vars::USER, SmolStr::from(user_id));
+        }
+
+        conn.dest_addr = dest_addr;
+        reply(&mut stream, v4::GRANTED).await?;
+        Ok(stream.into())
+    }
+}
@@ -1,8 +1,21 @@
-use std::convert::TryFrom;
+use std::{
+    convert::TryFrom,
+    io::BufReader,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 
+use anyhow::{anyhow, bail};
+use ring::digest::{digest, SHA256};
+use rustls_pemfile::Item;
+use tokio::sync::OnceCell;
 use tokio_rustls::{
-    rustls::{self, ServerName},
-    webpki::{DnsName, DnsNameRef},
+    rustls::{
+        self,
+        client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+        Certificate, PrivateKey, ServerName,
+    },
     TlsConnector,
 };
 
@@ -17,17 +30,119 @@ pub fn register(plumber: &mut Plumber) {
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ClientConfig {
-    sni: SmolStr,
+    /// Defaults to the destination domain.
+    #[serde(default)]
+    sni: Option<SmolStr>,
+    /// Extra PEM CA bundle trusted besides the webpki roots, relative to `data_dir`.
+    #[serde(default)]
+    ca: Option<PathBuf>,
+    /// Skips certificate verification. Pins are still checked if given.
+    #[serde(default)]
+    insecure: bool,
+    /// PEM client certificate chain for mutual TLS, relative to `data_dir`.
+    #[serde(default)]
+    client_cert: Option<PathBuf>,
+    #[serde(default)]
+    client_key: Option<PathBuf>,
+    #[serde(default = "default_alpn")]
+    alpn: Vec<SmolStr>,
+    /// Base64 SHA-256 digests of the server certificate (DER).
+    #[serde(default)]
+    pin_cert_sha256: Vec<SmolStr>,
+    /// Base64 SHA-256 digests of the server public key (SPKI DER).
+    #[serde(default)]
+    pin_spki_sha256: Vec<SmolStr>,
+}
+
+fn default_alpn() -> Vec<SmolStr> {
+    vec!["h2".into(), "http/1.1".into()]
+}
+
+impl ClientConfig {
+    /// Config of a client bundled in another protocol, like `trojan://` URLs.
+    pub fn new(sni: Option<SmolStr>, insecure: bool) -> Self {
+        Self {
+            sni,
+            ca: None,
+            insecure,
+            client_cert: None,
+            client_key: None,
+            alpn: default_alpn(),
+            pin_cert_sha256: vec![],
+            pin_spki_sha256: vec![],
+        }
+    }
 }
+
 pub struct ClientProcessor {
-    connector: TlsConnector,
-    sni: DnsName,
-    server_name: ServerName,
+    sni: Option<ServerName>,
     config: ClientConfig,
+    cert_pins: Vec<Vec<u8>>,
+    spki_pins: Vec<Vec<u8>>,
+    /// A load failure is kept so files are not read again for every connection.
+    connector: OnceCell<std::result::Result<TlsConnector, String>>,
 }
 
 impl ClientProcessor {
-    fn new(config: ClientConfig) -> Result<Self> {
+    pub fn new(config: ClientConfig) -> Result<Self> {
+        let sni = config
+            .sni
+            .as_ref()
+            .map(|sni| ServerName::try_from(sni.as_str()))
+            .transpose()?;
+        if config.client_cert.is_some() != config.client_key.is_some() {
+            bail!("client_cert and client_key must be set together");
+        }
+        let processor = Self {
+            sni,
+            cert_pins: decode_pins(&config.pin_cert_sha256)?,
+            spki_pins: decode_pins(&config.pin_spki_sha256)?,
+            config,
+            connector: OnceCell::new(),
+        };
+        if let Some(ServerName::IpAddress(ip)) = &processor.sni {
+            processor.check_ip_name(ip)?;
+        }
+        Ok(processor)
+    }
+
+    /// The webpki verifier only accepts DNS names, so IPs need pins or `insecure`.
+    fn check_ip_name(&self, ip: &IpAddr) -> Result<()> {
+        if !self.config.insecure && !self.has_pins() {
+            bail!(
+                "TLS to IP {} requires a domain `sni`, pins or `insecure`",
+                ip
+            );
+        }
+        Ok(())
+    }
+
+    fn has_pins(&self) -> bool {
+        !self.cert_pins.is_empty() || !self.spki_pins.is_empty()
+    }
+
+    /// Loads certificates from `data_dir` on first use, off the async workers.
+    async fn connector(self: &Arc<Self>, ctx: &AppContextRef) -> Result<&TlsConnector> {
+        let connector = self
+            .connector
+            .get_or_init(|| async {
+                let this = self.clone();
+                let ctx = ctx.clone();
+                let loaded = tokio::task::spawn_blocking(move || this.load_connector(&ctx))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|res| res);
+                loaded.map_err(|e| {
+                    let message = format!("Failed to load TLS certificates: {}", e);
+                    error!("{}", message);
+                    message
+                })
+            })
+            .await;
+        connector.as_ref().map_err(|e| anyhow!("{}", e))
+    }
+
+    fn load_connector(&self, ctx: &AppContextRef) -> Result<TlsConnector> {
         let mut root_store = rustls::RootCertStore::empty();
         root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
             rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
@@ -36,22 +151,149 @@ impl ClientProcessor {
                 ta.name_constraints,
             )
         }));
-        let mut rustls_config = rustls::ClientConfig::builder()
+        if let Some(ca) = &self.config.ca {
+            for cert in read_certs(&ctx.data_dir.join(ca))? {
+                root_store.add(&cert)?;
+            }
+        }
+
+        let verifier = Arc::new(PinningVerifier {
+            inner: WebPkiVerifier::new(root_store, None),
+            insecure: self.config.insecure,
+            pins_only_for_ips: self.has_pins(),
+            cert_pins: self.cert_pins.clone(),
+            spki_pins: self.spki_pins.clone(),
+        });
+        let builder = rustls::ClientConfig::builder()
             .with_safe_defaults()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
-        rustls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+            .with_custom_certificate_verifier(verifier);
+        let mut rustls_config = match (&self.config.client_cert, &self.config.client_key) {
+            (Some(cert), Some(key)) => builder.with_single_cert(
+                read_certs(&ctx.data_dir.join(cert))?,
+                read_private_key(&ctx.data_dir.join(key))?,
+            )?,
+            _ => builder.with_no_client_auth(),
+        };
+        rustls_config.alpn_protocols = self
+            .config
+            .alpn
+            .iter()
+            .map(|p| p.as_bytes().to_vec())
+            .collect();
 
-        let sni = DnsNameRef::try_from_ascii_str(&config.sni)?;
-        let server_name = ServerName::try_from(config.sni.as_str())?;
+        Ok(Arc::new(rustls_config).into())
+    }
 
-        Ok(Self {
-            connector: Arc::new(rustls_config).into(),
-            sni: sni.to_owned(),
-            server_name,
-            config,
+    fn server_name(&self, dest: &DestAddr) -> Result<ServerName> {
+        if let Some(sni) = &self.sni {
+            return Ok(sni.clone());
+        }
+        match (&dest.domain, dest.ip) {
+            (Some(domain), _) => Ok(ServerName::try_from(domain.as_str())?),
+            (None, Some(ip)) => {
+                self.check_ip_name(&ip)?;
+                Ok(ServerName::IpAddress(ip))
+            }
+            (None, None) => bail!("TLS requires an SNI or destination"),
+        }
+    }
+}
+
+fn decode_pins(pins: &[SmolStr]) -> Result<Vec<Vec<u8>>> {
+    pins.iter()
+        .map(|pin| {
+            let pin = base64::decode(pin.as_str())?;
+            if pin.len() != SHA256.output_len {
+                bail!("Invalid SHA-256 pin length: {}", pin.len());
+            }
+            Ok(pin)
         })
+        .collect()
+}
+
+/// Checks pins on top of (or, when insecure, instead of) the webpki verification.
+struct PinningVerifier {
+    inner: WebPkiVerifier,
+    insecure: bool,
+    /// Pins alone verify IP server names, which webpki rejects.
+    pins_only_for_ips: bool,
+    cert_pins: Vec<Vec<u8>>,
+    spki_pins: Vec<Vec<u8>>,
+}
+
+impl PinningVerifier {
+    fn check_pins(&self, end_entity: &Certificate) -> Result<(), rustls::Error> {
+        if self.cert_pins.is_empty() && self.spki_pins.is_empty() {
+            return Ok(());
+        }
+        let cert_digest = digest(&SHA256, &end_entity.0);
+        if self.cert_pins.iter().any(|p| p == cert_digest.as_ref()) {
+            return Ok(());
+        }
+        if !self.spki_pins.is_empty() {
+            let (_, cert) = x509_parser::parse_x509_certificate(&end_entity.0)
+                .map_err(|e| rustls::Error::General(e.to_string()))?;
+            let spki_digest = digest(&SHA256, cert.public_key().raw);
+            if self.spki_pins.iter().any(|p| p == spki_digest.as_ref()) {
+                return Ok(());
+            }
+        }
+        Err(rustls::Error::General(
+            "Server certificate does not match any pin".into(),
+        ))
+    }
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let ip_with_pins =
+            self.pins_only_for_ips && matches!(server_name, ServerName::IpAddress(_));
+        if !self.insecure && !ip_with_pins {
+            self.inner.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )?;
+        }
+        self.check_pins(end_entity)?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Reads a PEM certificate chain.
+pub(crate) fn read_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    let certs: Vec<_> = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        bail!("No certificate in {:?}", path);
     }
+    Ok(certs)
+}
+
+/// Reads the first RSA, PKCS#8 or EC private key from a PEM file.
+pub(crate) fn read_private_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    rustls_pemfile::read_all(&mut reader)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("No private key in {:?}", path))
 }
 
 #[async_trait]
@@ -63,11 +305,40 @@ impl Processor for ClientProcessor {
     async fn process(
         self: Arc<Self>,
         stream: ProxyStream,
-        _conn: &mut Connection,
-        _ctx: AppContextRef,
+        conn: &mut Connection,
+        ctx: AppContextRef,
     ) -> Result<ProxyStream> {
         let stream = stream.into_tcp()?;
-        let tls_stream = self.connector.connect(self.server_name.clone(), stream).await?;
+        let server_name = self.server_name(&conn.dest_addr)?;
+        let tls_stream = self
+            .connector(&ctx)
+            .await?
+            .connect(server_name, stream)
+            .await?;
         Ok(RWPair::new(tls_stream).into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn processor(config: &str) -> Result<ClientProcessor> {
+        ClientProcessor::new(serde_yaml::from_str(config).unwrap())
+    }
+
+    #[test]
+    fn ip_server_names_need_pins_or_insecure() {
+        assert!(processor("sni: 1.2.3.4").is_err());
+        assert!(processor("{sni: 1.2.3.4, insecure: true}").is_ok());
+        let pinned =
+            "{sni: 1.2.3.4, pin_cert_sha256: [AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=]}";
+        assert!(processor(pinned).is_ok());
+
+        let ip = DestAddr::new_ip(IpAddr::from([1, 2, 3, 4]), 443);
+        let domain = DestAddr::new_domain("example.com", 443);
+        let processor = processor("{}").unwrap();
+        assert!(processor.server_name(&ip).is_err());
+        assert!(processor.server_name(&domain).is_ok());
+    }
+}
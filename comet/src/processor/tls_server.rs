@@ -0,0 +1,235 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use super::tls::{read_certs, read_private_key};
+use crate::prelude::*;
+use anyhow::bail;
+use tokio::sync::OnceCell;
+use tokio_rustls::{
+    rustls::{
+        server::{ClientHello, ResolvesServerCert},
+        sign::{any_supported_type, CertifiedKey},
+        ServerConfig as RustlsServerConfig,
+    },
+    TlsAcceptor,
+};
+
+pub fn register(plumber: &mut Plumber) {
+    plumber.register("tls_server", |conf, _| {
+        let config: ServerConfig = from_value(conf)?;
+        Ok(Box::new(ServerProcessor::new(config)?))
+    });
+}
+
+/// How often certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Deserialize)]
+struct ServerConfig {
+    certificates: Vec<CertConfig>,
+    #[serde(default)]
+    alpn: Vec<SmolStr>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct CertConfig {
+    /// PEM certificate chain, relative to `data_dir`.
+    cert: PathBuf,
+    /// PEM private key, relative to `data_dir`.
+    key: PathBuf,
+    /// Server names this certificate is picked for, `*.` matching one label.
+    #[serde(default)]
+    server_names: Vec<SmolStr>,
+}
+
+struct LoadedCert {
+    key: Arc<CertifiedKey>,
+    modified: (SystemTime, SystemTime),
+}
+
+struct CertEntry {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    server_names: Vec<SmolStr>,
+    loaded: RwLock<LoadedCert>,
+}
+
+impl CertEntry {
+    fn load(config: &CertConfig, data_dir: &Path) -> Result<Self> {
+        let cert_path = data_dir.join(&config.cert);
+        let key_path = data_dir.join(&config.key);
+        let loaded = load_cert(&cert_path, &key_path)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            server_names: config
+                .server_names
+                .iter()
+                .map(|n| n.to_ascii_lowercase().into())
+                .collect(),
+            loaded: RwLock::new(loaded),
+        })
+    }
+
+    fn matches(&self, server_name: &str) -> bool {
+        self.server_names.iter().any(|name| {
+            if let Some(suffix) = name.strip_prefix("*.") {
+                matches!(server_name.split_once('.'), Some((_, rest)) if rest == suffix)
+            } else {
+                name == server_name
+            }
+        })
+    }
+
+    async fn reload_if_changed(&self) -> Result<()> {
+        let modified = (
+            tokio::fs::metadata(&self.cert_path).await?.modified()?,
+            tokio::fs::metadata(&self.key_path).await?.modified()?,
+        );
+        if self.loaded.read().unwrap().modified == modified {
+            return Ok(());
+        }
+
+        let (cert_path, key_path) = (self.cert_path.clone(), self.key_path.clone());
+        let loaded =
+            tokio::task::spawn_blocking(move || load_cert(&cert_path, &key_path)).await??;
+        info!("Reloaded certificate {:?}", self.cert_path);
+        *self.loaded.write().unwrap() = loaded;
+        Ok(())
+    }
+}
+
+fn load_cert(cert_path: &Path, key_path: &Path) -> Result<LoadedCert> {
+    let modified = (
+        std::fs::metadata(cert_path)?.modified()?,
+        std::fs::metadata(key_path)?.modified()?,
+    );
+    let certs = read_certs(cert_path)?;
+    let key = any_supported_type(&read_private_key(key_path)?)?;
+
+    Ok(LoadedCert {
+        key: Arc::new(CertifiedKey::new(certs, key)),
+        modified,
+    })
+}
+
+/// Picks a certificate by SNI, falling back to the first one.
+struct CertResolver {
+    entries: Vec<CertEntry>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let entry = client_hello
+            .server_name()
+            .map(|name| name.to_ascii_lowercase())
+            .and_then(|name| self.entries.iter().find(|e| e.matches(&name)))
+            .or_else(|| self.entries.first())?;
+        let key = entry.loaded.read().unwrap().key.clone();
+        Some(key)
+    }
+}
+
+struct Acceptor {
+    acceptor: TlsAcceptor,
+    resolver: Arc<CertResolver>,
+    last_check: Mutex<Instant>,
+}
+
+pub struct ServerProcessor {
+    config: ServerConfig,
+    acceptor: OnceCell<Acceptor>,
+}
+
+impl ServerProcessor {
+    fn new(config: ServerConfig) -> Result<Self> {
+        if config.certificates.is_empty() {
+            bail!("At least one certificate is required");
+        }
+        Ok(Self {
+            config,
+            acceptor: OnceCell::new(),
+        })
+    }
+
+    /// Loads certificates from `data_dir` on first use.
+    async fn acceptor(&self, ctx: &AppContextRef) -> Result<&Acceptor> {
+        self.acceptor
+            .get_or_try_init(|| async {
+                let certificates = self.config.certificates.clone();
+                let data_dir = ctx.data_dir.clone();
+                let entries = tokio::task::spawn_blocking(move || {
+                    certificates
+                        .iter()
+                        .map(|c| CertEntry::load(c, &data_dir))
+                        .collect::<Result<_>>()
+                })
+                .await??;
+                let resolver = Arc::new(CertResolver { entries });
+
+                let mut cfg = RustlsServerConfig::builder()
+                    .with_safe_defaults()
+                    .with_no_client_auth()
+                    .with_cert_resolver(resolver.clone());
+                cfg.alpn_protocols = self
+                    .config
+                    .alpn
+                    .iter()
+                    .map(|p| p.as_bytes().to_vec())
+                    .collect();
+
+                Ok(Acceptor {
+                    acceptor: TlsAcceptor::from(Arc::new(cfg)),
+                    resolver,
+                    last_check: Mutex::new(Instant::now()),
+                })
+            })
+            .await
+    }
+}
+
+impl Acceptor {
+    async fn reload_if_due(&self) {
+        {
+            let mut last_check = self.last_check.lock().unwrap();
+            if last_check.elapsed() < RELOAD_INTERVAL {
+                return;
+            }
+            *last_check = Instant::now();
+        }
+        for entry in &self.resolver.entries {
+            // Keep serving the old certificate until the new one is complete
+            if let Err(e) = entry.reload_if_changed().await {
+                warn!("Failed to reload certificate {:?}: {}", entry.cert_path, e);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Processor for ServerProcessor {
+    async fn process(
+        self: Arc<Self>,
+        stream: ProxyStream,
+        conn: &mut Connection,
+        ctx: AppContextRef,
+    ) -> Result<ProxyStream> {
+        let stream = stream.into_tcp()?;
+        let acceptor = self.acceptor(&ctx).await?;
+        acceptor.reload_if_due().await;
+
+        let stream = acceptor.acceptor.accept(stream).await?;
+        let (_, session) = stream.get_ref();
+        if let Some(sni) = session.sni_hostname() {
+            conn.set_var(vars::TLS_SNI, SmolStr::from(sni));
+        }
+        if let Some(alpn) = session.alpn_protocol() {
+            conn.set_var(vars::TLS_ALPN, SmolStr::from(String::from_utf8_lossy(alpn)));
+        }
+
+        Ok(RWPair::new(stream).into())
+    }
+}
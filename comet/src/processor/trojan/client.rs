@@ -1,8 +1,15 @@
 use crate::{
     crypto::hashing::{hash_bytes, HashKind},
     prelude::*,
-    utils::prepend_io::PrependWriter,
+    utils::{
+        prepend_io::PrependWriter,
+        socks_addr::{encode_addr, encode_socket_addr, read_addr},
+    },
 };
+use anyhow::anyhow;
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::sync::mpsc::channel;
+use tokio_stream::wrappers::ReceiverStream;
 
 pub fn register(plumber: &mut Plumber) {
     plumber.register("trojan_client", |conf, _| {
@@ -11,17 +18,20 @@ pub fn register(plumber: &mut Plumber) {
     });
 }
 
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
 #[derive(Debug, Clone, Deserialize)]
-struct ClientConfig {
-    password: String,
+pub(super) struct ClientConfig {
+    pub password: String,
 }
 
-struct ClientProcessor {
+pub(super) struct ClientProcessor {
     password: String,
 }
 
 impl ClientProcessor {
-    fn new(config: ClientConfig) -> Self {
+    pub fn new(config: ClientConfig) -> Self {
         let hashed_password = hash_bytes(HashKind::Sha224, config.password.as_bytes());
         Self {
             password: hex::encode(hashed_password),
@@ -38,8 +48,7 @@ impl Processor for ClientProcessor {
         _ctx: AppContextRef,
     ) -> Result<ProxyStream> {
         let stream = stream.into_tcp()?;
-        let dest_addr = &conn.dest_addr;
-        let mut buf = BytesMut::with_capacity(56 + 2 + 4 + 2);
+        let mut buf = BytesMut::with_capacity(56 + 2 + 1 + 1 + 256 + 2 + 2);
         /*
             +-----------------------+---------+----------------+---------+----------+
             | hex(SHA224(password)) |  CRLF   | Trojan Request |  CRLF   | Payload  |
@@ -68,29 +77,106 @@ impl Processor for ClientProcessor {
         */
 
         buf.extend_from_slice(self.password.as_bytes());
-        buf.put_slice(&[0x0D, 0x0A, 0x01]);
-
-        if let Some(domain) = &dest_addr.domain {
-            buf.put_u8(3);
-            buf.put_u8(domain.len() as u8);
-            buf.put_slice(domain.as_str().as_ref());
-        } else {
-            use std::net::IpAddr::*;
-            let ip = dest_addr.ip_or_error()?;
-            match ip {
-                V4(ip) => {
-                    buf.put_u8(1);
-                    buf.put_slice(&ip.octets());
+        buf.put_slice(&[0x0D, 0x0A]);
+
+        if conn.typ == TransportType::Udp {
+            // Packets carry their own destinations
+            let dest_addr = if conn.dest_addr.is_valid() {
+                conn.dest_addr.clone()
+            } else {
+                DestAddr::new_ip(Ipv4Addr::UNSPECIFIED, 0)
+            };
+            buf.put_u8(CMD_UDP_ASSOCIATE);
+            encode_addr(&dest_addr, &mut buf)?;
+            buf.put_slice(&[0x0D, 0x0A]);
+
+            let stream = PrependWriter::new(stream, buf);
+            return Ok(process_udp(stream, dest_addr).into());
+        }
+
+        buf.put_u8(CMD_CONNECT);
+        encode_addr(&conn.dest_addr, &mut buf)?;
+        buf.put_slice(&[0x0D, 0x0A]);
+
+        Ok(RWPair::new(PrependWriter::new(stream, buf)).into())
+    }
+}
+
+/// Frames packets over the stream:
+///
+/// ```text
+/// +------+----------+----------+--------+---------+----------+
+/// | ATYP | DST.ADDR | DST.PORT | Length |  CRLF   | Payload  |
+/// +------+----------+----------+--------+---------+----------+
+/// |  1   | Variable |    2     |   2    | X'0D0A' | Variable |
+/// +------+----------+----------+--------+---------+----------+
+/// ```
+fn process_udp<RW: AsyncRead + AsyncWrite + Send + 'static>(
+    stream: RW,
+    default_dest: DestAddr,
+) -> UdpStream {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (read_sender, read_receiver) = channel::<UdpPacket>(10);
+    let (write_sender, mut write_receiver) = channel::<UdpPacket>(10);
+
+    tokio::spawn(async move {
+        while let Some(packet) = write_receiver.recv().await {
+            let mut buf = BytesMut::with_capacity(1 + 256 + 2 + 2 + 2 + packet.len());
+            if let Some(target) = packet.target() {
+                encode_socket_addr(&target, &mut buf);
+            } else if let Err(e) = encode_addr(&default_dest, &mut buf) {
+                warn!("Dropping UDP packet: {}", e);
+                continue;
+            }
+            buf.put_u16(packet.len() as u16);
+            buf.put_slice(&[0x0D, 0x0A]);
+            buf.put_slice(&packet);
+
+            if writer.write_all(&buf).await.is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+
+    tokio::spawn(async move {
+        loop {
+            let packet = tokio::select! {
+                res = read_packet(&mut reader) => res,
+                _ = read_sender.closed() => break,
+            };
+            match packet {
+                Ok(Some(packet)) => {
+                    if read_sender.send(packet).await.is_err() {
+                        // Dropped
+                        break;
+                    }
                 }
-                V6(ip) => {
-                    buf.put_u8(4);
-                    buf.put_slice(&ip.octets());
+                Ok(None) => {}
+                Err(e) => {
+                    debug!("Trojan UDP stream closed: {}", e);
+                    break;
                 }
             }
         }
-        buf.put_u16(dest_addr.port_or_error()?);
-        buf.put_slice(&[0x0D, 0x0A]);
+    });
 
-        Ok(RWPair::new(PrependWriter::new(stream, buf)).into())
+    UdpStream::new(ReceiverStream::new(read_receiver), write_sender)
+}
+
+/// Reads a framed packet, returning `None` for packets from unresolved sources.
+async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<UdpPacket>> {
+    let mut src = DestAddr::default();
+    read_addr(reader, &mut src).await?;
+    let len = reader.read_u16().await? as usize;
+    let mut buf = BytesMut::zeroed(2 + len);
+    reader.read_exact(&mut buf).await?;
+    if &buf[..2] != b"\r\n" {
+        return Err(anyhow!("Missing CRLF"));
     }
+    buf.advance(2);
+
+    Ok(src
+        .ip
+        .map(|ip| UdpPacket::new(SocketAddr::new(ip, src.port.unwrap_or(0)), buf)))
 }
@@ -1,16 +1,38 @@
+use super::tls;
 use crate::prelude::*;
 use anyhow::bail;
+use percent_encoding::percent_decode_str;
 use url::{Host, Url};
 
 mod client;
+mod server;
+
+/// Destination to request once the TLS layer is connected to the server.
+const BUNDLE_DEST: &str = "trojan-bundle-dest";
+
 pub fn register(plumber: &mut Plumber) {
     client::register(plumber);
+    server::register(plumber);
+
+    plumber.register("trojan_bundle", |config, _| {
+        let config: BundleConfig = from_value(config)?;
+        let (server, inner) = match config {
+            BundleConfig::Config { server, inner } => (server, inner),
+            BundleConfig::Url { url } => match parse_url(&url)? {
+                BundleConfig::Config { server, inner } => (server, inner),
+                BundleConfig::Url { .. } => unreachable!(),
+            },
+        };
+        Ok(Box::new(BundleProcessor::new(inner, server)?))
+    });
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct BundleConfigInner {
     sni: Option<SmolStr>,
     password: String,
+    #[serde(default)]
+    allow_insecure: bool,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -26,6 +48,52 @@ pub enum BundleConfig {
     },
 }
 
+/// TLS and Trojan clients to a server, set up from a config or a `trojan://` URL.
+pub struct BundleProcessor {
+    server: Option<DestAddr>,
+    tls: Arc<tls::ClientProcessor>,
+    trojan: Arc<client::ClientProcessor>,
+}
+
+impl BundleProcessor {
+    fn new(config: BundleConfigInner, server: Option<DestAddr>) -> Result<Self> {
+        let tls =
+            tls::ClientProcessor::new(tls::ClientConfig::new(config.sni, config.allow_insecure))?;
+        let trojan = client::ClientProcessor::new(client::ClientConfig {
+            password: config.password,
+        });
+        Ok(Self {
+            server,
+            tls: Arc::new(tls),
+            trojan: Arc::new(trojan),
+        })
+    }
+}
+
+#[async_trait]
+impl Processor for BundleProcessor {
+    async fn prepare(self: Arc<Self>, conn: &mut Connection, _ctx: AppContextRef) -> Result<()> {
+        if let Some(server) = &self.server {
+            let dest = std::mem::replace(&mut conn.dest_addr, server.clone());
+            conn.set_var(BUNDLE_DEST, dest);
+        }
+        Ok(())
+    }
+
+    async fn process(
+        self: Arc<Self>,
+        mut stream: ProxyStream,
+        conn: &mut Connection,
+        ctx: AppContextRef,
+    ) -> Result<ProxyStream> {
+        stream = self.tls.clone().process(stream, conn, ctx.clone()).await?;
+        if let Some(dest) = conn.take_var::<DestAddr>(BUNDLE_DEST) {
+            conn.dest_addr = dest;
+        }
+        self.trojan.clone().process(stream, conn, ctx).await
+    }
+}
+
 pub fn parse_url(url: &str) -> Result<BundleConfig> {
     let mut server = DestAddr::default();
     let parsed = Url::parse(url)?;
@@ -34,14 +102,17 @@ pub fn parse_url(url: &str) -> Result<BundleConfig> {
         bail!("Not trojan URL");
     }
 
+    let password = percent_decode_str(parsed.username()).decode_utf8()?;
     let mut inner = BundleConfigInner {
         sni: None,
-        password: parsed.username().into(),
+        password: password.into(),
+        allow_insecure: false,
     };
 
     match parsed.host() {
+        // Hosts of non-special schemes are opaque, even IPv4 ones
         Some(Host::Domain(s)) => {
-            server.set_domain(s);
+            server.set_host_from_str(s);
         }
         Some(Host::Ipv4(s)) => {
             server.set_ip(s);
@@ -55,5 +126,81 @@ pub fn parse_url(url: &str) -> Result<BundleConfig> {
     }
     server.set_port(parsed.port().unwrap_or(443));
 
-    todo!();
+    for (key, value) in parsed.query_pairs() {
+        match key.as_ref() {
+            "sni" if !value.is_empty() => inner.sni = Some(value.as_ref().into()),
+            // Older name of `sni`
+            "peer" if !value.is_empty() && inner.sni.is_none() => {
+                inner.sni = Some(value.as_ref().into())
+            }
+            "allowInsecure" => inner.allow_insecure = value == "1" || value == "true",
+            _ => {}
+        }
+    }
+
+    Ok(BundleConfig::Config {
+        server: Some(server),
+        inner,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(url: &str) -> (DestAddr, BundleConfigInner) {
+        match parse_url(url).unwrap() {
+            BundleConfig::Config { server, inner } => (server.unwrap(), inner),
+            BundleConfig::Url { .. } => panic!("Expected a config"),
+        }
+    }
+
+    #[test]
+    fn url_with_sni() {
+        let (server, inner) =
+            parse("trojan://pass%40word@example.com:8443?sni=cdn.example.org&allowInsecure=1");
+        assert_eq!(server.domain.as_deref(), Some("example.com"));
+        assert_eq!(server.port, Some(8443));
+        assert_eq!(inner.password, "pass@word");
+        assert_eq!(inner.sni.as_deref(), Some("cdn.example.org"));
+        assert!(inner.allow_insecure);
+    }
+
+    #[test]
+    fn url_with_peer() {
+        let (server, inner) = parse("trojan://secret@1.2.3.4?peer=example.com&allowInsecure=0");
+        assert_eq!(server.ip, Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(server.port, Some(443));
+        assert_eq!(inner.sni.as_deref(), Some("example.com"));
+        assert!(!inner.allow_insecure);
+
+        // `sni` wins over its older name in either order
+        let (_, inner) = parse("trojan://secret@1.2.3.4?sni=a.example&peer=b.example");
+        assert_eq!(inner.sni.as_deref(), Some("a.example"));
+        let (_, inner) = parse("trojan://secret@1.2.3.4?peer=b.example&sni=a.example");
+        assert_eq!(inner.sni.as_deref(), Some("a.example"));
+    }
+
+    #[test]
+    fn url_allow_insecure_values() {
+        for (value, expected) in [("true", true), ("1", true), ("false", false), ("", false)] {
+            let url = format!("trojan://secret@example.com?allowInsecure={}", value);
+            assert_eq!(parse(&url).1.allow_insecure, expected, "{}", value);
+        }
+        assert!(!parse("trojan://secret@example.com").1.allow_insecure);
+    }
+
+    #[test]
+    fn bundle_config_from_url() {
+        let config: BundleConfig =
+            serde_yaml::from_str("url: trojan://secret@example.com").unwrap();
+        assert!(matches!(config, BundleConfig::Url { .. }));
+        let config: BundleConfig =
+            serde_yaml::from_str("server: example.com:443\npassword: secret\nallow_insecure: true")
+                .unwrap();
+        match config {
+            BundleConfig::Config { inner, .. } => assert!(inner.allow_insecure),
+            BundleConfig::Url { .. } => panic!("Expected a config"),
+        }
+    }
 }
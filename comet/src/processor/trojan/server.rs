@@ -0,0 +1,120 @@
+use crate::{
+    crypto::hashing::{hash_bytes, HashKind},
+    prelude::*,
+    utils::{prepend_io::PrependReader, socks_addr::read_addr},
+};
+use anyhow::bail;
+
+pub fn register(plumber: &mut Plumber) {
+    plumber.register("trojan_server", |conf, _| {
+        let config: ServerConfig = from_value(conf)?;
+        Ok(Box::new(ServerProcessor::new(config)))
+    });
+}
+
+/// Length of hex(SHA224(password)).
+const HASH_LEN: usize = 56;
+const CMD_CONNECT: u8 = 0x01;
+
+#[derive(Debug, Clone, Deserialize)]
+struct ServerConfig {
+    users: Vec<UserConfig>,
+    /// Destination of connections failing authentication, so that probes see an
+    /// ordinary server.
+    fallback: Option<DestAddr>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct UserConfig {
+    username: SmolStr,
+    password: String,
+}
+
+struct ServerProcessor {
+    /// hex(SHA224(password)) to username
+    users: HashMap<String, SmolStr>,
+    fallback: Option<DestAddr>,
+}
+
+impl ServerProcessor {
+    fn new(config: ServerConfig) -> Self {
+        let users = config
+            .users
+            .into_iter()
+            .map(|u| {
+                let hashed_password = hash_bytes(HashKind::Sha224, u.password.as_bytes());
+                (hex::encode(hashed_password), u.username)
+            })
+            .collect();
+        Self {
+            users,
+            fallback: config.fallback,
+        }
+    }
+
+    /// Reads until the password line is complete, returning the user it belongs to.
+    async fn authenticate(
+        &self,
+        stream: &mut RWPair,
+        buffer: &mut BytesMut,
+    ) -> Result<Option<SmolStr>> {
+        while buffer.len() < HASH_LEN + 2 {
+            // Don't keep waiting on what is clearly not a Trojan client
+            let hash_len = buffer.len().min(HASH_LEN);
+            if !buffer[..hash_len].iter().all(u8::is_ascii_hexdigit) {
+                return Ok(None);
+            }
+            if stream.read_buf(buffer).await? == 0 {
+                return Ok(None);
+            }
+        }
+        if &buffer[HASH_LEN..HASH_LEN + 2] != b"\r\n" {
+            return Ok(None);
+        }
+
+        let hash = std::str::from_utf8(&buffer[..HASH_LEN])?;
+        Ok(self.users.get(hash).cloned())
+    }
+}
+
+#[async_trait]
+impl Processor for ServerProcessor {
+    async fn process(
+        self: Arc<Self>,
+        stream: ProxyStream,
+        conn: &mut Connection,
+        _ctx: AppContextRef,
+    ) -> Result<ProxyStream> {
+        let mut stream = stream.into_tcp()?;
+        let mut buffer = BytesMut::with_capacity(512);
+
+        let user = match self.authenticate(&mut stream, &mut buffer).await? {
+            Some(user) => user,
+            None => {
+                let fallback = match &self.fallback {
+                    Some(fallback) => fallback,
+                    None => bail!("Trojan authentication failed"),
+                };
+                debug!("Trojan authentication failed, falling back to {}", fallback);
+                // Replay everything read so far
+                conn.dest_addr = fallback.clone();
+                return Ok(RWPair::new(PrependReader::new(stream, buffer)).into());
+            }
+        };
+        conn.set_var(vars::USER, user);
+
+        buffer.advance(HASH_LEN + 2);
+        let mut stream = PrependReader::new(stream, buffer);
+        let cmd = stream.read_u8().await?;
+        if cmd != CMD_CONNECT {
+            bail!("Unsupported command: {}", cmd);
+        }
+        let mut dest_addr = DestAddr::default();
+        read_addr(&mut stream, &mut dest_addr).await?;
+        let mut crlf = [0u8; 2];
+        stream.read_exact(&mut crlf).await?;
+        conn.dest_addr = dest_addr;
+
+        Ok(RWPair::new(stream).into())
+    }
+}
@@ -0,0 +1,242 @@
+use super::*;
+use crate::{
+    app::mux::is_mux_dest,
+    delegate_write_all,
+    utils::{
+        io::{eof, io_other_error},
+        prepend_io::PrependWriter,
+        socks_addr::encode_host,
+    },
+};
+use anyhow::bail;
+use futures::ready;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::{
+    io::ReadBuf,
+    sync::{mpsc::channel, oneshot},
+};
+use tokio_stream::wrappers::ReceiverStream;
+use uuid::Uuid;
+
+pub fn register(plumber: &mut Plumber) {
+    plumber.register("vless_client", |conf, _| {
+        let config: ClientConfig = from_value(conf)?;
+        Ok(Box::new(ClientProcessor::new(config)))
+    });
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ClientConfig {
+    user_id: Uuid,
+}
+
+struct ClientProcessor {
+    user_id: Uuid,
+}
+
+impl ClientProcessor {
+    fn new(config: ClientConfig) -> Self {
+        Self {
+            user_id: config.user_id,
+        }
+    }
+
+    fn encode_request(&self, cmd: u8, dest: &DestAddr) -> Result<BytesMut> {
+        let mut buf = BytesMut::with_capacity(1 + 16 + 1 + 1 + 2 + 1 + 256);
+        buf.put_u8(VERSION);
+        buf.put_slice(self.user_id.as_bytes());
+        buf.put_u8(0); // No addons
+        buf.put_u8(cmd);
+        // Mux requests carry no destination
+        if cmd != CMD_MUX {
+            buf.put_u16(dest.port_or_error()?);
+            encode_host(dest, &mut buf, ADDR_TYPES)?;
+        }
+        Ok(buf)
+    }
+
+    fn process_udp(self: Arc<Self>, stream: RWPair, dest: Option<DestAddr>) -> UdpStream {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (read_sender, read_receiver) = channel::<UdpPacket>(10);
+        let (write_sender, mut write_receiver) = channel::<UdpPacket>(10);
+        let (source_sender, source_receiver) = oneshot::channel::<SocketAddr>();
+
+        tokio::spawn(async move {
+            // The request goes out with the first packet, whose target is the destination
+            // unless one was given.
+            let first = match write_receiver.recv().await {
+                Some(packet) => packet,
+                None => return,
+            };
+            let dest =
+                match dest.or_else(|| first.target().map(|t| DestAddr::new_ip(t.ip(), t.port()))) {
+                    Some(dest) => dest,
+                    None => {
+                        warn!("VLESS UDP requires a destination");
+                        return;
+                    }
+                };
+            let source = SocketAddr::new(
+                dest.ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+                dest.port.unwrap_or(0),
+            );
+            let _ = source_sender.send(source);
+
+            let request = match self.encode_request(CMD_UDP, &dest) {
+                Ok(request) => request,
+                Err(e) => {
+                    warn!("Dropping VLESS UDP session: {}", e);
+                    return;
+                }
+            };
+            let mut writer = PrependWriter::new(&mut writer, request);
+            let mut packet = first;
+            loop {
+                if write_packet(&mut writer, &packet).await.is_err() {
+                    break;
+                }
+                packet = match write_receiver.recv().await {
+                    Some(packet) => packet,
+                    None => break,
+                };
+            }
+            let _ = writer.shutdown().await;
+        });
+
+        tokio::spawn(async move {
+            let source = match source_receiver.await {
+                Ok(source) => source,
+                Err(_) => return,
+            };
+            if let Err(e) = read_response(&mut reader).await {
+                debug!("VLESS UDP stream closed: {}", e);
+                return;
+            }
+            loop {
+                let packet = tokio::select! {
+                    res = read_packet(&mut reader) => res,
+                    _ = read_sender.closed() => break,
+                };
+                match packet {
+                    Ok(packet) => {
+                        if read_sender
+                            .send(UdpPacket::new(source, packet))
+                            .await
+                            .is_err()
+                        {
+                            // Dropped
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        debug!("VLESS UDP stream closed: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        UdpStream::new(ReceiverStream::new(read_receiver), write_sender)
+    }
+}
+
+#[async_trait]
+impl Processor for ClientProcessor {
+    async fn process(
+        self: Arc<Self>,
+        stream: ProxyStream,
+        conn: &mut Connection,
+        _ctx: AppContextRef,
+    ) -> Result<ProxyStream> {
+        let stream = stream.into_tcp()?;
+
+        if conn.typ == TransportType::Udp {
+            let dest = Some(conn.dest_addr.clone()).filter(DestAddr::is_valid);
+            return Ok(self.process_udp(stream, dest).into());
+        }
+
+        let cmd = if is_mux_dest(&conn.dest_addr) {
+            CMD_MUX
+        } else {
+            CMD_TCP
+        };
+        let request = self.encode_request(cmd, &conn.dest_addr)?;
+        let reader = ResponseReader::new(stream);
+        Ok(RWPair::new(PrependWriter::new(reader, request)).into())
+    }
+}
+
+/// Reads and discards the response header.
+async fn read_response<R: AsyncRead + Unpin>(reader: &mut R) -> Result<()> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header).await?;
+    if header[0] != VERSION {
+        bail!("Unexpected version: {}", header[0]);
+    }
+    let mut addons = [0u8; 255];
+    reader.read_exact(&mut addons[..header[1] as usize]).await?;
+    Ok(())
+}
+
+/// Strips the response header before passing data through.
+#[derive(Debug)]
+struct ResponseReader<RW> {
+    inner: RW,
+    header: [u8; 2],
+    header_len: usize,
+    /// Addon bytes left to skip, known once the header is read.
+    skip: Option<usize>,
+}
+
+impl<RW> ResponseReader<RW> {
+    fn new(inner: RW) -> Self {
+        Self {
+            inner,
+            header: [0; 2],
+            header_len: 0,
+            skip: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ResponseReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        let me = &mut *self;
+        loop {
+            match me.skip {
+                None => {
+                    let mut header_buf = ReadBuf::new(&mut me.header[me.header_len..]);
+                    ready!(Pin::new(&mut me.inner).poll_read(cx, &mut header_buf))?;
+                    let n = header_buf.filled().len();
+                    if n == 0 {
+                        return Poll::Ready(Err(eof()));
+                    }
+                    me.header_len += n;
+                    if me.header_len == me.header.len() {
+                        if me.header[0] != VERSION {
+                            return Poll::Ready(Err(io_other_error("Unexpected VLESS version")));
+                        }
+                        me.skip = Some(me.header[1] as usize);
+                    }
+                }
+                Some(0) => return Pin::new(&mut me.inner).poll_read(cx, buf),
+                Some(len) => {
+                    let mut addons = [0u8; 255];
+                    let mut addons_buf = ReadBuf::new(&mut addons[..len]);
+                    ready!(Pin::new(&mut me.inner).poll_read(cx, &mut addons_buf))?;
+                    let n = addons_buf.filled().len();
+                    if n == 0 {
+                        return Poll::Ready(Err(eof()));
+                    }
+                    me.skip = Some(len - n);
+                }
+            }
+        }
+    }
+}
+
+delegate_write_all!(ResponseReader);
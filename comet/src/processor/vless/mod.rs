@@ -0,0 +1,54 @@
+//! VLESS, a plain UUID-authenticated header meant to be carried by TLS.
+//!
+//! ```text
+//! Request:
+//! +---------+------+-------------+--------+-----+------+------+----------+----------+
+//! | Version | UUID | Addons Len  | Addons | Cmd | Port | ATYP | DST.ADDR | Payload  |
+//! +---------+------+-------------+--------+-----+------+------+----------+----------+
+//! |    1    |  16  |      1      |   N    |  1  |  2   |  1   | Variable | Variable |
+//! +---------+------+-------------+--------+-----+------+------+----------+----------+
+//!
+//! Response:
+//! +---------+-------------+--------+----------+
+//! | Version | Addons Len  | Addons | Payload  |
+//! +---------+-------------+--------+----------+
+//! |    1    |      1      |   N    | Variable |
+//! +---------+-------------+--------+----------+
+//! ```
+//!
+//! UDP payloads are framed as `Length (2) | Payload`, all going to the requested destination.
+mod client;
+mod server;
+
+use crate::{prelude::*, utils::socks_addr::AddrTypes};
+
+pub fn register(plumber: &mut Plumber) {
+    client::register(plumber);
+    server::register(plumber);
+}
+
+const VERSION: u8 = 0;
+const CMD_TCP: u8 = 0x01;
+const CMD_UDP: u8 = 0x02;
+const CMD_MUX: u8 = 0x03;
+
+const ADDR_TYPES: AddrTypes = AddrTypes {
+    ipv4: 1,
+    domain: 2,
+    ipv6: 3,
+};
+
+async fn write_packet<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    let mut buf = BytesMut::with_capacity(2 + payload.len());
+    buf.put_u16(payload.len() as u16);
+    buf.put_slice(payload);
+    writer.write_all(&buf).await?;
+    Ok(())
+}
+
+async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R) -> Result<BytesMut> {
+    let len = reader.read_u16().await? as usize;
+    let mut buf = BytesMut::zeroed(len);
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
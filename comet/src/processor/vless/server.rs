@@ -0,0 +1,140 @@
+use super::*;
+use crate::utils::{prepend_io::PrependWriter, socks_addr::read_host};
+use anyhow::bail;
+use std::net::SocketAddr;
+use tokio::sync::mpsc::channel;
+use tokio_stream::wrappers::ReceiverStream;
+use uuid::Uuid;
+
+pub fn register(plumber: &mut Plumber) {
+    plumber.register("vless_server", |conf, _| {
+        let config: ServerConfig = from_value(conf)?;
+        Ok(Box::new(ServerProcessor::new(config)))
+    });
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ServerConfig {
+    users: Vec<UserConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct UserConfig {
+    username: SmolStr,
+    user_id: Uuid,
+}
+
+struct ServerProcessor {
+    users: HashMap<Uuid, SmolStr>,
+}
+
+impl ServerProcessor {
+    fn new(config: ServerConfig) -> Self {
+        let users = config
+            .users
+            .into_iter()
+            .map(|u| (u.user_id, u.username))
+            .collect();
+        Self { users }
+    }
+}
+
+#[async_trait]
+impl Processor for ServerProcessor {
+    async fn process(
+        self: Arc<Self>,
+        stream: ProxyStream,
+        conn: &mut Connection,
+        _ctx: AppContextRef,
+    ) -> Result<ProxyStream> {
+        let mut stream = stream.into_tcp()?;
+
+        let mut header = [0u8; 1 + 16 + 1];
+        stream.read_exact(&mut header).await?;
+        if header[0] != VERSION {
+            bail!("Unsupported VLESS version: {}", header[0]);
+        }
+        let user_id = Uuid::from_slice(&header[1..17])?;
+        let user = match self.users.get(&user_id) {
+            Some(user) => user.clone(),
+            None => bail!("VLESS authentication failed: {}", user_id),
+        };
+        // Addons (flow control) are not supported and skipped
+        let mut addons = [0u8; 255];
+        stream
+            .read_exact(&mut addons[..header[17] as usize])
+            .await?;
+
+        let cmd = stream.read_u8().await?;
+        let udp = match cmd {
+            CMD_TCP => false,
+            CMD_UDP => true,
+            // Mux requests carry no address
+            CMD_MUX => bail!("VLESS mux is not supported"),
+            _ => bail!("Unsupported command: {}", cmd),
+        };
+        let mut dest_addr = DestAddr::default();
+        dest_addr.set_port(stream.read_u16().await?);
+        read_host(&mut stream, &mut dest_addr, ADDR_TYPES).await?;
+
+        conn.set_var(vars::USER, user);
+        conn.dest_addr = dest_addr;
+
+        let stream = PrependWriter::new(stream, [VERSION, 0].as_ref());
+        if udp {
+            conn.typ = TransportType::Udp;
+            Ok(process_udp(stream, conn.dest_addr.clone()).into())
+        } else {
+            Ok(RWPair::new(stream).into())
+        }
+    }
+}
+
+/// Relays packets from a single destination over the stream.
+fn process_udp<RW: AsyncRead + AsyncWrite + Send + 'static>(
+    stream: RW,
+    dest: DestAddr,
+) -> UdpStream {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (read_sender, read_receiver) = channel::<UdpPacket>(10);
+    let (write_sender, mut write_receiver) = channel::<UdpPacket>(10);
+
+    tokio::spawn(async move {
+        while let Some(packet) = write_receiver.recv().await {
+            if write_packet(&mut writer, &packet).await.is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+
+    tokio::spawn(async move {
+        let target = dest
+            .ip
+            .map(|ip| SocketAddr::new(ip, dest.port.unwrap_or(0)));
+        loop {
+            let packet = tokio::select! {
+                res = read_packet(&mut reader) => res,
+                _ = read_sender.closed() => break,
+            };
+            let packet = match packet {
+                Ok(packet) => packet,
+                Err(e) => {
+                    debug!("VLESS UDP stream closed: {}", e);
+                    break;
+                }
+            };
+            // Domains are left to the outbound, which resolves `dest_addr`
+            let packet = match target {
+                Some(target) => UdpPacket::new(target, packet),
+                None => UdpPacket::new_unknown(packet),
+            };
+            if read_sender.send(packet).await.is_err() {
+                // Dropped
+                break;
+            }
+        }
+    });
+
+    UdpStream::new(ReceiverStream::new(read_receiver), write_sender)
+}
@@ -4,9 +4,18 @@ use sha3::{
 };
 
 use crate::crypto::{
-    aead::NonceSeq,
+    aead::{AeadCipherKind, AeadCrypter, FixedNonce, NonceSeq, SsCrypter},
     hashing::{hash_bytes, HashKind},
+    CrypterMode,
 };
+use crate::prelude::*;
+use crate::utils::unix_ts;
+use aes::{
+    cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use rand::{thread_rng, Rng};
+use std::convert::TryInto;
 
 pub struct ShakeGenerator(Sha3XofReader);
 
@@ -72,3 +81,208 @@ pub fn generate_chacha20poly1305_key(key: &[u8]) -> Vec<u8> {
     let key_2 = hash_bytes(HashKind::Md5, &key_1);
     [key_1, key_2].concat()
 }
+
+const KDF_SALT: &[u8] = b"VMess AEAD KDF";
+pub const KDF_AUTH_ID_KEY: &[u8] = b"AES Auth ID Encryption";
+pub const KDF_RESP_HEADER_LEN_KEY: &[u8] = b"AEAD Resp Header Len Key";
+pub const KDF_RESP_HEADER_LEN_IV: &[u8] = b"AEAD Resp Header Len IV";
+pub const KDF_RESP_HEADER_KEY: &[u8] = b"AEAD Resp Header Key";
+pub const KDF_RESP_HEADER_IV: &[u8] = b"AEAD Resp Header IV";
+pub const KDF_HEADER_KEY: &[u8] = b"VMess Header AEAD Key";
+pub const KDF_HEADER_IV: &[u8] = b"VMess Header AEAD Nonce";
+pub const KDF_HEADER_LEN_KEY: &[u8] = b"VMess Header AEAD Key_Length";
+pub const KDF_HEADER_LEN_IV: &[u8] = b"VMess Header AEAD Nonce_Length";
+
+/// HMAC-SHA256 nested once per key, each level using the previous one as its hash.
+fn nested_hmac(keys: &[&[u8]], data: &[u8]) -> [u8; 32] {
+    let (key, parents) = match keys.split_last() {
+        Some(split) => split,
+        None => {
+            return hash_bytes(HashKind::Sha256, data)
+                .as_ref()
+                .try_into()
+                .unwrap()
+        }
+    };
+
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        block[..32].copy_from_slice(&nested_hmac(parents, key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(data);
+    let inner_hash = nested_hmac(parents, &inner);
+
+    let mut outer: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&inner_hash);
+    nested_hmac(parents, &outer)
+}
+
+/// The VMess AEAD key derivation function.
+pub fn kdf(key: &[u8], path: &[&[u8]]) -> [u8; 32] {
+    let mut keys = Vec::with_capacity(1 + path.len());
+    keys.push(KDF_SALT);
+    keys.extend_from_slice(path);
+    nested_hmac(&keys, key)
+}
+
+pub fn kdf16(key: &[u8], path: &[&[u8]]) -> [u8; 16] {
+    kdf(key, path)[..16].try_into().unwrap()
+}
+
+fn aes128_gcm(mode: CrypterMode, key: &[u8], nonce: &[u8]) -> Result<SsCrypter<FixedNonce>> {
+    AeadCipherKind::Aes128Gcm.to_crypter(mode, key, FixedNonce::new(nonce))
+}
+
+/// `AES(timestamp | random | crc32)`, identifying the user of an AEAD header.
+pub fn create_auth_id(cmd_key: &[u8], timestamp: u64) -> [u8; 16] {
+    let mut auth_id = [0u8; 16];
+    auth_id[..8].copy_from_slice(&timestamp.to_be_bytes());
+    thread_rng().fill(&mut auth_id[8..12]);
+    let checksum = crc32fast::hash(&auth_id[..12]);
+    auth_id[12..].copy_from_slice(&checksum.to_be_bytes());
+
+    let cipher = Aes128::new(GenericArray::from_slice(&kdf16(
+        cmd_key,
+        &[KDF_AUTH_ID_KEY],
+    )));
+    cipher.encrypt_block(GenericArray::from_mut_slice(&mut auth_id));
+    auth_id
+}
+
+/// Decrypts auth IDs of one user, keeping the derived cipher.
+pub struct AuthIdDecoder(Aes128);
+
+impl AuthIdDecoder {
+    pub fn new(cmd_key: &[u8]) -> Self {
+        Self(Aes128::new(GenericArray::from_slice(&kdf16(
+            cmd_key,
+            &[KDF_AUTH_ID_KEY],
+        ))))
+    }
+
+    /// Returns the timestamp of `auth_id` if its checksum matches.
+    pub fn open(&self, auth_id: &[u8; 16]) -> Option<u64> {
+        let mut buf = *auth_id;
+        self.0.decrypt_block(GenericArray::from_mut_slice(&mut buf));
+
+        let checksum = u32::from_be_bytes(buf[12..].try_into().unwrap());
+        if crc32fast::hash(&buf[..12]) != checksum {
+            return None;
+        }
+        Some(u64::from_be_bytes(buf[..8].try_into().unwrap()))
+    }
+}
+
+/// Seals a request header as `auth ID | length | nonce | header`.
+pub fn seal_header(cmd_key: &[u8], header: &[u8]) -> Result<BytesMut> {
+    let auth_id = create_auth_id(cmd_key, unix_ts().as_secs());
+    let nonce: [u8; 8] = thread_rng().gen();
+    let path = |label| [label, &auth_id[..], &nonce[..]];
+
+    let mut buf = BytesMut::with_capacity(16 + 2 + 16 + 8 + header.len() + 16);
+    buf.put_slice(&auth_id);
+
+    let start = buf.len();
+    buf.put_u16(header.len() as u16);
+    buf.put_bytes(0, 16);
+    aes128_gcm(
+        CrypterMode::Encrypt,
+        &kdf16(cmd_key, &path(KDF_HEADER_LEN_KEY)),
+        &kdf(cmd_key, &path(KDF_HEADER_LEN_IV)),
+    )?
+    .update_with_aad(&auth_id, &mut buf[start..])?;
+
+    buf.put_slice(&nonce);
+
+    let start = buf.len();
+    buf.put_slice(header);
+    buf.put_bytes(0, 16);
+    aes128_gcm(
+        CrypterMode::Encrypt,
+        &kdf16(cmd_key, &path(KDF_HEADER_KEY)),
+        &kdf(cmd_key, &path(KDF_HEADER_IV)),
+    )?
+    .update_with_aad(&auth_id, &mut buf[start..])?;
+
+    Ok(buf)
+}
+
+/// Opens the sealed length of a request header in place.
+pub fn open_header_length(
+    cmd_key: &[u8],
+    auth_id: &[u8],
+    nonce: &[u8],
+    buf: &mut [u8],
+) -> Result<usize> {
+    let path = |label| [label, auth_id, nonce];
+    aes128_gcm(
+        CrypterMode::Decrypt,
+        &kdf16(cmd_key, &path(KDF_HEADER_LEN_KEY)),
+        &kdf(cmd_key, &path(KDF_HEADER_LEN_IV)),
+    )?
+    .update_with_aad(auth_id, buf)?;
+    Ok(u16::from_be_bytes([buf[0], buf[1]]) as usize)
+}
+
+/// Opens a sealed request header in place, returning its length.
+pub fn open_header(cmd_key: &[u8], auth_id: &[u8], nonce: &[u8], buf: &mut [u8]) -> Result<usize> {
+    let path = |label| [label, auth_id, nonce];
+    aes128_gcm(
+        CrypterMode::Decrypt,
+        &kdf16(cmd_key, &path(KDF_HEADER_KEY)),
+        &kdf(cmd_key, &path(KDF_HEADER_IV)),
+    )?
+    .update_with_aad(auth_id, buf)
+}
+
+/// Seals a response header as `length | header`.
+pub fn seal_response(key: &[u8], iv: &[u8], header: &[u8]) -> Result<BytesMut> {
+    let mut buf = BytesMut::with_capacity(2 + 16 + header.len() + 16);
+    buf.put_u16(header.len() as u16);
+    buf.put_bytes(0, 16);
+    aes128_gcm(
+        CrypterMode::Encrypt,
+        &kdf16(key, &[KDF_RESP_HEADER_LEN_KEY]),
+        &kdf(iv, &[KDF_RESP_HEADER_LEN_IV]),
+    )?
+    .update(&mut buf[..])?;
+
+    let start = buf.len();
+    buf.put_slice(header);
+    buf.put_bytes(0, 16);
+    aes128_gcm(
+        CrypterMode::Encrypt,
+        &kdf16(key, &[KDF_RESP_HEADER_KEY]),
+        &kdf(iv, &[KDF_RESP_HEADER_IV]),
+    )?
+    .update(&mut buf[start..])?;
+
+    Ok(buf)
+}
+
+/// Opens the length of an AEAD response header from its first 18 bytes.
+pub fn open_response_length(key: &[u8], iv: &[u8], buf: &[u8]) -> Result<usize> {
+    let mut len = [0u8; 2 + 16];
+    len.copy_from_slice(&buf[..2 + 16]);
+    aes128_gcm(
+        CrypterMode::Decrypt,
+        &kdf16(key, &[KDF_RESP_HEADER_LEN_KEY]),
+        &kdf(iv, &[KDF_RESP_HEADER_LEN_IV]),
+    )?
+    .update(&mut len)?;
+    Ok(u16::from_be_bytes([len[0], len[1]]) as usize)
+}
+
+/// Opens an AEAD response header (following its length) in place, returning its length.
+pub fn open_response(key: &[u8], iv: &[u8], buf: &mut [u8]) -> Result<usize> {
+    aes128_gcm(
+        CrypterMode::Decrypt,
+        &kdf16(key, &[KDF_RESP_HEADER_KEY]),
+        &kdf(iv, &[KDF_RESP_HEADER_IV]),
+    )?
+    .update(buf)
+}
@@ -1,5 +1,6 @@
 mod alter_id;
 mod crypto;
+mod server;
 mod session;
 
 use crate::{delegate_read, delegate_write_all, utils::io::eof};
@@ -12,6 +13,7 @@ use crate::{
 };
 use bytes::BufMut;
 use futures::ready;
+use rand::{seq::SliceRandom, thread_rng};
 use tokio_util::io::poll_read_buf;
 use uuid::Uuid;
 
@@ -24,6 +26,7 @@ use self::{
 };
 
 pub fn register(plumber: &mut Plumber) {
+    server::register(plumber);
     plumber.register("vmess_client", |conf, _| {
         let config: ClientConfig = from_value(conf)?;
         let processor = ClientProcessor::new(config);
@@ -61,11 +64,15 @@ struct ClientConfig {
     alter_id: u16,
     #[serde(default)]
     security: SecurityType,
+    /// Use the AEAD header, defaults to `true` when `alter_id` is 0.
+    aead: Option<bool>,
 }
 
 struct ClientProcessor {
     security: SecurityType,
+    /// Alter IDs first, primary ID last.
     accounts: Vec<UserId>,
+    aead: bool,
 }
 
 impl ClientProcessor {
@@ -85,6 +92,7 @@ impl ClientProcessor {
         Self {
             security: config.security,
             accounts,
+            aead: config.aead.unwrap_or(config.alter_id == 0),
         }
     }
 }
@@ -99,8 +107,14 @@ impl Processor for ClientProcessor {
     ) -> Result<ProxyStream> {
         let stream = stream.into_tcp()?;
 
-        let user = &self.accounts[0];
-        let session = Arc::new(ClientSession::new(user));
+        let primary = self.accounts.last().unwrap();
+        let auth_id = if self.aead {
+            // AEAD only authenticates the primary ID
+            primary
+        } else {
+            self.accounts.choose(&mut thread_rng()).unwrap()
+        };
+        let session = Arc::new(ClientSession::new(primary, auth_id, self.aead));
         let header = session.encode_request_header(self.security, conn)?;
 
         let reader = ClientReader::new(stream, session.clone(), self.security)?;
@@ -109,6 +123,22 @@ impl Processor for ClientProcessor {
     }
 }
 
+fn new_crypter(
+    mode: CrypterMode,
+    security: SecurityType,
+    key: &[u8],
+    nonce_seq: VmessNonceSeq,
+) -> Result<aead::SsCrypter<VmessNonceSeq>> {
+    match security {
+        SecurityType::Aes128Gcm => aead::AeadCipherKind::Aes128Gcm.to_crypter(mode, key, nonce_seq),
+        SecurityType::Chacha20Poly1305 => {
+            let key = crypto::generate_chacha20poly1305_key(key);
+            aead::AeadCipherKind::Chacha20Poly1305.to_crypter(mode, &key, nonce_seq)
+        }
+        SecurityType::Auto => unimplemented!(),
+    }
+}
+
 #[derive(Debug)]
 struct ClientReader<R> {
     inner: R,
@@ -122,26 +152,42 @@ struct ClientReader<R> {
 
 impl<R: AsyncRead + Unpin> ClientReader<R> {
     fn new(inner: R, session: Arc<ClientSession>, security: SecurityType) -> Result<Self> {
+        let (key, iv) = (session.response_key, session.response_iv);
+        Self::with_key(
+            inner,
+            session,
+            security,
+            &key,
+            &iv,
+            ClientReaderState::ReadHeader,
+        )
+    }
+
+    /// Reads the request body on the server side, whose header has been consumed.
+    fn new_server(inner: R, session: Arc<ClientSession>, security: SecurityType) -> Result<Self> {
+        let (key, iv) = (session.request_key, session.request_iv);
+        Self::with_key(
+            inner,
+            session,
+            security,
+            &key,
+            &iv,
+            ClientReaderState::ReadLength,
+        )
+    }
+
+    fn with_key(
+        inner: R,
+        session: Arc<ClientSession>,
+        security: SecurityType,
+        key: &[u8],
+        iv: &[u8],
+        state: ClientReaderState,
+    ) -> Result<Self> {
         let read_buf = BytesMut::with_capacity(4 + 2);
-        let shake = ShakeGenerator::new(&session.response_iv);
-        let nonce_seq = VmessNonceSeq::new(&session.response_iv[2..12]);
-        let crypter = match security {
-            SecurityType::Aes128Gcm => aead::AeadCipherKind::Aes128Gcm.to_crypter(
-                CrypterMode::Decrypt,
-                &session.response_key,
-                nonce_seq,
-            )?,
-            SecurityType::Chacha20Poly1305 => {
-                let key = crypto::generate_chacha20poly1305_key(&session.response_key);
-                aead::AeadCipherKind::Chacha20Poly1305.to_crypter(
-                    CrypterMode::Decrypt,
-                    &key,
-                    nonce_seq,
-                )?
-            }
-            SecurityType::Auto => unimplemented!(),
-        };
-        let state = ClientReaderState::ReadHeader;
+        let shake = ShakeGenerator::new(iv);
+        let nonce_seq = VmessNonceSeq::new(&iv[2..12]);
+        let crypter = new_crypter(CrypterMode::Decrypt, security, key, nonce_seq)?;
 
         Ok(Self {
             inner,
@@ -205,9 +251,16 @@ impl<R: AsyncRead + Unpin> AsyncRead for ClientReader<R> {
         loop {
             match me.state {
                 ClientReaderState::ReadHeader => {
-                    ready!(me.poll_fill_at_least(cx, 4))?;
+                    if me.session.aead {
+                        ready!(me.poll_fill_at_least(cx, session::AEAD_LEN_SIZE))?;
+                    }
+                    let header_len = me
+                        .session
+                        .response_header_len(&me.read_buf)
+                        .map_err(io_other_error)?;
+                    ready!(me.poll_fill_at_least(cx, header_len))?;
 
-                    let header = me.read_buf.split_to(4);
+                    let header = me.read_buf.split_to(header_len);
                     me.session
                         .decode_response_header(&header)
                         .map_err(io_other_error)?;
@@ -279,26 +332,43 @@ impl<W: AsyncWrite + Unpin> ClientWriter<W> {
         session: Arc<ClientSession>,
         security: SecurityType,
         header: BytesMut,
+    ) -> Result<Self> {
+        Self::with_key(
+            inner,
+            security,
+            &session.request_key,
+            &session.request_iv,
+            header,
+        )
+    }
+
+    /// Writes the response body on the server side, prefixed by the response header.
+    fn new_server(
+        inner: W,
+        session: Arc<ClientSession>,
+        security: SecurityType,
+        header: BytesMut,
+    ) -> Result<Self> {
+        Self::with_key(
+            inner,
+            security,
+            &session.response_key,
+            &session.response_iv,
+            header,
+        )
+    }
+
+    fn with_key(
+        inner: W,
+        security: SecurityType,
+        key: &[u8],
+        iv: &[u8],
+        header: BytesMut,
     ) -> Result<Self> {
         let write_buf = header;
-        let shake = ShakeGenerator::new(&session.request_iv);
-        let nonce_seq = VmessNonceSeq::new(&session.request_iv[2..12]);
-        let crypter = match security {
-            SecurityType::Aes128Gcm => aead::AeadCipherKind::Aes128Gcm.to_crypter(
-                CrypterMode::Encrypt,
-                &session.request_key,
-                nonce_seq,
-            )?,
-            SecurityType::Chacha20Poly1305 => {
-                let key = crypto::generate_chacha20poly1305_key(&session.request_key);
-                aead::AeadCipherKind::Chacha20Poly1305.to_crypter(
-                    CrypterMode::Encrypt,
-                    &key,
-                    nonce_seq,
-                )?
-            }
-            SecurityType::Auto => unimplemented!(),
-        };
+        let shake = ShakeGenerator::new(iv);
+        let nonce_seq = VmessNonceSeq::new(&iv[2..12]);
+        let crypter = new_crypter(CrypterMode::Encrypt, security, key, nonce_seq)?;
         let state = ClientWriterState::Waiting;
 
         Ok(Self {
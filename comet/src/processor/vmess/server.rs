@@ -0,0 +1,443 @@
+use super::{
+    alter_id::{self, UserId},
+    crypto::{self, AuthIdDecoder},
+    session::{self, ClientSession},
+    ClientReader, ClientWriter, SecurityType,
+};
+use crate::{
+    crypto::{
+        hashing::{sign_bytes, HashKind},
+        stream::{StreamCipherKind, StreamCrypter},
+        CrypterMode,
+    },
+    prelude::*,
+    utils::unix_ts,
+};
+use anyhow::bail;
+use lz_fnv::{Fnv1a, FnvHasher};
+use std::{
+    collections::VecDeque,
+    convert::TryInto,
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+pub fn register(plumber: &mut Plumber) {
+    plumber.register("vmess_server", |conf, _| {
+        let config: ServerConfig = from_value(conf)?;
+        Ok(Box::new(ServerProcessor::new(config)))
+    });
+}
+
+/// Maximum clock difference between client and server, in seconds.
+const MAX_TIME_DIFF: u64 = 120;
+/// How long an AEAD auth ID is remembered for replay detection.
+const AUTH_ID_TTL: Duration = Duration::from_secs(2 * MAX_TIME_DIFF);
+/// Interval between sweeps of expired auth IDs.
+const AUTH_ID_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+/// Ver .. address type, the fixed part of a request command.
+const CMD_FIXED_LEN: usize = 41;
+
+#[derive(Debug, Clone, Deserialize)]
+struct ServerConfig {
+    users: Vec<UserConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct UserConfig {
+    username: SmolStr,
+    user_id: Uuid,
+    #[serde(default)]
+    alter_id: u16,
+}
+
+struct Account {
+    username: SmolStr,
+    /// ID authenticating the request, the primary ID or one of its alter IDs.
+    id: UserId,
+    /// Its command section is always encrypted with the primary ID.
+    primary: UserId,
+    /// Only primary IDs may use the AEAD header.
+    aead: Option<AuthIdDecoder>,
+}
+
+struct AuthIdFilter {
+    seen: HashMap<[u8; 16], Instant>,
+    last_sweep: Instant,
+}
+
+impl Default for AuthIdFilter {
+    fn default() -> Self {
+        Self {
+            seen: HashMap::new(),
+            last_sweep: Instant::now(),
+        }
+    }
+}
+
+impl AuthIdFilter {
+    /// Returns `false` if `auth_id` has been seen.
+    fn insert(&mut self, auth_id: [u8; 16]) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.last_sweep) >= AUTH_ID_SWEEP_INTERVAL {
+            self.seen
+                .retain(|_, seen| now.duration_since(*seen) < AUTH_ID_TTL);
+            self.last_sweep = now;
+        }
+        !matches!(self.seen.insert(auth_id, now),
+            Some(seen) if now.duration_since(seen) < AUTH_ID_TTL)
+    }
+}
+
+/// Legacy auth hashes of all accounts for every timestamp within `MAX_TIME_DIFF` of now, like
+/// V2Ray's `TimedUserValidator`, so that matching one is a lookup.
+#[derive(Default)]
+struct LegacyAuthTable {
+    hashes: HashMap<[u8; 16], (usize, u64)>,
+    /// Hashes generated for each timestamp, oldest first.
+    generated: VecDeque<(u64, Vec<[u8; 16]>)>,
+}
+
+impl LegacyAuthTable {
+    fn update(&mut self, accounts: &[Account], now: u64) {
+        while let Some((ts, _)) = self.generated.front() {
+            if *ts + MAX_TIME_DIFF >= now {
+                break;
+            }
+            let (_, hashes) = self.generated.pop_front().unwrap();
+            for hash in hashes {
+                self.hashes.remove(&hash);
+            }
+        }
+
+        // Skips timestamps left behind by a clock jump
+        let start = match self.generated.back() {
+            Some((ts, _)) => (ts + 1).max(now - MAX_TIME_DIFF),
+            None => now - MAX_TIME_DIFF,
+        };
+        for ts in start..=now + MAX_TIME_DIFF {
+            let ts_bytes = ts.to_be_bytes();
+            let mut hashes = Vec::with_capacity(accounts.len());
+            for (i, account) in accounts.iter().enumerate() {
+                let hash = sign_bytes(HashKind::Md5, &account.id.uuid().as_bytes()[..], &ts_bytes);
+                let hash: [u8; 16] = hash.as_ref().try_into().unwrap();
+                self.hashes.insert(hash, (i, ts));
+                hashes.push(hash);
+            }
+            self.generated.push_back((ts, hashes));
+        }
+    }
+}
+
+struct Request {
+    key: [u8; 16],
+    iv: [u8; 16],
+    auth_v: u8,
+    security: SecurityType,
+    dest_addr: DestAddr,
+}
+
+struct ServerProcessor {
+    accounts: Vec<Account>,
+    auth_ids: Mutex<AuthIdFilter>,
+    legacy_auths: Mutex<LegacyAuthTable>,
+}
+
+impl ServerProcessor {
+    fn new(config: ServerConfig) -> Self {
+        let mut accounts = vec![];
+        for user in config.users {
+            let primary = UserId::new(user.user_id);
+            for id in alter_id::new_alter_ids(&primary, user.alter_id) {
+                accounts.push(Account {
+                    username: user.username.clone(),
+                    id,
+                    primary: primary.clone(),
+                    aead: None,
+                });
+            }
+            accounts.push(Account {
+                username: user.username,
+                aead: Some(AuthIdDecoder::new(&primary.cmd_key())),
+                id: primary.clone(),
+                primary,
+            });
+        }
+        let mut legacy_auths = LegacyAuthTable::default();
+        legacy_auths.update(&accounts, unix_ts().as_secs());
+        Self {
+            accounts,
+            auth_ids: Default::default(),
+            legacy_auths: Mutex::new(legacy_auths),
+        }
+    }
+
+    fn match_aead(&self, auth_id: &[u8; 16], now: u64) -> Option<&Account> {
+        self.accounts.iter().find(|a| {
+            matches!(a.aead.as_ref().and_then(|d| d.open(auth_id)),
+                Some(ts) if now.abs_diff(ts) <= MAX_TIME_DIFF)
+        })
+    }
+
+    /// Finds the account and timestamp of a legacy `HMAC(uuid, timestamp)` auth.
+    fn match_legacy(&self, auth: &[u8; 16], now: u64) -> Option<(&Account, u64)> {
+        let mut table = self.legacy_auths.lock().unwrap();
+        table.update(&self.accounts, now);
+        let &(i, ts) = table.hashes.get(auth)?;
+        Some((&self.accounts[i], ts))
+    }
+
+    async fn read_aead_header(
+        &self,
+        stream: &mut RWPair,
+        cmd_key: &[u8],
+        auth_id: [u8; 16],
+    ) -> Result<BytesMut> {
+        if !self.auth_ids.lock().unwrap().insert(auth_id) {
+            bail!("Replayed auth ID");
+        }
+        let mut len = [0u8; 2 + 16];
+        stream.read_exact(&mut len).await?;
+        let mut nonce = [0u8; 8];
+        stream.read_exact(&mut nonce).await?;
+        let len = crypto::open_header_length(cmd_key, &auth_id, &nonce, &mut len)?;
+
+        let mut header = BytesMut::new();
+        header.resize(len + 16, 0);
+        stream.read_exact(&mut header).await?;
+        let len = crypto::open_header(cmd_key, &auth_id, &nonce, &mut header)?;
+        header.truncate(len);
+        Ok(header)
+    }
+}
+
+/// Reads `len` more bytes of the legacy command and decrypts them.
+async fn read_decrypt(
+    stream: &mut RWPair,
+    crypter: &mut impl StreamCrypter,
+    buf: &mut BytesMut,
+    len: usize,
+) -> Result<()> {
+    let start = buf.len();
+    buf.resize(start + len, 0);
+    stream.read_exact(&mut buf[start..]).await?;
+    crypter.update(&mut buf[start..])?;
+    Ok(())
+}
+
+/// Reads the CFB encrypted command, decrypting as far as needed to know its length.
+async fn read_legacy_header(stream: &mut RWPair, cmd_key: &[u8], ts: u64) -> Result<BytesMut> {
+    let cmd_iv = session::cmd_iv(&ts.to_be_bytes());
+    let mut crypter =
+        StreamCipherKind::Aes128Cfb.to_crypter(CrypterMode::Decrypt, cmd_key, &cmd_iv)?;
+    let mut header = BytesMut::new();
+    read_decrypt(stream, &mut crypter, &mut header, CMD_FIXED_LEN).await?;
+
+    let padding_len = (header[35] >> 4) as usize;
+    let addr_len = match header[40] {
+        0x01 => 4,
+        0x03 => 16,
+        0x02 => {
+            read_decrypt(stream, &mut crypter, &mut header, 1).await?;
+            // The length byte has been read
+            header[CMD_FIXED_LEN] as usize
+        }
+        t => bail!("Unknown address type: {}", t),
+    };
+
+    read_decrypt(
+        stream,
+        &mut crypter,
+        &mut header,
+        addr_len + padding_len + 4,
+    )
+    .await?;
+    Ok(header)
+}
+
+fn decode_request(buf: &[u8]) -> Result<Request> {
+    if buf.len() < CMD_FIXED_LEN + 4 {
+        bail!("Request header too short");
+    }
+    let (buf, checksum) = buf.split_at(buf.len() - 4);
+    let mut hasher = Fnv1a::<u32>::new();
+    hasher.write(buf);
+    if hasher.finish() != u32::from_be_bytes(checksum.try_into().unwrap()) {
+        bail!("Request header checksum mismatch");
+    }
+
+    if buf[0] != 1 {
+        bail!("Unsupported version: {}", buf[0]);
+    }
+    // ChunkStream | ChunkMasking | GlobalPadding, the only framing implemented
+    let opt = buf[34];
+    if opt & 0x0d != 0x0d {
+        bail!("Unsupported options: {:#x}", opt);
+    }
+    let padding_len = (buf[35] >> 4) as usize;
+    let security = match buf[35] & 0x0f {
+        0x03 => SecurityType::Aes128Gcm,
+        0x04 => SecurityType::Chacha20Poly1305,
+        s => bail!("Unsupported security type: {}", s),
+    };
+    if buf[37] != 0x01 {
+        bail!("Unsupported command: {}", buf[37]);
+    }
+    let port = u16::from_be_bytes([buf[38], buf[39]]);
+
+    let addr = &buf[CMD_FIXED_LEN..];
+    let (dest_addr, addr_len) = match buf[40] {
+        0x01 if addr.len() >= 4 => {
+            let ip: [u8; 4] = addr[..4].try_into().unwrap();
+            (DestAddr::new_ip(Ipv4Addr::from(ip), port), 4)
+        }
+        0x03 if addr.len() >= 16 => {
+            let ip: [u8; 16] = addr[..16].try_into().unwrap();
+            (DestAddr::new_ip(Ipv6Addr::from(ip), port), 16)
+        }
+        0x02 if !addr.is_empty() && addr.len() > addr[0] as usize => {
+            let len = addr[0] as usize;
+            let domain = std::str::from_utf8(&addr[1..1 + len])?;
+            (DestAddr::new_domain(domain, port), 1 + len)
+        }
+        t => bail!("Invalid address of type {}", t),
+    };
+    if addr.len() != addr_len + padding_len {
+        bail!("Request header length mismatch");
+    }
+
+    Ok(Request {
+        iv: buf[1..17].try_into().unwrap(),
+        key: buf[17..33].try_into().unwrap(),
+        auth_v: buf[33],
+        security,
+        dest_addr,
+    })
+}
+
+#[async_trait]
+impl Processor for ServerProcessor {
+    async fn process(
+        self: Arc<Self>,
+        stream: ProxyStream,
+        conn: &mut Connection,
+        _ctx: AppContextRef,
+    ) -> Result<ProxyStream> {
+        let mut stream = stream.into_tcp()?;
+
+        let mut auth = [0u8; 16];
+        stream.read_exact(&mut auth).await?;
+        let now = unix_ts().as_secs();
+
+        let (account, header, aead) = if let Some(account) = self.match_aead(&auth, now) {
+            let header = self
+                .read_aead_header(&mut stream, &account.primary.cmd_key(), auth)
+                .await?;
+            (account, header, true)
+        } else if let Some((account, ts)) = self.match_legacy(&auth, now) {
+            let header = read_legacy_header(&mut stream, &account.primary.cmd_key(), ts).await?;
+            (account, header, false)
+        } else {
+            bail!("VMess authentication failed");
+        };
+
+        let request = decode_request(&header)?;
+        conn.set_var(vars::USER, account.username.clone());
+        conn.dest_addr = request.dest_addr;
+
+        let session = Arc::new(ClientSession::with_request(
+            &account.primary,
+            request.key,
+            request.iv,
+            request.auth_v,
+            aead,
+        ));
+        let response = session.encode_response_header()?;
+
+        let reader = ClientReader::new_server(stream, session.clone(), request.security)?;
+        let writer = ClientWriter::new_server(reader, session, request.security, response)?;
+        Ok(RWPair::new(writer).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Auth and command of a legacy request from alter ID 1 at `TS`, encoded as V2Ray does.
+    const LEGACY_REQUEST: &str = concat!(
+        "fdd78905d2f83cb3f07c55f1758f407e0584d48d70fa690bff577e3d11baddbb",
+        "e756f4bfdc36b2354e40f33c02ad3de8c1593ede918066d6a28388892cc5450e",
+        "3e5a505e2fee3cf801"
+    );
+    const TS: u64 = 1_600_000_000;
+
+    #[tokio::test]
+    async fn legacy_header_of_alter_id() {
+        let processor = ServerProcessor::new(ServerConfig {
+            users: vec![UserConfig {
+                username: "user".into(),
+                user_id: "b831381d-6324-4d53-ad4f-8cda48b30811".parse().unwrap(),
+                alter_id: 1,
+            }],
+        });
+        let request = hex::decode(LEGACY_REQUEST).unwrap();
+        let auth: [u8; 16] = request[..16].try_into().unwrap();
+
+        let mut table = LegacyAuthTable::default();
+        table.update(&processor.accounts, TS);
+        let &(i, ts) = table.hashes.get(&auth).unwrap();
+        let account = &processor.accounts[i];
+        assert_eq!(ts, TS);
+        assert!(account.aead.is_none(), "matched the primary ID");
+
+        let (mut client, server) = tokio::io::duplex(256);
+        client.write_all(&request[16..]).await.unwrap();
+        let mut stream = RWPair::new(server);
+        let header = read_legacy_header(&mut stream, &account.primary.cmd_key(), ts)
+            .await
+            .unwrap();
+        let request = decode_request(&header).unwrap();
+        assert_eq!(request.dest_addr.domain.as_deref(), Some("example.com"));
+        assert_eq!(request.dest_addr.port, Some(443));
+        assert_eq!(request.security, SecurityType::Aes128Gcm);
+        assert_eq!(request.auth_v, 0x2a);
+        assert_eq!(request.iv[0], 0);
+        assert_eq!(request.key[0], 16);
+    }
+
+    #[tokio::test]
+    async fn legacy_request_of_alter_id_round_trip() {
+        let user_id: Uuid = "b831381d-6324-4d53-ad4f-8cda48b30811".parse().unwrap();
+        let processor = ServerProcessor::new(ServerConfig {
+            users: vec![UserConfig {
+                username: "user".into(),
+                user_id,
+                alter_id: 2,
+            }],
+        });
+        let primary = UserId::new(user_id);
+        let alter_ids = alter_id::new_alter_ids(&primary, 2);
+        let session = ClientSession::new(&primary, &alter_ids[1], false);
+        let mut conn = Connection::new(([127, 0, 0, 1], 1080), "test", None, TransportType::Tcp);
+        conn.dest_addr = DestAddr::new_domain("example.com", 443);
+        let request = session
+            .encode_request_header(SecurityType::Chacha20Poly1305, &conn)
+            .unwrap();
+
+        let auth: [u8; 16] = request[..16].try_into().unwrap();
+        let (account, ts) = processor.match_legacy(&auth, unix_ts().as_secs()).unwrap();
+        assert_eq!(account.id, alter_ids[1]);
+
+        let (mut client, server) = tokio::io::duplex(256);
+        client.write_all(&request[16..]).await.unwrap();
+        let header = read_legacy_header(&mut RWPair::new(server), &account.primary.cmd_key(), ts)
+            .await
+            .unwrap();
+        let request = decode_request(&header).unwrap();
+        assert_eq!(request.dest_addr.domain.as_deref(), Some("example.com"));
+        assert_eq!(request.key, session.request_key);
+    }
+}
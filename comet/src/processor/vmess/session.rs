@@ -1,10 +1,10 @@
-use crate::{crypto::hashing::sign_bytes, utils::unix_ts};
+use crate::{app::mux::is_mux_dest, crypto::hashing::sign_bytes, utils::unix_ts};
 use std::{convert::TryInto, net::IpAddr};
 
 use lz_fnv::{Fnv1a, FnvHasher};
 use rand::{thread_rng, Rng};
 
-use super::{alter_id::UserId, SecurityType};
+use super::{alter_id::UserId, crypto, SecurityType};
 use crate::{
     crypto::{
         hashing::{hash_bytes, new_hasher, HashKind, Hasher},
@@ -16,6 +16,9 @@ use crate::{
 };
 use anyhow::bail;
 
+/// Size of the sealed length preceding an AEAD response header.
+pub const AEAD_LEN_SIZE: usize = 2 + 16;
+
 #[derive(Debug, Clone)]
 pub struct ClientSession {
     auth_info: [u8; 16],
@@ -26,48 +29,63 @@ pub struct ClientSession {
     pub response_key: [u8; 16],
     pub response_iv: [u8; 16],
     auth_v: u8,
+    /// Whether the AEAD header format is used.
+    pub aead: bool,
 }
 
 impl ClientSession {
-    pub fn new(user: &UserId) -> Self {
+    /// Alter IDs are only used for the legacy auth, the rest is keyed by the primary ID.
+    pub fn new(primary: &UserId, auth_id: &UserId, aead: bool) -> Self {
         let mut rng = thread_rng();
 
         let req_key: [u8; 16] = rng.gen();
         let req_iv: [u8; 16] = rng.gen();
 
-        let res_key = hash_bytes(HashKind::Md5, &req_key[..])
-            .as_ref()
-            .try_into()
-            .unwrap();
-        let res_iv = hash_bytes(HashKind::Md5, &req_iv[..])
-            .as_ref()
-            .try_into()
-            .unwrap();
-
         let timestamp = unix_ts().as_secs().to_be_bytes();
 
-        let cmd_iv = {
-            let mut iv_hasher = new_hasher(HashKind::Md5);
-            for _ in 0..4 {
-                iv_hasher.update(&timestamp[..]);
-            }
-            iv_hasher.finish().as_ref().try_into().unwrap()
-        };
+        let mut this = Self::with_request(primary, req_key, req_iv, rng.gen(), aead);
+        this.cmd_iv = cmd_iv(&timestamp);
+        this.auth_info = sign_bytes(
+            HashKind::Md5,
+            &auth_id.uuid().as_bytes()[..],
+            &timestamp[..],
+        )
+        .as_ref()
+        .try_into()
+        .unwrap();
+        this
+    }
 
-        let auth_info = sign_bytes(HashKind::Md5, &user.uuid().as_bytes()[..], &timestamp[..])
-            .as_ref()
+    /// Session of a request received by the server.
+    pub fn with_request(
+        user: &UserId,
+        request_key: [u8; 16],
+        request_iv: [u8; 16],
+        auth_v: u8,
+        aead: bool,
+    ) -> Self {
+        let hash_kind = if aead {
+            HashKind::Sha256
+        } else {
+            HashKind::Md5
+        };
+        let response_key = hash_bytes(hash_kind, &request_key[..])[..16]
+            .try_into()
+            .unwrap();
+        let response_iv = hash_bytes(hash_kind, &request_iv[..])[..16]
             .try_into()
             .unwrap();
 
         Self {
-            auth_info,
+            auth_info: [0; 16],
             cmd_key: user.cmd_key(),
-            cmd_iv,
-            request_key: req_key,
-            request_iv: req_iv,
-            response_key: res_key,
-            response_iv: res_iv,
-            auth_v: rng.gen(),
+            cmd_iv: [0; 16],
+            request_key,
+            request_iv,
+            response_key,
+            response_iv,
+            auth_v,
+            aead,
         }
     }
 
@@ -80,7 +98,10 @@ impl ClientSession {
         */
         let mut ret =
             BytesMut::with_capacity(16 + 1 + 16 + 16 + 1 + 1 + 1 /* 4 + 4 bits */ + 1 + 1 + 2 + 1);
-        ret.put_slice(&self.auth_info); // Auth
+        if !self.aead {
+            ret.put_slice(&self.auth_info); // Auth
+        }
+        let cmd_start = ret.len();
         ret.put_u8(1); // Ver
         ret.put_slice(&self.request_iv); // IV
         ret.put_slice(&self.request_key); // Key
@@ -93,28 +114,33 @@ impl ClientSession {
             SecurityType::Chacha20Poly1305 => 0x04,
             SecurityType::Auto => bail!("Auto should not be here"),
         };
+        let mux = is_mux_dest(&conn.dest_addr);
         let cmd = match conn.typ {
+            _ if mux => 0x03,
             TransportType::Tcp => 0x01,
             TransportType::Udp => 0x02,
         };
         ret.put_slice(&[(padding_len << 4) | sec, 0, cmd]);
-        ret.put_u16(conn.dest_addr.port_or_error()?);
 
-        match (conn.dest_addr.domain.as_ref(), conn.dest_addr.ip) {
-            (Some(d), _) => {
-                ret.put_u8(0x02);
-                ret.put_u8(d.len() as u8);
-                ret.put_slice(d.as_bytes());
-            }
-            (_, Some(IpAddr::V4(ip))) => {
-                ret.put_u8(0x01);
-                ret.put_slice(&ip.octets()[..]);
+        // Mux requests carry no destination
+        if !mux {
+            ret.put_u16(conn.dest_addr.port_or_error()?);
+            match (conn.dest_addr.domain.as_ref(), conn.dest_addr.ip) {
+                (Some(d), _) => {
+                    ret.put_u8(0x02);
+                    ret.put_u8(d.len() as u8);
+                    ret.put_slice(d.as_bytes());
+                }
+                (_, Some(IpAddr::V4(ip))) => {
+                    ret.put_u8(0x01);
+                    ret.put_slice(&ip.octets()[..]);
+                }
+                (_, Some(IpAddr::V6(ip))) => {
+                    ret.put_u8(0x03);
+                    ret.put_slice(&ip.octets()[..]);
+                }
+                (None, None) => bail!("Invalid dest addr"),
             }
-            (_, Some(IpAddr::V6(ip))) => {
-                ret.put_u8(0x03);
-                ret.put_slice(&ip.octets()[..]);
-            }
-            (None, None) => bail!("Invalid dest addr"),
         }
 
         if padding_len > 0 {
@@ -125,9 +151,13 @@ impl ClientSession {
         }
 
         let mut hasher = Fnv1a::<u32>::new();
-        hasher.write(&ret[16..]);
+        hasher.write(&ret[cmd_start..]);
         ret.put_u32(hasher.finish());
 
+        if self.aead {
+            return crypto::seal_header(&self.cmd_key, &ret);
+        }
+
         let mut crypter = StreamCipherKind::Aes128Cfb.to_crypter(
             CrypterMode::Encrypt,
             &self.cmd_key,
@@ -139,18 +169,50 @@ impl ClientSession {
         Ok(ret)
     }
 
-    pub fn decode_response_header(&self, buf: &[u8]) -> Result<()> {
-        if buf.len() < 4 {
-            bail!("Buffer too short");
+    pub fn encode_response_header(&self) -> Result<BytesMut> {
+        // V, Opt, Cmd, Cmd length
+        let mut ret = BytesMut::from(&[self.auth_v, 0, 0, 0][..]);
+        if self.aead {
+            return crypto::seal_response(&self.response_key, &self.response_iv, &ret);
         }
-        let mut buf: [u8; 4] = buf[0..4].try_into()?;
 
         let mut crypter = StreamCipherKind::Aes128Cfb.to_crypter(
-            CrypterMode::Decrypt,
+            CrypterMode::Encrypt,
             &self.response_key,
             &self.response_iv,
         )?;
-        crypter.update(&mut buf)?;
+        crypter.update(&mut ret)?;
+        Ok(ret)
+    }
+
+    /// Length of the response header, given at least its first `AEAD_LEN_SIZE` bytes
+    /// in AEAD mode.
+    pub fn response_header_len(&self, buf: &[u8]) -> Result<usize> {
+        if !self.aead {
+            return Ok(4);
+        }
+        let len = crypto::open_response_length(&self.response_key, &self.response_iv, buf)?;
+        Ok(AEAD_LEN_SIZE + len + 16)
+    }
+
+    pub fn decode_response_header(&self, buf: &[u8]) -> Result<()> {
+        let mut buf = BytesMut::from(buf);
+        if self.aead {
+            let mut header = buf.split_off(AEAD_LEN_SIZE);
+            let len = crypto::open_response(&self.response_key, &self.response_iv, &mut header)?;
+            header.truncate(len);
+            buf = header;
+        } else {
+            let mut crypter = StreamCipherKind::Aes128Cfb.to_crypter(
+                CrypterMode::Decrypt,
+                &self.response_key,
+                &self.response_iv,
+            )?;
+            crypter.update(&mut buf)?;
+        }
+        if buf.len() < 4 {
+            bail!("Buffer too short");
+        }
 
         if buf[0] != self.auth_v {
             bail!("Authentication mismatch");
@@ -163,3 +225,12 @@ impl ClientSession {
         Ok(())
     }
 }
+
+/// IV of the legacy command section, `MD5(timestamp * 4)`.
+pub fn cmd_iv(timestamp: &[u8]) -> [u8; 16] {
+    let mut iv_hasher = new_hasher(HashKind::Md5);
+    for _ in 0..4 {
+        iv_hasher.update(timestamp);
+    }
+    iv_hasher.finish().as_ref().try_into().unwrap()
+}
@@ -1,28 +1,113 @@
-use futures::{future, ready, Sink, StreamExt};
-use tokio_tungstenite::client_async_with_config;
+use futures::{future, future::BoxFuture, ready, Sink, StreamExt};
+use std::{cmp::min, sync::Mutex, task::Waker, time::Duration};
+use tokio::time::{sleep, Sleep};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{
+    header::{HeaderName, HOST},
+    HeaderMap, HeaderValue, StatusCode,
+};
 use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
-use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message};
+use tokio_tungstenite::{accept_hdr_async_with_config, client_async_with_config, WebSocketStream};
 use tokio_util::io::StreamReader;
 use url::Url;
 
 use crate::prelude::*;
 use crate::utils::io::io_other_error;
+use crate::utils::prepend_io::PrependReader;
 
 pub fn register(plumber: &mut Plumber) {
     plumber.register("ws_client", |conf, _| {
-        Ok(Box::new(ClientProcessor {
+        let config: ClientConfig = from_value(conf)?;
+        Ok(Box::new(ClientProcessor::new(config)?))
+    });
+    plumber.register("ws_server", |conf, _| {
+        Ok(Box::new(ServerProcessor {
             config: from_value(conf)?,
         }))
     });
 }
 
+/// Carries V2Ray style early data, base64 encoded.
+const EARLY_DATA_HEADER: &str = "Sec-WebSocket-Protocol";
+
+fn ws_config() -> WebSocketConfig {
+    WebSocketConfig {
+        max_send_queue: Some(1),
+        ..Default::default()
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 struct ClientConfig {
     url: Url,
+    /// `Host` header, defaulting to the host of `url`.
+    host: Option<SmolStr>,
+    #[serde(default)]
+    headers: HashMap<SmolStr, SmolStr>,
+    /// Bytes of the first write to send along with the handshake, defaulting to the
+    /// `ed` query of `url`.
+    max_early_data: Option<usize>,
 }
 
 struct ClientProcessor {
-    config: ClientConfig,
+    url: Url,
+    headers: HeaderMap,
+    max_early_data: usize,
+}
+
+impl ClientProcessor {
+    fn new(config: ClientConfig) -> Result<Self> {
+        let mut url = config.url;
+        // `ed` only configures the client, the server never sees it
+        let mut ed = None;
+        let query: Vec<(String, String)> = url
+            .query_pairs()
+            .into_owned()
+            .filter(|(k, v)| {
+                if k == "ed" {
+                    ed = v.parse().ok();
+                    return false;
+                }
+                true
+            })
+            .collect();
+        if query.is_empty() {
+            url.set_query(None);
+        } else {
+            url.query_pairs_mut().clear().extend_pairs(query);
+        }
+
+        let mut headers = HeaderMap::new();
+        if let Some(host) = config.host {
+            headers.insert(HOST, HeaderValue::from_str(&host)?);
+        }
+        for (k, v) in config.headers {
+            headers.insert(
+                HeaderName::from_bytes(k.as_bytes())?,
+                HeaderValue::from_str(&v)?,
+            );
+        }
+
+        Ok(Self {
+            url,
+            headers,
+            max_early_data: config.max_early_data.or(ed).unwrap_or(0),
+        })
+    }
+
+    fn request(&self, early_data: &[u8]) -> Result<Request> {
+        let mut request = (&self.url).into_client_request()?;
+        let headers = request.headers_mut();
+        for (k, v) in &self.headers {
+            headers.insert(k, v.clone());
+        }
+        if !early_data.is_empty() {
+            let encoded = base64::encode_config(early_data, base64::URL_SAFE_NO_PAD);
+            headers.insert(EARLY_DATA_HEADER, HeaderValue::from_str(&encoded)?);
+        }
+        Ok(request)
+    }
 }
 
 #[async_trait]
@@ -35,28 +120,301 @@ impl Processor for ClientProcessor {
     ) -> Result<ProxyStream> {
         let stream = stream.into_tcp()?;
 
-        let ws_config = WebSocketConfig {
-            max_send_queue: Some(1),
-            ..Default::default()
+        if self.max_early_data > 0 {
+            return Ok(RWPair::new(EarlyDataStream::new(self, stream)).into());
+        }
+        let request = self.request(&[])?;
+        Ok(connect(request, stream).await?.into())
+    }
+}
+
+async fn connect(request: Request, stream: RWPair) -> Result<RWPair> {
+    let (socket, _) = client_async_with_config(request, stream, Some(ws_config())).await?;
+    Ok(into_rw_pair(socket))
+}
+
+/// Turns binary frames into a byte stream.
+fn into_rw_pair(socket: WebSocketStream<RWPair>) -> RWPair {
+    let (sink, stream) = socket.split();
+
+    let stream = stream.filter_map(|msg| {
+        let r = match msg {
+            Ok(Message::Binary(data)) => Some(Ok(Bytes::from(data))),
+            Ok(Message::Close(_)) | Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => None,
+            Ok(_) => Some(Err(io_other_error("unexpected message type"))),
+            Err(e) => Some(Err(io_other_error(e))),
         };
-        let (socket, _) =
-            client_async_with_config(&self.config.url, stream, Some(ws_config)).await?;
-        let (sink, stream) = socket.split();
-
-        let stream = stream.filter_map(|msg| {
-            let r = match msg {
-                Ok(Message::Binary(data)) => Some(Ok(Bytes::from(data))),
-                Ok(Message::Close(_)) => None,
-                Ok(_) => Some(Err(io_other_error("unexpected message type"))),
-                Err(e) => Some(Err(io_other_error(e))),
-            };
-            future::ready(r)
-        });
-
-        let reader = StreamReader::new(stream);
-        let writer = WsWriter { inner: sink };
-
-        Ok(RWPair::new_parts(reader, writer).into())
+        future::ready(r)
+    });
+
+    let reader = StreamReader::new(stream);
+    let writer = WsWriter { inner: sink };
+
+    RWPair::new_parts(reader, writer)
+}
+
+/// Defers the handshake to the first write, which is sent along in [`EARLY_DATA_HEADER`].
+///
+/// A read waiting longer than [`EARLY_DATA_WAIT`] starts the handshake without early data, as
+/// the server may speak first.
+struct EarlyDataStream {
+    processor: Arc<ClientProcessor>,
+    state: EarlyDataState,
+    /// Early data sent by a handshake the reader completed, not yet reported to the writer.
+    unacked_early_data: usize,
+    read_timer: Option<Pin<Box<Sleep>>>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+/// How long a read waits for a write to carry early data.
+const EARLY_DATA_WAIT: Duration = Duration::from_millis(200);
+
+enum EarlyDataState {
+    Waiting(RWPair),
+    Connecting {
+        // Mutex only makes it `Sync`
+        handshake: Mutex<BoxFuture<'static, Result<RWPair>>>,
+        early_data_len: usize,
+    },
+    Connected(RWPair),
+    Failed,
+}
+
+impl std::fmt::Debug for EarlyDataStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EarlyDataStream")
+    }
+}
+
+fn handshake_failed<T>() -> Poll<IoResult<T>> {
+    Poll::Ready(Err(io_other_error("WebSocket handshake failed")))
+}
+
+impl EarlyDataStream {
+    fn new(processor: Arc<ClientProcessor>, inner: RWPair) -> Self {
+        Self {
+            processor,
+            state: EarlyDataState::Waiting(inner),
+            unacked_early_data: 0,
+            read_timer: None,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    /// Drives the handshake, sending `buf` as early data if it has not started yet.
+    fn poll_connect(&mut self, cx: &mut std::task::Context<'_>, buf: &[u8]) -> Poll<IoResult<()>> {
+        loop {
+            match &mut self.state {
+                EarlyDataState::Waiting(_) => {
+                    let inner = match std::mem::replace(&mut self.state, EarlyDataState::Failed) {
+                        EarlyDataState::Waiting(inner) => inner,
+                        _ => unreachable!(),
+                    };
+                    let early_data_len = min(buf.len(), self.processor.max_early_data);
+                    let request = self
+                        .processor
+                        .request(&buf[..early_data_len])
+                        .map_err(io_other_error)?;
+                    self.state = EarlyDataState::Connecting {
+                        handshake: Mutex::new(Box::pin(connect(request, inner))),
+                        early_data_len,
+                    };
+                }
+                EarlyDataState::Connecting {
+                    handshake,
+                    early_data_len,
+                } => {
+                    let handshake = handshake.get_mut().unwrap();
+                    let res = ready!(handshake.as_mut().poll(cx));
+                    self.unacked_early_data = *early_data_len;
+                    // The other side may be waiting on the handshake too
+                    if let Some(waker) = self.read_waker.take() {
+                        waker.wake();
+                    }
+                    if let Some(waker) = self.write_waker.take() {
+                        waker.wake();
+                    }
+                    return Poll::Ready(match res {
+                        Ok(stream) => {
+                            self.state = EarlyDataState::Connected(stream);
+                            Ok(())
+                        }
+                        Err(e) => {
+                            self.state = EarlyDataState::Failed;
+                            Err(io_other_error(e))
+                        }
+                    });
+                }
+                EarlyDataState::Connected(_) => return Poll::Ready(Ok(())),
+                EarlyDataState::Failed => return handshake_failed(),
+            }
+        }
+    }
+}
+
+impl AsyncRead for EarlyDataStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        let me = &mut *self;
+        loop {
+            match &mut me.state {
+                EarlyDataState::Connected(stream) => return Pin::new(stream).poll_read(cx, buf),
+                EarlyDataState::Failed => return handshake_failed(),
+                EarlyDataState::Waiting(_) => {
+                    let timer = me
+                        .read_timer
+                        .get_or_insert_with(|| Box::pin(sleep(EARLY_DATA_WAIT)));
+                    if std::future::Future::poll(timer.as_mut(), cx).is_pending() {
+                        // A write may start the handshake first
+                        me.read_waker = Some(cx.waker().clone());
+                        return Poll::Pending;
+                    }
+                    ready!(me.poll_connect(cx, &[]))?;
+                }
+                EarlyDataState::Connecting { .. } => {
+                    me.read_waker = Some(cx.waker().clone());
+                    ready!(me.poll_connect(cx, &[]))?;
+                }
+            }
+        }
+    }
+}
+
+impl AsyncWrite for EarlyDataStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, futures_io::Error>> {
+        let me = &mut *self;
+        loop {
+            match &mut me.state {
+                EarlyDataState::Connected(stream) => {
+                    if me.unacked_early_data > 0 {
+                        // `buf` starts with what the handshake sent
+                        return Poll::Ready(Ok(std::mem::take(&mut me.unacked_early_data)));
+                    }
+                    return Pin::new(stream).poll_write(cx, buf);
+                }
+                EarlyDataState::Failed => return handshake_failed(),
+                _ => {
+                    me.write_waker = Some(cx.waker().clone());
+                    ready!(me.poll_connect(cx, buf))?;
+                }
+            }
+        }
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), futures_io::Error>> {
+        match &mut self.state {
+            EarlyDataState::Connected(stream) => Pin::new(stream).poll_flush(cx),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), futures_io::Error>> {
+        let me = &mut *self;
+        match &mut me.state {
+            // Never connected, nothing to close
+            EarlyDataState::Waiting(inner) => Pin::new(inner).poll_shutdown(cx),
+            EarlyDataState::Connected(stream) => Pin::new(stream).poll_shutdown(cx),
+            _ => {
+                ready!(me.poll_connect(cx, &[]))?;
+                Pin::new(me).poll_shutdown(cx)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ServerConfig {
+    #[serde(default = "default_path")]
+    path: SmolStr,
+    /// Accepted `Host`, any if unset.
+    host: Option<SmolStr>,
+}
+
+fn default_path() -> SmolStr {
+    "/".into()
+}
+
+struct ServerProcessor {
+    config: ServerConfig,
+}
+
+impl ServerProcessor {
+    fn check_request(&self, req: &Request) -> bool {
+        if req.uri().path() != self.config.path {
+            return false;
+        }
+        let host = match &self.config.host {
+            Some(host) => host,
+            None => return true,
+        };
+        let req_host = req
+            .headers()
+            .get(HOST)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or_default();
+        // Ignoring the port
+        req_host == host
+            || matches!(req_host.strip_prefix(host.as_str()), Some(port) if port.starts_with(':'))
+    }
+}
+
+#[async_trait]
+impl Processor for ServerProcessor {
+    // The callback's error type is tungstenite's
+    #[allow(clippy::result_large_err)]
+    async fn process(
+        self: Arc<Self>,
+        stream: ProxyStream,
+        _conn: &mut Connection,
+        _ctx: AppContextRef,
+    ) -> Result<ProxyStream> {
+        let stream = stream.into_tcp()?;
+
+        let mut early_data = None;
+        let callback = |req: &Request, mut res: Response| -> Result<Response, ErrorResponse> {
+            if !self.check_request(req) {
+                let mut err = ErrorResponse::new(None);
+                *err.status_mut() = StatusCode::NOT_FOUND;
+                return Err(err);
+            }
+            // Not early data unless it decodes, as it may be an actual subprotocol
+            if let Some(protocol) = req.headers().get(EARLY_DATA_HEADER) {
+                if let Ok(data) =
+                    base64::decode_config(protocol.as_bytes(), base64::URL_SAFE_NO_PAD)
+                {
+                    res.headers_mut()
+                        .insert(EARLY_DATA_HEADER, protocol.clone());
+                    early_data = Some(data);
+                }
+            }
+            Ok(res)
+        };
+        let socket = accept_hdr_async_with_config(stream, callback, Some(ws_config())).await?;
+
+        let stream = into_rw_pair(socket);
+        Ok(match early_data {
+            Some(data) => {
+                let (reader, writer) = stream.split();
+                RWPair::new_parts(PrependReader::new(reader, data.as_slice()), writer)
+            }
+            None => stream,
+        }
+        .into())
     }
 }
 
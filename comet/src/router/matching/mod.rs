@@ -7,7 +7,7 @@ use std::{net::IpAddr, str::FromStr};
 use tokio_stream::StreamExt;
 
 mod domain;
-use domain::DomainCondition;
+pub use domain::DomainCondition;
 
 /// This is used to hint the matchers that only the specified
 /// properties of a connection should be concerned.
@@ -54,6 +54,12 @@ pub enum MatchCondition {
     Transport(TransportType),
 
     InboundName(SmolStr),
+    /// Username authenticated by the inbound.
+    User(SmolStr),
+    /// SNI received by `tls_server`.
+    TlsSni(SmolStr),
+    /// ALPN protocol negotiated by `tls_server`.
+    TlsAlpn(SmolStr),
     Provider(ProviderCondition),
 }
 
@@ -95,6 +101,11 @@ impl MatchCondition {
                 }
                 MatchCondition::Transport(t) => &conn.typ == t,
                 MatchCondition::InboundName(name) => &conn.inbound_tag == name,
+                MatchCondition::User(name) => conn.get_var::<SmolStr>(vars::USER) == Some(name),
+                MatchCondition::TlsSni(sni) => conn.get_var::<SmolStr>(vars::TLS_SNI) == Some(sni),
+                MatchCondition::TlsAlpn(alpn) => {
+                    conn.get_var::<SmolStr>(vars::TLS_ALPN) == Some(alpn)
+                }
                 MatchCondition::DestPort(cond) => {
                     if let Some(port) = &conn.dest_addr.port {
                         return cond.is_match(*port);
@@ -151,6 +162,9 @@ impl MatchCondition {
 
                 MatchCondition::Transport(_) => false,
                 MatchCondition::InboundName(_) => false,
+                MatchCondition::User(_) => false,
+                MatchCondition::TlsSni(_) => false,
+                MatchCondition::TlsAlpn(_) => false,
                 MatchCondition::DestPort(_) => false,
                 MatchCondition::SrcIp(_) => false,
             }
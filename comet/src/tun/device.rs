@@ -0,0 +1,97 @@
+use crate::prelude::*;
+use libc::{c_int, c_short, c_ulong, ifreq, sockaddr, sockaddr_in};
+use socket2::{Domain, Socket, Type};
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::Ipv4Addr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use tokio::io::unix::AsyncFd;
+
+const TUNSETIFF: c_ulong = 0x4004_54ca;
+
+pub struct TunDevice {
+    file: AsyncFd<File>,
+}
+
+fn ioctl(fd: c_int, request: c_ulong, req: &mut ifreq) -> IoResult<()> {
+    if unsafe { libc::ioctl(fd, request as _, req as *mut ifreq) } < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn new_ifreq(name: &str) -> IoResult<ifreq> {
+    if name.len() >= libc::IFNAMSIZ {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Interface name too long",
+        ));
+    }
+    let mut req: ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in req.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as _;
+    }
+    Ok(req)
+}
+
+fn to_sockaddr(addr: Ipv4Addr) -> sockaddr {
+    let mut sin: sockaddr_in = unsafe { std::mem::zeroed() };
+    sin.sin_family = libc::AF_INET as _;
+    sin.sin_addr.s_addr = u32::from(addr).to_be();
+    unsafe { std::mem::transmute(sin) }
+}
+
+impl TunDevice {
+    /// Creates the interface and brings it up with `address/netmask`.
+    pub fn open(name: &str, address: Ipv4Addr, netmask: Ipv4Addr, mtu: u16) -> IoResult<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/net/tun")?;
+        let mut req = new_ifreq(name)?;
+        req.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as c_short;
+        ioctl(file.as_raw_fd(), TUNSETIFF, &mut req)?;
+
+        let ctl = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+        let fd = ctl.as_raw_fd();
+        let mut req = new_ifreq(name)?;
+        req.ifr_ifru.ifru_addr = to_sockaddr(address);
+        ioctl(fd, libc::SIOCSIFADDR, &mut req)?;
+        req.ifr_ifru.ifru_netmask = to_sockaddr(netmask);
+        ioctl(fd, libc::SIOCSIFNETMASK, &mut req)?;
+        req.ifr_ifru.ifru_mtu = mtu as c_int;
+        ioctl(fd, libc::SIOCSIFMTU, &mut req)?;
+        ioctl(fd, libc::SIOCGIFFLAGS, &mut req)?;
+        unsafe {
+            req.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as c_short;
+        }
+        ioctl(fd, libc::SIOCSIFFLAGS, &mut req)?;
+
+        Ok(Self {
+            file: AsyncFd::new(file)?,
+        })
+    }
+
+    pub async fn recv(&self, buf: &mut [u8]) -> IoResult<usize> {
+        loop {
+            let mut guard = self.file.readable().await?;
+            match guard.try_io(|file| file.get_ref().read(buf)) {
+                Ok(res) => return res,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    pub async fn send(&self, buf: &[u8]) -> IoResult<()> {
+        loop {
+            let mut guard = self.file.writable().await?;
+            match guard.try_io(|file| file.get_ref().write(buf)) {
+                Ok(res) => return res.map(|_| ()),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
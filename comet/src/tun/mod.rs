@@ -0,0 +1,119 @@
+//! TUN inbound for Linux, terminating the TCP and UDP routed to the interface in userspace.
+//!
+//! Packets of both IP families go through the stack in [`stack`], which hands each TCP
+//! connection and UDP flow to the dispatcher with its original source and destination. Fake IPs,
+//! including IPv6 ones, are mapped back to domains by the dispatcher.
+//!
+//! Notes on routing:
+//! * The interface only gets an IPv4 address, IPv6 traffic is captured by routing it to the
+//!   interface, e.g. `ip -6 route add default dev comet0`.
+//! * Replies to forwarded hosts are written back to the interface, so forwarding them needs
+//!   `ip_forward` and a loose `rp_filter` on it.
+//! * Comet's own connections loop back into the interface if the default route points there.
+//!   Set `outbound_socket.mark` with a policy routing rule, or `outbound_socket.bind_device`.
+#![cfg(target_os = "linux")]
+use crate::app::inbound_manager::ConnSender;
+use crate::config::Inbound;
+use crate::prelude::*;
+use crate::utils::metered_stream::MeteredStream;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+pub mod device;
+pub mod packet;
+pub mod stack;
+pub mod tcp;
+
+use device::TunDevice;
+use stack::{Accepted, Stack};
+
+const GC_INTERVAL: Duration = Duration::from_secs(30);
+
+pub struct TunInbound {
+    device: TunDevice,
+    mtu: u16,
+}
+
+impl TunInbound {
+    /// Creates `name` with `address/30`.
+    pub async fn new(name: &str, address: Ipv4Addr, mtu: u16) -> Result<Self> {
+        let device = TunDevice::open(name, address, Ipv4Addr::new(255, 255, 255, 252), mtu)?;
+        Ok(Self { device, mtu })
+    }
+
+    pub async fn serve(
+        self,
+        tag: SmolStr,
+        inbound: Inbound,
+        sender: ConnSender<ProxyStream>,
+        ctx: AppContextRef,
+    ) {
+        let (mut stack, mut out, accepted) = Stack::new(self.mtu);
+        let device = Arc::new(self.device);
+        tokio::spawn(handle_accepted(accepted, tag.clone(), inbound, sender, ctx));
+
+        let device_clone = device.clone();
+        let tag_clone = tag.clone();
+        tokio::spawn(async move {
+            while let Some(packet) = out.recv().await {
+                if let Err(e) = device_clone.send(&packet).await {
+                    warn!("Inbound {}/TUN failed to write: {}", tag_clone, e);
+                }
+            }
+        });
+
+        let mut gc = tokio::time::interval(GC_INTERVAL);
+        let mut buffer = vec![0u8; self.mtu as usize];
+        loop {
+            let n = tokio::select! {
+                res = device.recv(&mut buffer) => match res {
+                    Ok(n) => n,
+                    Err(e) => {
+                        error!("Inbound {}/TUN failed to read: {}", tag, e);
+                        break;
+                    }
+                },
+                _ = gc.tick() => {
+                    stack.gc();
+                    continue;
+                }
+            };
+            if let Err(e) = stack.input(&buffer[..n]) {
+                trace!("Dropping TUN packet: {}", e);
+            }
+        }
+    }
+}
+
+async fn handle_accepted(
+    mut accepted: UnboundedReceiver<Accepted>,
+    tag: SmolStr,
+    inbound: Inbound,
+    sender: ConnSender<ProxyStream>,
+    ctx: AppContextRef,
+) {
+    while let Some(flow) = accepted.recv().await {
+        let (src, dest, typ, stream): (_, _, _, ProxyStream) = match flow {
+            Accepted::Tcp { src, dest, stream } => {
+                let stream = if inbound.metering {
+                    RWPair::new(MeteredStream::new_inbound(stream, &tag, &ctx))
+                } else {
+                    RWPair::new(stream)
+                };
+                (src, dest, TransportType::Tcp, stream.into())
+            }
+            Accepted::Udp { src, dest, stream } => (src, dest, TransportType::Udp, stream.into()),
+        };
+
+        let mut conn = Connection::new(src, tag.clone(), inbound.pipeline.clone(), typ);
+        conn.dest_addr = DestAddr::new_ip(dest.ip(), dest.port());
+        info!(
+            "({}) Inbound {}/TUN accepted {} to {}",
+            conn.id, tag, typ, conn.dest_addr
+        );
+        if sender.send((conn, stream)).is_err() {
+            break;
+        }
+    }
+}
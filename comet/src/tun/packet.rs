@@ -0,0 +1,353 @@
+//! Parsing and building of the IPv4, IPv6, TCP and UDP headers handled by the stack.
+use crate::prelude::*;
+use anyhow::bail;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+
+const TTL: u8 = 64;
+
+/// Transport layer of an IP packet, borrowing its payload.
+pub struct IpPacket<'a> {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub proto: u8,
+    pub payload: &'a [u8],
+}
+
+/// TCP header fields used by the stack, with the MSS option if any.
+#[derive(Debug, Clone, Default)]
+pub struct Segment {
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub mss: Option<u16>,
+    pub payload: Bytes,
+}
+
+impl Segment {
+    /// Sequence space taken by the segment, SYN and FIN counting as one.
+    pub fn seq_len(&self) -> u32 {
+        self.payload.len() as u32
+            + (self.flags & TCP_SYN != 0) as u32
+            + (self.flags & TCP_FIN != 0) as u32
+    }
+}
+
+pub fn parse_ip(buf: &[u8]) -> Result<IpPacket<'_>> {
+    match buf.first().map(|b| b >> 4) {
+        Some(4) => parse_ipv4(buf),
+        Some(6) => parse_ipv6(buf),
+        _ => bail!("Not an IP packet"),
+    }
+}
+
+fn parse_ipv4(buf: &[u8]) -> Result<IpPacket<'_>> {
+    if buf.len() < 20 {
+        bail!("IPv4 packet too short");
+    }
+    let ihl = (buf[0] & 0x0f) as usize * 4;
+    let total_len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    if ihl < 20 || total_len < ihl || total_len > buf.len() {
+        bail!("Invalid IPv4 header");
+    }
+    // More fragments or a non-zero offset
+    if u16::from_be_bytes([buf[6], buf[7]]) & 0x3fff != 0 {
+        bail!("IPv4 fragments are not supported");
+    }
+    Ok(IpPacket {
+        src: Ipv4Addr::new(buf[12], buf[13], buf[14], buf[15]).into(),
+        dst: Ipv4Addr::new(buf[16], buf[17], buf[18], buf[19]).into(),
+        proto: buf[9],
+        payload: &buf[ihl..total_len],
+    })
+}
+
+fn parse_ipv6(buf: &[u8]) -> Result<IpPacket<'_>> {
+    if buf.len() < 40 {
+        bail!("IPv6 packet too short");
+    }
+    let payload_len = u16::from_be_bytes([buf[4], buf[5]]) as usize;
+    if 40 + payload_len > buf.len() {
+        bail!("Invalid IPv6 header");
+    }
+    let mut src = [0u8; 16];
+    src.copy_from_slice(&buf[8..24]);
+    let mut dst = [0u8; 16];
+    dst.copy_from_slice(&buf[24..40]);
+    // Extension headers are not followed, packets with them are dropped by protocol
+    Ok(IpPacket {
+        src: Ipv6Addr::from(src).into(),
+        dst: Ipv6Addr::from(dst).into(),
+        proto: buf[6],
+        payload: &buf[40..40 + payload_len],
+    })
+}
+
+/// Ports and segment of a TCP packet, checking its checksum.
+pub fn parse_tcp(ip: &IpPacket) -> Result<(u16, u16, Segment)> {
+    let buf = ip.payload;
+    if buf.len() < 20 {
+        bail!("TCP header too short");
+    }
+    let data_offset = (buf[12] >> 4) as usize * 4;
+    if data_offset < 20 || data_offset > buf.len() {
+        bail!("Invalid TCP header");
+    }
+    if checksum(buf, pseudo_sum(ip.src, ip.dst, PROTO_TCP, buf.len())) != 0 {
+        bail!("Invalid TCP checksum");
+    }
+
+    let mut mss = None;
+    let mut options = &buf[20..data_offset];
+    while let Some(&kind) = options.first() {
+        match kind {
+            0 => break,
+            1 => options = &options[1..],
+            _ => {
+                let len = match options.get(1) {
+                    Some(&len) if len >= 2 && len as usize <= options.len() => len as usize,
+                    _ => break,
+                };
+                if kind == 2 && len == 4 {
+                    mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                }
+                options = &options[len..];
+            }
+        }
+    }
+
+    Ok((
+        u16::from_be_bytes([buf[0], buf[1]]),
+        u16::from_be_bytes([buf[2], buf[3]]),
+        Segment {
+            seq: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            ack: u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]),
+            flags: buf[13],
+            window: u16::from_be_bytes([buf[14], buf[15]]),
+            mss,
+            payload: Bytes::copy_from_slice(&buf[data_offset..]),
+        },
+    ))
+}
+
+/// Ports and payload of a UDP packet, checking its checksum if present.
+pub fn parse_udp<'a>(ip: &IpPacket<'a>) -> Result<(u16, u16, &'a [u8])> {
+    let buf = ip.payload;
+    if buf.len() < 8 {
+        bail!("UDP header too short");
+    }
+    let len = u16::from_be_bytes([buf[4], buf[5]]) as usize;
+    if len < 8 || len > buf.len() {
+        bail!("Invalid UDP header");
+    }
+    let has_checksum = buf[6] != 0 || buf[7] != 0;
+    if has_checksum && checksum(&buf[..len], pseudo_sum(ip.src, ip.dst, PROTO_UDP, len)) != 0 {
+        bail!("Invalid UDP checksum");
+    }
+    Ok((
+        u16::from_be_bytes([buf[0], buf[1]]),
+        u16::from_be_bytes([buf[2], buf[3]]),
+        &buf[8..len],
+    ))
+}
+
+pub fn build_tcp(src: SocketAddr, dst: SocketAddr, seg: &Segment) -> Vec<u8> {
+    let header_len = if seg.mss.is_some() { 24 } else { 20 };
+    let mut l4 = Vec::with_capacity(header_len + seg.payload.len());
+    l4.extend_from_slice(&src.port().to_be_bytes());
+    l4.extend_from_slice(&dst.port().to_be_bytes());
+    l4.extend_from_slice(&seg.seq.to_be_bytes());
+    l4.extend_from_slice(&seg.ack.to_be_bytes());
+    l4.push((header_len as u8 / 4) << 4);
+    l4.push(seg.flags);
+    l4.extend_from_slice(&seg.window.to_be_bytes());
+    // Checksum and urgent pointer
+    l4.extend_from_slice(&[0; 4]);
+    if let Some(mss) = seg.mss {
+        l4.extend_from_slice(&[2, 4]);
+        l4.extend_from_slice(&mss.to_be_bytes());
+    }
+    l4.extend_from_slice(&seg.payload);
+    build_ip(src.ip(), dst.ip(), PROTO_TCP, l4, 16)
+}
+
+pub fn build_udp(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut l4 = Vec::with_capacity(8 + payload.len());
+    l4.extend_from_slice(&src.port().to_be_bytes());
+    l4.extend_from_slice(&dst.port().to_be_bytes());
+    l4.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+    l4.extend_from_slice(&[0; 2]);
+    l4.extend_from_slice(payload);
+    build_ip(src.ip(), dst.ip(), PROTO_UDP, l4, 6)
+}
+
+/// Fills the checksum of `l4` at `checksum_at` and prepends the IP header.
+fn build_ip(src: IpAddr, dst: IpAddr, proto: u8, mut l4: Vec<u8>, checksum_at: usize) -> Vec<u8> {
+    let mut sum = checksum(&l4, pseudo_sum(src, dst, proto, l4.len()));
+    if sum == 0 && proto == PROTO_UDP {
+        // Zero means no checksum for UDP
+        sum = 0xffff;
+    }
+    l4[checksum_at..checksum_at + 2].copy_from_slice(&sum.to_be_bytes());
+
+    let mut packet = match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut header = [0u8; 20];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&((20 + l4.len()) as u16).to_be_bytes());
+            // Don't fragment
+            header[6] = 0x40;
+            header[8] = TTL;
+            header[9] = proto;
+            header[12..16].copy_from_slice(&src.octets());
+            header[16..20].copy_from_slice(&dst.octets());
+            let sum = checksum(&header, 0);
+            header[10..12].copy_from_slice(&sum.to_be_bytes());
+            header.to_vec()
+        }
+        (src, dst) => {
+            let mut header = [0u8; 40];
+            header[0] = 0x60;
+            header[4..6].copy_from_slice(&(l4.len() as u16).to_be_bytes());
+            header[6] = proto;
+            header[7] = TTL;
+            header[8..24].copy_from_slice(&to_ipv6(src).octets());
+            header[24..40].copy_from_slice(&to_ipv6(dst).octets());
+            header.to_vec()
+        }
+    };
+    packet.extend_from_slice(&l4);
+    packet
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Partial sum of the pseudo header for the transport checksum.
+fn pseudo_sum(src: IpAddr, dst: IpAddr, proto: u8, len: usize) -> u32 {
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let sum = sum_words(&src.octets(), 0);
+            sum_words(&dst.octets(), sum) + proto as u32 + len as u32
+        }
+        (src, dst) => {
+            let sum = sum_words(&to_ipv6(src).octets(), 0);
+            let sum = sum_words(&to_ipv6(dst).octets(), sum);
+            sum_words(&(len as u32).to_be_bytes(), sum) + proto as u32
+        }
+    }
+}
+
+fn sum_words(data: &[u8], mut sum: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+/// Internet checksum of `data`, continuing from a partial sum.
+fn checksum(data: &[u8], initial: u32) -> u16 {
+    let mut sum = sum_words(data, initial);
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_header_checksum() {
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(checksum(&header, 0), 0xb861);
+    }
+
+    #[test]
+    fn tcp_round_trip() {
+        for (src, dst) in [
+            (addr("10.0.0.1:40000"), addr("1.1.1.1:443")),
+            (addr("[fd00::1]:40000"), addr("[2606:4700::1111]:443")),
+        ] {
+            let seg = Segment {
+                seq: 1,
+                ack: 2,
+                flags: TCP_SYN | TCP_ACK,
+                window: 1000,
+                mss: Some(1400),
+                // Odd length
+                payload: Bytes::from_static(b"hello"),
+            };
+            let packet = build_tcp(src, dst, &seg);
+            let ip = parse_ip(&packet).unwrap();
+            assert_eq!((ip.src, ip.dst, ip.proto), (src.ip(), dst.ip(), PROTO_TCP));
+            let (src_port, dst_port, parsed) = parse_tcp(&ip).unwrap();
+            assert_eq!((src_port, dst_port), (src.port(), dst.port()));
+            assert_eq!(
+                (parsed.seq, parsed.ack, parsed.flags),
+                (1, 2, TCP_SYN | TCP_ACK)
+            );
+            assert_eq!((parsed.window, parsed.mss), (1000, Some(1400)));
+            assert_eq!(&parsed.payload[..], b"hello");
+            assert_eq!(parsed.seq_len(), 6);
+        }
+    }
+
+    #[test]
+    fn udp_round_trip() {
+        for (src, dst) in [
+            (addr("192.168.1.5:5000"), addr("8.8.8.8:53")),
+            (addr("[fd00::5]:5000"), addr("[2001:4860:4860::8888]:53")),
+        ] {
+            let packet = build_udp(src, dst, b"query");
+            let ip = parse_ip(&packet).unwrap();
+            assert_eq!((ip.src, ip.dst, ip.proto), (src.ip(), dst.ip(), PROTO_UDP));
+            let (src_port, dst_port, payload) = parse_udp(&ip).unwrap();
+            assert_eq!((src_port, dst_port), (src.port(), dst.port()));
+            assert_eq!(payload, b"query");
+        }
+    }
+
+    #[test]
+    fn corrupted_packets_are_rejected() {
+        let seg = Segment {
+            flags: TCP_ACK,
+            payload: Bytes::from_static(b"data"),
+            ..Default::default()
+        };
+        let mut packet = build_tcp(addr("10.0.0.1:1"), addr("10.0.0.2:2"), &seg);
+        let last = packet.len() - 1;
+        packet[last] ^= 1;
+        assert!(parse_tcp(&parse_ip(&packet).unwrap()).is_err());
+
+        let mut fragment = build_udp(addr("10.0.0.1:1"), addr("10.0.0.2:2"), b"data");
+        // More fragments
+        fragment[6] |= 0x20;
+        assert!(parse_ip(&fragment).is_err());
+    }
+}
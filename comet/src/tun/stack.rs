@@ -0,0 +1,367 @@
+//! Userspace TCP/IP stack terminating the TCP and UDP flows of IPv4 and IPv6 packets.
+//!
+//! Packets are fed to [`Stack::input`], and those it sends back are queued to be written to the
+//! device. Every new flow is handed out with its original source and destination.
+use super::packet::{self, Segment, PROTO_TCP, PROTO_UDP, TCP_ACK, TCP_RST, TCP_SYN};
+use super::tcp;
+use crate::prelude::*;
+use anyhow::bail;
+use std::net::SocketAddr;
+use tokio::io::DuplexStream;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, unbounded_channel, Sender, UnboundedReceiver, UnboundedSender};
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Segments queued per TCP connection, more are dropped like on a congested link.
+const SEGMENT_QUEUE: usize = 256;
+const UDP_QUEUE: usize = 64;
+
+pub enum Accepted {
+    Tcp {
+        src: SocketAddr,
+        dest: SocketAddr,
+        stream: DuplexStream,
+    },
+    Udp {
+        src: SocketAddr,
+        dest: SocketAddr,
+        stream: UdpStream,
+    },
+}
+
+type FlowKey = (SocketAddr, SocketAddr);
+
+pub struct Stack {
+    mtu: usize,
+    out: UnboundedSender<Vec<u8>>,
+    accepted: UnboundedSender<Accepted>,
+    tcp: HashMap<FlowKey, Sender<Segment>>,
+    udp: HashMap<FlowKey, Sender<UdpPacket>>,
+}
+
+impl Stack {
+    /// Returns the stack with the receivers of packets to write and of accepted flows.
+    pub fn new(
+        mtu: u16,
+    ) -> (
+        Self,
+        UnboundedReceiver<Vec<u8>>,
+        UnboundedReceiver<Accepted>,
+    ) {
+        let (out, out_receiver) = unbounded_channel();
+        let (accepted, accepted_receiver) = unbounded_channel();
+        let stack = Self {
+            mtu: mtu as usize,
+            out,
+            accepted,
+            tcp: HashMap::new(),
+            udp: HashMap::new(),
+        };
+        (stack, out_receiver, accepted_receiver)
+    }
+
+    pub fn input(&mut self, buf: &[u8]) -> Result<()> {
+        let ip = packet::parse_ip(buf)?;
+        match ip.proto {
+            PROTO_TCP => {
+                let (src_port, dst_port, seg) = packet::parse_tcp(&ip)?;
+                self.input_tcp(
+                    SocketAddr::new(ip.src, src_port),
+                    SocketAddr::new(ip.dst, dst_port),
+                    seg,
+                );
+            }
+            PROTO_UDP => {
+                let (src_port, dst_port, payload) = packet::parse_udp(&ip)?;
+                self.input_udp(
+                    SocketAddr::new(ip.src, src_port),
+                    SocketAddr::new(ip.dst, dst_port),
+                    payload,
+                );
+            }
+            proto => bail!("Unsupported protocol {}", proto),
+        }
+        Ok(())
+    }
+
+    /// Forgets flows that have ended.
+    pub fn gc(&mut self) {
+        self.tcp.retain(|_, s| !s.is_closed());
+        self.udp.retain(|_, s| !s.is_closed());
+    }
+
+    fn input_tcp(&mut self, src: SocketAddr, dest: SocketAddr, mut seg: Segment) {
+        let key = (src, dest);
+        if let Some(sender) = self.tcp.get(&key) {
+            match sender.try_send(seg) {
+                Ok(()) | Err(TrySendError::Full(_)) => return,
+                Err(TrySendError::Closed(s)) => {
+                    self.tcp.remove(&key);
+                    seg = s;
+                }
+            }
+        }
+
+        if seg.flags & (TCP_SYN | TCP_ACK | TCP_RST) != TCP_SYN {
+            if seg.flags & TCP_RST == 0 {
+                self.reset(src, dest, &seg);
+            }
+            return;
+        }
+        // TCP and IP headers without options
+        let header_len = if dest.is_ipv4() { 40 } else { 60 };
+        let local_mss = self.mtu.saturating_sub(header_len).max(1);
+
+        let (sender, receiver) = channel(SEGMENT_QUEUE);
+        self.tcp.insert(key, sender);
+        let accepted = self.accepted.clone();
+        tokio::spawn(tcp::serve(
+            dest,
+            src,
+            seg,
+            local_mss,
+            receiver,
+            self.out.clone(),
+            move |stream| {
+                let _ = accepted.send(Accepted::Tcp { src, dest, stream });
+            },
+        ));
+    }
+
+    /// Answers a segment of an unknown connection.
+    fn reset(&self, src: SocketAddr, dest: SocketAddr, seg: &Segment) {
+        let rst = if seg.flags & TCP_ACK != 0 {
+            Segment {
+                seq: seg.ack,
+                flags: TCP_RST,
+                ..Default::default()
+            }
+        } else {
+            Segment {
+                ack: seg.seq.wrapping_add(seg.seq_len()),
+                flags: TCP_RST | TCP_ACK,
+                ..Default::default()
+            }
+        };
+        let _ = self.out.send(packet::build_tcp(dest, src, &rst));
+    }
+
+    fn input_udp(&mut self, src: SocketAddr, dest: SocketAddr, payload: &[u8]) {
+        let key = (src, dest);
+        let mut packet = UdpPacket::new(dest, BytesMut::from(payload));
+        if let Some(sender) = self.udp.get(&key) {
+            match sender.try_send(packet) {
+                Ok(()) | Err(TrySendError::Full(_)) => return,
+                // Receiver dropped
+                Err(TrySendError::Closed(p)) => {
+                    self.udp.remove(&key);
+                    packet = p;
+                }
+            }
+        }
+
+        let (read_sender, read_receiver) = channel(UDP_QUEUE);
+        let (write_sender, mut write_receiver) = channel::<UdpPacket>(UDP_QUEUE);
+        let out = self.out.clone();
+        tokio::spawn(async move {
+            // Replies all appear to come from the original destination
+            while let Some(packet) = write_receiver.recv().await {
+                if out.send(packet::build_udp(dest, src, &packet)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let _ = read_sender.try_send(packet);
+        self.udp.insert(key, read_sender);
+        let stream = UdpStream::new(ReceiverStream::new(read_receiver), write_sender);
+        let _ = self.accepted.send(Accepted::Udp { src, dest, stream });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tun::packet::{TCP_FIN, TCP_PSH};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const CLIENT: &str = "10.0.0.1:40000";
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    /// Client side of a TCP connection through the stack.
+    struct Client {
+        src: SocketAddr,
+        dest: SocketAddr,
+        seq: u32,
+        ack: u32,
+    }
+
+    impl Client {
+        fn send(&mut self, stack: &mut Stack, flags: u8, payload: &[u8]) {
+            let seg = Segment {
+                seq: self.seq,
+                ack: self.ack,
+                flags,
+                window: 65535,
+                mss: None,
+                payload: Bytes::copy_from_slice(payload),
+            };
+            self.seq = self.seq.wrapping_add(seg.seq_len());
+            stack
+                .input(&packet::build_tcp(self.src, self.dest, &seg))
+                .unwrap();
+        }
+
+        async fn recv(&mut self, out: &mut UnboundedReceiver<Vec<u8>>) -> Segment {
+            let buf = out.recv().await.unwrap();
+            let ip = packet::parse_ip(&buf).unwrap();
+            assert_eq!((ip.src, ip.dst), (self.dest.ip(), self.src.ip()));
+            let (_, _, seg) = packet::parse_tcp(&ip).unwrap();
+            if seg.flags & TCP_SYN != 0 {
+                self.ack = seg.seq.wrapping_add(1);
+            } else {
+                self.ack = seg.seq.wrapping_add(seg.seq_len());
+            }
+            seg
+        }
+    }
+
+    #[tokio::test]
+    async fn tcp_connection() {
+        let src = addr("[fd00::1]:40000");
+        let dest = addr("[2606:4700::1111]:443");
+        let (mut stack, mut out, mut accepted) = Stack::new(1500);
+        let mut client = Client {
+            src,
+            dest,
+            seq: 100,
+            ack: 0,
+        };
+
+        client.send(&mut stack, TCP_SYN, &[]);
+        let syn_ack = client.recv(&mut out).await;
+        assert_eq!(syn_ack.flags, TCP_SYN | TCP_ACK);
+        assert_eq!(syn_ack.ack, 101);
+        assert_eq!(syn_ack.mss, Some(1440));
+
+        client.send(&mut stack, TCP_ACK, b"ping");
+        let mut stream = match accepted.recv().await.unwrap() {
+            Accepted::Tcp {
+                src: s,
+                dest: d,
+                stream,
+            } => {
+                assert_eq!((s, d), (src, dest));
+                stream
+            }
+            Accepted::Udp { .. } => panic!("Expected TCP"),
+        };
+        assert_eq!(client.recv(&mut out).await.ack, 105);
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        stream.write_all(b"pong").await.unwrap();
+        let data = client.recv(&mut out).await;
+        assert_eq!(data.flags, TCP_ACK | TCP_PSH);
+        assert_eq!(&data.payload[..], b"pong");
+
+        // Closed by the client, then by the dispatcher
+        client.send(&mut stack, TCP_FIN | TCP_ACK, &[]);
+        assert_eq!(client.recv(&mut out).await.ack, client.seq);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        drop(stream);
+        let fin = client.recv(&mut out).await;
+        assert_eq!(fin.flags, TCP_FIN | TCP_ACK);
+        client.send(&mut stack, TCP_ACK, &[]);
+
+        // Retransmitted FINs are still acknowledged in TIME_WAIT
+        client.seq -= 1;
+        client.send(&mut stack, TCP_FIN | TCP_ACK, &[]);
+        assert_eq!(client.recv(&mut out).await.flags, TCP_ACK);
+        assert_eq!(stack.tcp.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reset_connections_are_freed() {
+        let (mut stack, mut out, mut accepted) = Stack::new(1500);
+        let mut client = Client {
+            src: addr(CLIENT),
+            dest: addr("1.1.1.1:443"),
+            seq: 1,
+            ack: 0,
+        };
+        client.send(&mut stack, TCP_SYN, &[]);
+        client.recv(&mut out).await;
+        client.send(&mut stack, TCP_ACK, &[]);
+        let _stream = accepted.recv().await.unwrap();
+        client.send(&mut stack, TCP_RST, &[]);
+
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while stack.tcp.values().any(|s| !s.is_closed()) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        stack.gc();
+        assert!(stack.tcp.is_empty());
+
+        // The port can be reused right away
+        client.seq = 1000;
+        client.send(&mut stack, TCP_SYN, &[]);
+        assert_eq!(client.recv(&mut out).await.flags, TCP_SYN | TCP_ACK);
+    }
+
+    #[tokio::test]
+    async fn unknown_segments_are_reset() {
+        let (mut stack, mut out, _accepted) = Stack::new(1500);
+        let mut client = Client {
+            src: addr(CLIENT),
+            dest: addr("1.1.1.1:443"),
+            seq: 1,
+            ack: 7,
+        };
+        client.send(&mut stack, TCP_ACK, b"data");
+        let rst = client.recv(&mut out).await;
+        assert_eq!((rst.flags, rst.seq), (TCP_RST, 7));
+        assert!(stack.tcp.is_empty());
+    }
+
+    #[tokio::test]
+    async fn udp_flow() {
+        let src = addr("[fd00::1]:5000");
+        let dest = addr("[2001:4860:4860::8888]:53");
+        let (mut stack, mut out, mut accepted) = Stack::new(1500);
+        stack
+            .input(&packet::build_udp(src, dest, b"query"))
+            .unwrap();
+        let mut stream = match accepted.recv().await.unwrap() {
+            Accepted::Udp { stream, .. } => stream,
+            Accepted::Tcp { .. } => panic!("Expected UDP"),
+        };
+
+        // Later packets of the flow go to the same stream
+        stack
+            .input(&packet::build_udp(src, dest, b"again"))
+            .unwrap();
+        assert!(accepted.try_recv().is_err());
+
+        let packet = stream.next().await.unwrap();
+        assert_eq!(&packet[..], b"query");
+        stream
+            .send(UdpPacket::new(dest, BytesMut::from(&b"answer"[..])))
+            .await
+            .unwrap();
+        let reply = out.recv().await.unwrap();
+        let ip = packet::parse_ip(&reply).unwrap();
+        let (src_port, dst_port, payload) = packet::parse_udp(&ip).unwrap();
+        assert_eq!(SocketAddr::new(ip.src, src_port), dest);
+        assert_eq!(SocketAddr::new(ip.dst, dst_port), src);
+        assert_eq!(payload, b"answer");
+    }
+}
@@ -0,0 +1,443 @@
+//! TCP connections terminated by the stack, bridged to a duplex stream for the dispatcher.
+//!
+//! Only passive opens are handled. Window scaling, SACK and timestamps are not negotiated, out of
+//! order segments are dropped, and lost ones are retransmitted go-back-N from the last ACK.
+use super::packet::{self, Segment, TCP_ACK, TCP_FIN, TCP_PSH, TCP_RST, TCP_SYN};
+use crate::prelude::*;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc::{Receiver, UnboundedSender};
+use tokio::time::{sleep_until, Instant};
+
+/// Buffer between the connection and the dispatcher, each way.
+const APP_BUFFER: usize = 64 * 1024;
+/// Data from the dispatcher waiting to be sent or acknowledged.
+const SEND_BUFFER: usize = 64 * 1024;
+/// Largest window without window scaling.
+const RECV_WINDOW: usize = u16::MAX as usize;
+/// MSS assumed when the peer sends none.
+const DEFAULT_MSS: usize = 536;
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MAX_RTO: Duration = Duration::from_secs(60);
+const MAX_RETRIES: u32 = 8;
+/// How long a closed connection keeps acknowledging retransmitted FINs.
+pub const TIME_WAIT: Duration = Duration::from_secs(10);
+
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    !seq_lt(b, a)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynReceived,
+    Established,
+}
+
+enum Event {
+    Segment(Option<Segment>),
+    AppRead(IoResult<usize>),
+    AppWrite(IoResult<usize>),
+    Timeout,
+}
+
+struct Tcb {
+    /// Original destination, acting as our end.
+    local: SocketAddr,
+    remote: SocketAddr,
+    out: UnboundedSender<Vec<u8>>,
+    state: State,
+    local_mss: usize,
+    mss: usize,
+
+    iss: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_wnd: usize,
+    /// Bytes from `snd_una` on, sent or not.
+    send_buf: VecDeque<u8>,
+    /// The dispatcher has no more data.
+    fin_queued: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+
+    rcv_nxt: u32,
+    /// Received bytes not yet written to the dispatcher.
+    recv_buf: VecDeque<u8>,
+    peer_fin: bool,
+    adv_wnd: usize,
+
+    rto: Duration,
+    retries: u32,
+    deadline: Option<Instant>,
+    dup_acks: u32,
+    reset: bool,
+}
+
+impl Tcb {
+    fn new(
+        local: SocketAddr,
+        remote: SocketAddr,
+        syn: &Segment,
+        local_mss: usize,
+        out: UnboundedSender<Vec<u8>>,
+    ) -> Self {
+        let iss: u32 = rand::random();
+        Self {
+            local,
+            remote,
+            out,
+            state: State::SynReceived,
+            local_mss,
+            mss: syn
+                .mss
+                .map_or(DEFAULT_MSS, usize::from)
+                .min(local_mss)
+                .max(1),
+            iss,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_wnd: syn.window as usize,
+            send_buf: VecDeque::new(),
+            fin_queued: false,
+            fin_sent: false,
+            fin_acked: false,
+            rcv_nxt: syn.seq.wrapping_add(1),
+            recv_buf: VecDeque::new(),
+            peer_fin: false,
+            adv_wnd: RECV_WINDOW,
+            rto: INITIAL_RTO,
+            retries: 0,
+            deadline: None,
+            dup_acks: 0,
+            reset: false,
+        }
+    }
+
+    fn window(&self) -> usize {
+        RECV_WINDOW - self.recv_buf.len()
+    }
+
+    /// Sent sequence space not yet acknowledged, including the FIN.
+    fn in_flight(&self) -> usize {
+        self.snd_nxt.wrapping_sub(self.snd_una) as usize
+    }
+
+    fn closed(&self) -> bool {
+        self.reset || (self.fin_acked && self.peer_fin && self.recv_buf.is_empty())
+    }
+
+    fn emit(&mut self, seq: u32, flags: u8, mss: Option<u16>, payload: Bytes) {
+        self.adv_wnd = self.window();
+        let seg = Segment {
+            seq,
+            ack: self.rcv_nxt,
+            flags,
+            window: self.adv_wnd as u16,
+            mss,
+            payload,
+        };
+        let _ = self
+            .out
+            .send(packet::build_tcp(self.local, self.remote, &seg));
+    }
+
+    fn send_syn_ack(&mut self) {
+        let mss = Some(self.local_mss as u16);
+        self.emit(self.iss, TCP_SYN | TCP_ACK, mss, Bytes::new());
+        self.arm();
+    }
+
+    fn send_ack(&mut self) {
+        self.emit(self.snd_nxt, TCP_ACK, None, Bytes::new());
+    }
+
+    /// Resets the connection, like when the dispatcher drops it with data still coming.
+    fn abort(&mut self) {
+        self.emit(self.snd_nxt, TCP_RST | TCP_ACK, None, Bytes::new());
+        self.reset = true;
+    }
+
+    fn arm(&mut self) {
+        if self.deadline.is_none() {
+            self.deadline = Some(Instant::now() + self.rto);
+        }
+    }
+
+    /// Sends what the peer's window allows, then the FIN once all data is out.
+    fn flush(&mut self) {
+        if self.state != State::Established {
+            return;
+        }
+        if !self.fin_sent {
+            let mut sent = self.in_flight();
+            while sent < self.send_buf.len() {
+                let window = self.snd_wnd.saturating_sub(sent);
+                let n = (self.send_buf.len() - sent).min(window).min(self.mss);
+                if n == 0 {
+                    break;
+                }
+                let payload: Bytes = self.send_buf.range(sent..sent + n).copied().collect();
+                let flags = if sent + n == self.send_buf.len() {
+                    TCP_ACK | TCP_PSH
+                } else {
+                    TCP_ACK
+                };
+                self.emit(self.snd_nxt, flags, None, payload);
+                self.snd_nxt = self.snd_nxt.wrapping_add(n as u32);
+                sent += n;
+            }
+            if self.fin_queued && sent == self.send_buf.len() {
+                self.emit(self.snd_nxt, TCP_FIN | TCP_ACK, None, Bytes::new());
+                self.snd_nxt = self.snd_nxt.wrapping_add(1);
+                self.fin_sent = true;
+            }
+        }
+        // Also probes a zero window
+        if self.in_flight() > 0 || !self.send_buf.is_empty() {
+            self.arm();
+        }
+    }
+
+    fn retransmit(&mut self) {
+        self.snd_nxt = self.snd_una;
+        self.fin_sent = false;
+        self.deadline = None;
+        self.flush();
+    }
+
+    fn on_timeout(&mut self) {
+        self.deadline = None;
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            self.abort();
+            return;
+        }
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        match self.state {
+            State::SynReceived => self.send_syn_ack(),
+            State::Established => {
+                if self.in_flight() == 0 {
+                    // Probes with one byte
+                    self.snd_wnd = self.snd_wnd.max(1);
+                }
+                self.retransmit();
+            }
+        }
+    }
+
+    fn on_segment(&mut self, seg: Segment) {
+        if seg.flags & TCP_RST != 0 {
+            // Blind resets outside the window are ignored
+            let offset = seg.seq.wrapping_sub(self.rcv_nxt) as usize;
+            if offset <= self.window() {
+                self.reset = true;
+            }
+            return;
+        }
+        if seg.flags & TCP_SYN != 0 {
+            if self.state == State::SynReceived && seg.seq.wrapping_add(1) == self.rcv_nxt {
+                // Our SYN-ACK was lost
+                self.send_syn_ack();
+            } else {
+                self.send_ack();
+            }
+            return;
+        }
+        if seg.flags & TCP_ACK == 0 {
+            return;
+        }
+
+        if self.state == State::SynReceived {
+            if seg.ack != self.iss.wrapping_add(1) {
+                let rst = Segment {
+                    seq: seg.ack,
+                    flags: TCP_RST,
+                    ..Default::default()
+                };
+                let _ = self
+                    .out
+                    .send(packet::build_tcp(self.local, self.remote, &rst));
+                return;
+            }
+            self.state = State::Established;
+            self.snd_una = seg.ack;
+            self.snd_wnd = seg.window as usize;
+            self.rto = INITIAL_RTO;
+            self.retries = 0;
+            self.deadline = None;
+        } else {
+            self.on_ack(&seg);
+        }
+        self.on_data(seg);
+        self.flush();
+    }
+
+    fn on_ack(&mut self, seg: &Segment) {
+        let ack = seg.ack;
+        if seq_lt(self.snd_una, ack) && seq_le(ack, self.snd_nxt) {
+            let acked = ack.wrapping_sub(self.snd_una) as usize;
+            let data = acked.min(self.send_buf.len());
+            self.send_buf.drain(..data);
+            if self.fin_sent && acked > data {
+                self.fin_acked = true;
+            }
+            self.snd_una = ack;
+            self.snd_wnd = seg.window as usize;
+            self.rto = INITIAL_RTO;
+            self.retries = 0;
+            self.dup_acks = 0;
+            // Restarted by `flush` if anything is left
+            self.deadline = None;
+        } else if ack == self.snd_una {
+            let duplicate = seg.payload.is_empty()
+                && seg.flags & TCP_FIN == 0
+                && seg.window as usize == self.snd_wnd
+                && self.in_flight() > 0;
+            self.snd_wnd = seg.window as usize;
+            if seg.window == 0 {
+                // The peer is alive but not reading, keep probing
+                self.retries = 0;
+            }
+            if duplicate {
+                self.dup_acks += 1;
+                if self.dup_acks == 3 {
+                    self.retransmit();
+                }
+            }
+        }
+    }
+
+    fn on_data(&mut self, seg: Segment) {
+        let fin = seg.flags & TCP_FIN != 0;
+        let mut payload = seg.payload;
+        if payload.is_empty() && !fin {
+            return;
+        }
+        if self.peer_fin {
+            // Retransmitted FIN
+            self.send_ack();
+            return;
+        }
+        if seq_lt(seg.seq, self.rcv_nxt) {
+            let skip = self.rcv_nxt.wrapping_sub(seg.seq) as usize;
+            if skip > payload.len() {
+                self.send_ack();
+                return;
+            }
+            payload = payload.slice(skip..);
+        } else if seg.seq != self.rcv_nxt {
+            // Out of order, the duplicate ACK asks for what is missing
+            self.send_ack();
+            return;
+        }
+
+        let n = payload.len().min(self.window());
+        self.recv_buf.extend(&payload[..n]);
+        self.rcv_nxt = self.rcv_nxt.wrapping_add(n as u32);
+        if fin && n == payload.len() {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.peer_fin = true;
+        }
+        self.send_ack();
+    }
+
+    fn on_app_read(&mut self, data: &[u8]) {
+        self.send_buf.extend(data);
+        self.flush();
+    }
+
+    fn on_app_write(&mut self, n: usize) {
+        self.recv_buf.drain(..n);
+        // Tells a peer held back by a small window that it can send again
+        if self.adv_wnd < RECV_WINDOW / 2 && self.window() >= self.adv_wnd + self.mss {
+            self.send_ack();
+        }
+    }
+}
+
+/// Runs the connection opened by `syn` from `remote` to `local`, handing its stream to `accept`
+/// once established. Returns after the connection is reset, or closed and out of TIME_WAIT.
+pub async fn serve(
+    local: SocketAddr,
+    remote: SocketAddr,
+    syn: Segment,
+    local_mss: usize,
+    mut segments: Receiver<Segment>,
+    out: UnboundedSender<Vec<u8>>,
+    accept: impl FnOnce(DuplexStream),
+) {
+    let mut tcb = Tcb::new(local, remote, &syn, local_mss, out);
+    tcb.send_syn_ack();
+
+    let (app, stream) = tokio::io::duplex(APP_BUFFER);
+    let mut app = Some(app);
+    let mut accept = Some(accept);
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut app_eof_sent = false;
+    let mut buffer = vec![0u8; tcb.mss];
+
+    loop {
+        let established = tcb.state == State::Established;
+        if established {
+            if let (Some(app), Some(accept)) = (app.take(), accept.take()) {
+                accept(app);
+            }
+            if tcb.peer_fin && tcb.recv_buf.is_empty() && !app_eof_sent {
+                let _ = writer.shutdown().await;
+                app_eof_sent = true;
+            }
+        }
+        if tcb.closed() {
+            break;
+        }
+
+        let read_len = buffer
+            .len()
+            .min(SEND_BUFFER.saturating_sub(tcb.send_buf.len()));
+        let can_read = established && !tcb.fin_queued && read_len > 0;
+        let can_write = established && !tcb.recv_buf.is_empty();
+        let deadline = tcb.deadline;
+        let event = tokio::select! {
+            seg = segments.recv() => Event::Segment(seg),
+            res = reader.read(&mut buffer[..read_len]), if can_read => Event::AppRead(res),
+            res = writer.write(tcb.recv_buf.as_slices().0), if can_write => Event::AppWrite(res),
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                Event::Timeout
+            }
+        };
+        match event {
+            Event::Segment(Some(seg)) => tcb.on_segment(seg),
+            // The stack is gone
+            Event::Segment(None) => return,
+            Event::AppRead(Ok(n)) if n > 0 => tcb.on_app_read(&buffer[..n]),
+            Event::AppRead(_) => {
+                tcb.fin_queued = true;
+                tcb.flush();
+            }
+            Event::AppWrite(Ok(n)) => tcb.on_app_write(n),
+            Event::AppWrite(Err(_)) => tcb.abort(),
+            Event::Timeout => tcb.on_timeout(),
+        }
+    }
+    if tcb.reset {
+        return;
+    }
+
+    let until = Instant::now() + TIME_WAIT;
+    loop {
+        tokio::select! {
+            seg = segments.recv() => match seg {
+                Some(seg) if seg.flags & TCP_FIN != 0 => tcb.send_ack(),
+                Some(_) => {}
+                None => return,
+            },
+            _ = sleep_until(until) => return,
+        }
+    }
+}
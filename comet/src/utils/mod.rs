@@ -2,6 +2,7 @@ pub mod connector;
 pub mod io;
 pub mod metered_stream;
 pub mod prepend_io;
+pub mod socks_addr;
 
 use anyhow::Result;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
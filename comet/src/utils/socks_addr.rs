@@ -0,0 +1,159 @@
+//! SOCKS5-style address encoding, also borrowed by Shadowsocks, Trojan and friends.
+//!
+//! ```text
+//! +------+----------+----------+
+//! | ATYP | DST.ADDR | DST.PORT |
+//! +------+----------+----------+
+//! |  1   | Variable |    2     |
+//! +------+----------+----------+
+//! ```
+use crate::prelude::*;
+use anyhow::bail;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub const TYPE_IPV4: u8 = 1;
+pub const TYPE_DOMAIN: u8 = 3;
+pub const TYPE_IPV6: u8 = 4;
+
+/// Address type values, which some protocols number differently.
+#[derive(Debug, Clone, Copy)]
+pub struct AddrTypes {
+    pub ipv4: u8,
+    pub domain: u8,
+    pub ipv6: u8,
+}
+
+pub const SOCKS_TYPES: AddrTypes = AddrTypes {
+    ipv4: TYPE_IPV4,
+    domain: TYPE_DOMAIN,
+    ipv6: TYPE_IPV6,
+};
+
+/// Reads an address from `reader` into `dest`.
+pub async fn read_addr<R: AsyncRead + Unpin>(reader: &mut R, dest: &mut DestAddr) -> Result<()> {
+    read_host(reader, dest, SOCKS_TYPES).await?;
+    dest.set_port(reader.read_u16().await?);
+    Ok(())
+}
+
+/// Reads `ATYP | DST.ADDR` from `reader` into `dest`, leaving its port untouched.
+pub async fn read_host<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    dest: &mut DestAddr,
+    types: AddrTypes,
+) -> Result<()> {
+    let addr_type = reader.read_u8().await?;
+    match addr_type {
+        t if t == types.ipv4 => {
+            let mut buffer = [0; 4];
+            reader.read_exact(&mut buffer).await?;
+            dest.set_ip(buffer);
+        }
+        t if t == types.ipv6 => {
+            let mut buffer = [0; 16];
+            reader.read_exact(&mut buffer).await?;
+            dest.set_ip(buffer);
+        }
+        t if t == types.domain => {
+            let mut buffer = [0; 255];
+            let len = reader.read_u8().await? as usize;
+            reader.read_exact(&mut buffer[0..len]).await?;
+            let s = String::from_utf8_lossy(&buffer[0..len]);
+            dest.set_domain(s);
+        }
+        _ => bail!("Invalid ATYP: {}", addr_type),
+    }
+    Ok(())
+}
+
+/// Decodes an address at the start of `buf`, returning it and the number of bytes consumed.
+pub fn decode_addr(buf: &[u8]) -> Result<(DestAddr, usize)> {
+    let mut dest = DestAddr::default();
+    if buf.is_empty() {
+        bail!("Address incomplete");
+    }
+    let addr_len = match buf[0] {
+        TYPE_IPV4 => 4,
+        TYPE_IPV6 => 16,
+        TYPE_DOMAIN => {
+            if buf.len() < 2 {
+                bail!("Address incomplete");
+            }
+            1 + buf[1] as usize
+        }
+        t => bail!("Invalid ATYP: {}", t),
+    };
+    let len = 1 + addr_len + 2;
+    if buf.len() < len {
+        bail!("Address incomplete");
+    }
+
+    let addr = &buf[1..1 + addr_len];
+    match buf[0] {
+        TYPE_IPV4 => {
+            let mut octets = [0; 4];
+            octets.copy_from_slice(addr);
+            dest.set_ip(octets);
+        }
+        TYPE_IPV6 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(addr);
+            dest.set_ip(octets);
+        }
+        _ => dest.set_domain(String::from_utf8_lossy(&addr[1..])),
+    }
+    dest.set_port(u16::from_be_bytes([buf[len - 2], buf[len - 1]]));
+
+    Ok((dest, len))
+}
+
+/// Encodes `dest`, preferring the domain if it is known.
+pub fn encode_addr(dest: &DestAddr, buf: &mut BytesMut) -> Result<()> {
+    encode_host(dest, buf, SOCKS_TYPES)?;
+    buf.put_u16(dest.port_or_error()?);
+    Ok(())
+}
+
+/// Encodes `ATYP | DST.ADDR` of `dest`, preferring the domain if it is known.
+pub fn encode_host(dest: &DestAddr, buf: &mut BytesMut, types: AddrTypes) -> Result<()> {
+    if let Some(domain) = &dest.domain {
+        if domain.len() > 255 {
+            bail!("Domain too long: {}", domain);
+        }
+        buf.reserve(2 + domain.len() + 2);
+        buf.put_u8(types.domain);
+        buf.put_u8(domain.len() as u8);
+        buf.put_slice(domain.as_bytes());
+    } else {
+        encode_ip(*dest.ip_or_error()?, buf, types);
+    }
+    Ok(())
+}
+
+pub fn encode_socket_addr(addr: &SocketAddr, buf: &mut BytesMut) {
+    encode_ip(addr.ip(), buf, SOCKS_TYPES);
+    buf.put_u16(addr.port());
+}
+
+fn encode_ip(ip: IpAddr, buf: &mut BytesMut, types: AddrTypes) {
+    match ip {
+        IpAddr::V4(ip) => {
+            buf.reserve(1 + 4 + 2);
+            buf.put_u8(types.ipv4);
+            buf.put_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.reserve(1 + 16 + 2);
+            buf.put_u8(types.ipv6);
+            buf.put_slice(&ip.octets());
+        }
+    }
+}
+
+/// Unspecified address of the same family as `ip`.
+pub fn unspecified_like(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}